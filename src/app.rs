@@ -6,12 +6,50 @@ use std:: {
     collections::HashMap
 };
 
+use retry::RetryPolicy;
+use pacing::Pacing;
+use link::{Link, ResetLine};
+use watch::WatchOptions;
+use raw::{RawUpload, Jump};
+use memory::Operation;
+use symbols::{self, SymbolTable};
+use runner::{self, TestOptions, TestRun};
+use monitor;
+use regex::Regex;
+
+/// This structure holds all settings given on the
+/// command line which apply to every session.
+struct Settings<'a> {
+    /// Folder where CD-ROM file system is mounted.
+    folder : &'a str,
+
+    /// Folder where files sent by the console are written, if any.
+    output_folder : Option<&'a str>,
+
+    /// Capabilities enabled on the host.
+    capabilities : u16,
+
+    policy : RetryPolicy,
+
+    /// Whether a new session is awaited when the current
+    /// one ends or the console stops responding.
+    daemon : bool,
+
+    watch : Option<WatchOptions>,
+
+    /// Raw binary sent instead of the executable, if any.
+    raw : Option<RawUpload>
+}
+
 /// This function is called once all command line a
 /// rguments have been successfully parsed, and tries
 /// to establish a TCP connection against a front-end
 /// if configured by command line parameters.
 pub fn app(arg_hash: HashMap<String, String>) -> Result<()> {
     use cmdline;
+    use protocol;
+    use transfer;
+    use std::path::Path;
 
     let addr = arg_hash.get(&String::from(cmdline::TCP_ARG));
 
@@ -19,9 +57,6 @@ pub fn app(arg_hash: HashMap<String, String>) -> Result<()> {
         setup_tcp(addr)?
     }
 
-    // Since this should never return None, always unwrap() it.
-    let port_name = arg_hash.get(&String::from(cmdline::PORT_NAME_ARG)).unwrap();
-
     // Extract baud rate from command line parameters,
     // but don't process it yet.
     let baud_rate = arg_hash.get(&String::from(cmdline::BAUDRATE_ARG));
@@ -29,16 +64,238 @@ pub fn app(arg_hash: HashMap<String, String>) -> Result<()> {
     // Extract folder where CD-ROM file system is mounted.
     let folder = arg_hash.get(&String::from(cmdline::CDIMG_FOLDER)).expect("Invalid given folder");
 
-    serial_comm(addr, port_name, baud_rate, folder)?;
+    // Debug text sent by the console is printed unless disabled.
+    let output_enabled = !arg_hash.contains_key(&String::from(cmdline::DISABLE_OUTPUT_ARG));
 
-    Ok(())
+    // Extract folder where files sent by the console are written, if any.
+    let output_folder = arg_hash.get(&String::from(cmdline::OUTPUT_FOLDER)).map(|f| f.as_str());
+
+    // Framed messages are used whenever supported
+    // by the console, unless disabled.
+    let capabilities = if arg_hash.contains_key(&String::from(cmdline::UNFRAMED_ARG)) {
+        protocol::HOST_CAPABILITIES & !protocol::FRAMING
+    }
+    else
+    {
+        protocol::HOST_CAPABILITIES
+    };
+
+    let mut policy = RetryPolicy::new();
+
+    if let Some(timeouts) = arg_hash.get(&String::from(cmdline::TIMEOUTS_ARG)) {
+        policy.set_timeouts(timeouts).map_err(Error::other)?;
+    }
+
+    if let Some(retries) = arg_hash.get(&String::from(cmdline::RETRIES_ARG)) {
+        policy.max_retries = retries.parse().map_err(|_| Error::other("Invalid retry count"))?;
+    }
+
+    if let Some(backoff) = arg_hash.get(&String::from(cmdline::BACKOFF_ARG)) {
+        policy.backoff = match backoff.parse::<f64>() {
+            Ok(b) if b >= 1.0 => b,
+            _ => return Err(Error::other("Invalid backoff factor"))
+        };
+    }
+
+    if let Some(deadline) = arg_hash.get(&String::from(cmdline::DEADLINE_ARG)) {
+        let seconds = deadline.parse().map_err(|_| Error::other("Invalid deadline"))?;
+
+        policy.deadline = Some(std::time::Duration::from_secs(seconds));
+    }
+
+    let pacing = match arg_hash.get(&String::from(cmdline::PACING_ARG)) {
+        Some(pacing) => Pacing::parse(pacing).map_err(Error::other)?,
+        None => Pacing::Adaptive
+    };
+
+    // A raw binary can be sent instead of the executable.
+    let raw = match arg_hash.get(&String::from(cmdline::RAW_ARG)) {
+        Some(raw) => {
+            let mut raw = RawUpload::parse(raw).map_err(Error::other)?;
+
+            raw.verify = arg_hash.contains_key(&String::from(cmdline::VERIFY_ARG));
+
+            if let Some(jump) = arg_hash.get(&String::from(cmdline::JUMP_ARG)) {
+                raw.jump = Jump::parse(jump).map_err(Error::other)?;
+            }
+
+            Some(raw)
+        },
+        None => None
+    };
+
+    // Symbols of the uploaded program can be used instead
+    // of addresses, and to decode crash reports.
+    let symbols = match arg_hash.get(&String::from(cmdline::SYMBOLS_ARG)) {
+        Some(path) => Some(SymbolTable::load(path)?),
+        None => find_symbols(folder, raw.as_ref())
+    };
+
+    // Symbols are looked up without the console.
+    if let Some(("symbol", params)) = cmdline::subcommand(&arg_hash) {
+        return symbols::print_lookups(symbols.as_ref(), &params)
+    }
+
+    // In test mode, the executable is sent as usual, and debug
+    // text sent by the console tells whether its tests passed.
+    let test = match cmdline::subcommand(&arg_hash) {
+        Some(("test", params)) => {
+            let pattern = |arg : &str| match arg_hash.get(&String::from(arg)) {
+                Some(pattern) => Regex::new(pattern).map(Some).map_err(|e| Error::other(format!("Invalid pattern {}: {}", pattern, e))),
+                None => Ok(None)
+            };
+
+            let timeout = match arg_hash.get(&String::from(cmdline::TEST_TIMEOUT_ARG)) {
+                Some(seconds) => match seconds.parse() {
+                    Ok(seconds) if seconds > 0 => std::time::Duration::from_secs(seconds),
+                    _ => return Err(Error::other("Invalid test timeout"))
+                },
+                None => runner::DEFAULT_TIMEOUT
+            };
+
+            // The suite is named after the executable by default.
+            let name = match params.first() {
+                Some(name) => String::from(*name),
+                None => raw.as_ref()
+                           .map(|raw| raw.path.clone())
+                           .or_else(|| transfer::get_exe_name(folder))
+                           .and_then(|path| Path::new(&path).file_name().and_then(|n| n.to_str()).map(String::from))
+                           .unwrap_or_else(|| String::from("tests"))
+            };
+
+            Some(TestOptions {
+                name,
+                pass_pattern : pattern(cmdline::PASS_PATTERN_ARG)?,
+                fail_pattern : pattern(cmdline::FAIL_PATTERN_ARG)?,
+                timeout,
+                report : arg_hash.get(&String::from(cmdline::JUNIT_ARG)).cloned()
+            })
+        },
+        _ => None
+    };
+
+    // A subcommand performs a single operation on
+    // the console instead of sending an executable.
+    let operation = match cmdline::subcommand(&arg_hash) {
+        Some(_) if test.is_some() => None,
+        Some((name, params)) => {
+            let size = match arg_hash.get(&String::from(cmdline::VALUE_SIZE_ARG)).map(|s| s.as_str()) {
+                None | Some("1") => 1,
+                Some("2") => 2,
+                Some("4") => 4,
+                Some(_) => return Err(Error::other("Invalid value size, expected 1, 2 or 4"))
+            };
+
+            let interval = match arg_hash.get(&String::from(cmdline::POLL_INTERVAL_ARG)) {
+                Some(ms) => match ms.parse() {
+                    Ok(ms) if ms > 0 => std::time::Duration::from_millis(ms),
+                    _ => return Err(Error::other("Invalid poll interval"))
+                },
+                None => monitor::DEFAULT_INTERVAL
+            };
+
+            Some(Operation::parse(name, &params, size, interval, symbols.as_ref()).map_err(Error::other)?)
+        },
+        None => None
+    };
+
+    // It is only missing for subcommands not needing the
+    // console, which have already returned, so always unwrap() it.
+    let port_name = arg_hash.get(&String::from(cmdline::PORT_NAME_ARG)).unwrap();
+
+    let mut port = Link::new(serial_init(addr, port_name, baud_rate)?);
+
+    port.set_pacing(pacing, baud_rate.and_then(|b| b.parse().ok()).unwrap_or(115200));
+    port.console().set_output_enabled(output_enabled);
+    port.console().set_symbols(symbols);
+
+    // In daemon mode, a new session is awaited when
+    // the current one ends or the console stops responding.
+    let daemon = arg_hash.contains_key(&String::from(cmdline::DAEMON_ARG));
+
+    // In watch mode, the executable is sent again
+    // every time it changes.
+    let watch = if arg_hash.contains_key(&String::from(cmdline::WATCH_ARG)) {
+        let reset_line = match arg_hash.get(&String::from(cmdline::RESET_LINE_ARG)) {
+            Some(line) => Some(ResetLine::parse(line).ok_or_else(|| Error::other("Invalid reset line, expected dtr or rts"))?),
+            None => None
+        };
+
+        Some(WatchOptions {
+            folder : arg_hash.get(&String::from(cmdline::WATCH_FOLDER_ARG)).cloned(),
+            build_command : arg_hash.get(&String::from(cmdline::BUILD_COMMAND_ARG)).cloned(),
+            reset_line
+        })
+    }
+    else
+    {
+        None
+    };
+
+    let settings = Settings {
+        folder,
+        output_folder,
+        capabilities,
+        policy,
+        daemon,
+        watch,
+        raw
+    };
+
+    match (operation, test) {
+        (Some(operation), _) => memory_comm(&mut port, &settings, &operation),
+        (None, Some(options)) => {
+            // A test run ends once its outcome is known,
+            // so it cannot wait for new sessions.
+            if settings.daemon || settings.watch.is_some() {
+                return Err(Error::other("Test mode cannot be combined with daemon or watch mode"))
+            }
+
+            let mut run = TestRun::new(options);
+
+            port.console().set_capture(true);
+
+            let result = serial_comm(&mut port, &settings, Some(&mut run));
+
+            run.finish(result)
+        },
+        (None, None) => serial_comm(&mut port, &settings, None)
+    }
+}
+
+/// This function loads symbols from a file found next to the
+/// executable, or the raw binary if given, if there is any.
+fn find_symbols(folder : &str, raw : Option<&RawUpload>) -> Option<SymbolTable> {
+    use std::path::Path;
+    use transfer;
+
+    let exe_path = match raw {
+        Some(raw) => Some(raw.path.clone()),
+        None if Path::new(folder).join("SYSTEM.CNF").is_file() => {
+            transfer::get_exe_name(folder).map(|name| format!("{}/{}", folder, name))
+        },
+        None => None
+    };
+
+    let path = exe_path.and_then(|exe_path| SymbolTable::find_for(&exe_path))?;
+
+    match SymbolTable::load(&path) {
+        Ok(symbols) => {
+            println!("Loaded symbols from {}", path);
+            Some(symbols)
+        },
+        Err(e) => {
+            println!("Could not load symbols: {}", e);
+            None
+        }
+    }
 }
 
 fn setup_tcp(tcp_addr : &String) -> Result<()> {
 
     use std::net::{TcpListener};
 
-    let listener = TcpListener::bind(tcp_addr)?;
+    let _listener = TcpListener::bind(tcp_addr)?;
 
     println!("Awaiting for connection on address {}", tcp_addr);
 
@@ -59,53 +316,313 @@ fn setup_tcp(tcp_addr : &String) -> Result<()> {
     Ok(())
 }
 
-fn serial_comm(addr : Option<&String>, port_name : &String, baud_rate : Option<&String>, folder : &String) -> Result<()> {
+/// This function sends the executable, or the raw binary if given,
+/// and serves requests from the console until the session ends.
+/// If a test run is given, the session ends once it has finished.
+fn serial_comm(port : &mut Link, settings : &Settings, mut test_run : Option<&mut TestRun>) -> Result<()> {
     use transfer;
-    use transfer::TransferState;
+    use transfer::{TransferState, Window, FileTransfer};
+    use protocol::{self, Protocol};
+    use payload::{Payload, Cache};
+    use pcdrv::HandleTable;
+    use framer::RequestFramer;
+    use watch::{self, Watcher};
+    use std::{path::Path, time::Instant};
 
-    let mut port = serial_init(addr, port_name, baud_rate).unwrap();
+    let Settings { folder, output_folder, capabilities, ref policy, daemon, ref watch, ref raw } = *settings;
+    let watch = watch.as_ref();
+    let raw = raw.as_ref();
 
     let mut state = TransferState::FirstContact;
+    let mut protocol = Protocol::lock_step();
     let mut prev_state = state;
-    let mut sent_bytes = 0 as usize;
-    let mut requested_file = String::new();
-    let exe_data = transfer::get_exe_data(&folder).unwrap();
-    let mut file_data : Vec<u8> = Vec::new();
-    let mut file_size : Option<usize> = None;
+    let mut sent_bytes : usize = 0;
+    let mut window = Window::new();
+    let mut exe = match transfer::get_exe_data(folder) {
+        Some(exe) => exe,
+        // No executable is needed to send a raw binary.
+        None if raw.is_some() => transfer::Executable::default(),
+        None => return Err(Error::new(ErrorKind::NotFound, "Could not find a valid executable"))
+    };
+    let mut exe_payload = Payload::new();
+    let mut cache = Cache::new();
+    let mut handles = HandleTable::new();
+    let mut file = FileTransfer::new();
+    let mut framer = RequestFramer::new();
+    let mut started = Instant::now();
+    let mut image_hash : u32 = 0;
+    let mut last_image : Option<transfer::Image> = None;
+
+    // Whether the executable has changed, and has been built
+    // if needed, but the console has not been reloaded yet.
+    let mut reload_pending = false;
+
+    // Watch mode keeps running across sessions, as daemon mode does.
+    let daemon = daemon || watch.is_some();
+
+    let mut watcher = match watch {
+        Some(options) => {
+            // Raw binaries are sent again when they change.
+            let exe_path = Path::new(raw.map_or(&exe.path, |raw| &raw.path));
+            let exe_folder = exe_path.parent()
+                                     .and_then(|p| p.to_str())
+                                     .filter(|p| !p.is_empty())
+                                     .unwrap_or(".");
+            let exe_name = exe_path.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+
+            Some(Watcher::new(exe_folder, &[exe_name, "SYSTEM.CNF"], options.folder.as_deref())?)
+        },
+        None => None
+    };
 
     loop {
+        if let Some(ref mut run) = test_run {
+            run.feed(&port.console().take_captured());
+
+            if run.finished() {
+                break
+            }
+        }
+
+        if let (Some(watcher), Some(options)) = (watcher.as_mut(), watch) {
+            if let Some(name) = watcher.changed()? {
+                println!("\n{} has changed", name);
+
+                let built = match options.build_command {
+                    Some(ref command) => {
+                        let built = watch::run_build_command(command);
+
+                        // Files written by the build command
+                        // must not trigger another build.
+                        watcher.discard()?;
+                        built
+                    },
+                    None => true
+                };
+
+                reload_pending |= built;
+            }
+
+            // The console is only asked to reload once it is idle, waiting
+            // for requests, so transfers in progress are never interrupted.
+            // The executable is read again during the handshake, so nothing
+            // else is needed if no session is running.
+            if reload_pending {
+                match state {
+                    TransferState::WaitFileRequest => {
+                        reload_pending = false;
+                        state = transfer::reload_console(port, options.reset_line);
+                    },
+                    TransferState::FirstContact
+                    | TransferState::Handshake
+                    | TransferState::Finished
+                    | TransferState::Failed => reload_pending = false,
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(deadline) = policy.deadline {
+            if started.elapsed() > deadline {
+                let message = format!("Session deadline of {} seconds exceeded", deadline.as_secs());
+
+                if !daemon {
+                    return Err(Error::new(ErrorKind::TimedOut, message))
+                }
+
+                println!("\n{}", message);
+
+                started = Instant::now();
+                state = TransferState::Failed;
+            }
+        }
+
         state = match state {
-            TransferState::FirstContact => transfer::first_contact(&mut port),
-            TransferState::WaitAck => {
-                state = transfer::wait_ack_default(&mut port, prev_state);
+            TransferState::FirstContact => {
+                // Files opened by the console, as well as any
+                // transfer in progress, are only valid during
+                // the session they were started in.
+                handles.close_all();
+                framer.reset();
+                protocol = Protocol::lock_step();
+                sent_bytes = 0;
+                window = Window::new();
+                file = FileTransfer::new();
+                transfer::first_contact(port)
+            },
+            TransferState::Handshake => {
+                state = transfer::wait_handshake(port, &mut protocol, capabilities, &mut image_hash, policy);
+
+                let uploading = state == TransferState::SendHeader || state == TransferState::CheckImage;
+
+                if uploading && raw.is_some() {
+                    if protocol.supports(protocol::RAW_UPLOAD) {
+                        state = TransferState::SendRaw;
+                    }
+                    else
+                    {
+                        let message = "The console does not support raw uploads";
+
+                        if !daemon {
+                            return Err(Error::new(ErrorKind::Unsupported, message))
+                        }
+
+                        println!("{}", message);
+                        state = TransferState::Failed;
+                    }
+
+                    started = Instant::now();
+                }
+                else if uploading {
+                    // The executable might have been
+                    // rebuilt since the last session.
+                    match transfer::get_exe_data(folder) {
+                        Some(new_exe) => exe = new_exe,
+                        None => println!("Could not read executable again, sending {}", exe.path)
+                    }
+
+                    started = Instant::now();
+                }
+
                 prev_state = state;
                 state
             },
-            TransferState::SendHeader => transfer::send_header(&mut port, &exe_data),
-            TransferState::SendExeSize => transfer::send_exe_size(&mut port, &exe_data),
-            TransferState::CleaningRAM => transfer::wait_ack_default(&mut port, prev_state),
-            TransferState::SendExeData => transfer::send_exe_data(&mut port, &mut sent_bytes, &exe_data),
+            TransferState::CheckImage => {
+                state = transfer::check_image(port, &exe, image_hash, last_image.as_ref());
+                prev_state = state;
+                state
+            },
+            TransferState::SendDelta => {
+                state = transfer::send_delta(port, &exe, last_image.as_ref(), &mut exe_payload, &mut cache, &protocol);
+                // The whole executable is sent if no delta could be built.
+                if state != TransferState::WaitAck {
+                    prev_state = state;
+                }
+                state
+            },
+            TransferState::WaitAck => {
+                state = match prev_state {
+                    TransferState::SendExeData | TransferState::SendFile =>
+                        transfer::wait_data_ack(port,
+                                                prev_state,
+                                                &mut sent_bytes,
+                                                &mut window,
+                                                &protocol,
+                                                policy),
+                    _ => transfer::wait_ack_default(port, prev_state, &mut window, policy)
+                };
+                // Acknowledgements are waited for again on timeout.
+                if state != TransferState::WaitAck {
+                    prev_state = state;
+                }
+                state
+            },
+            TransferState::SendHeader => transfer::send_header(port, &exe),
+            TransferState::SendExeSize => transfer::send_exe_size(port, &exe, &mut exe_payload, &mut cache, &protocol),
+            TransferState::CleaningRAM => {
+                state = transfer::wait_ack_default(port, prev_state, &mut window, policy);
+                if state != TransferState::WaitAck {
+                    prev_state = state;
+                }
+                state
+            },
+            TransferState::SendExeData => {
+                state = transfer::send_exe_data(port, &mut sent_bytes, &mut exe_payload, &mut window, &protocol);
+
+                if state == TransferState::WaitFileRequest {
+                    match raw {
+                        Some(raw) if raw.verify => state = TransferState::VerifyRaw,
+                        Some(_) => state = TransferState::Jump,
+                        // The uploaded image is kept, so only blocks changed
+                        // by the next build are sent. If the file has changed
+                        // in the meantime, its hash will not match the one
+                        // reported by the console, so it is never used.
+                        None if protocol.supports(protocol::DELTA_UPLOAD) => last_image = transfer::Image::load(&exe).ok(),
+                        None => {}
+                    }
+                }
+
+                state
+            },
+            TransferState::SendRaw => raw.map_or(TransferState::Failed, |raw| {
+                transfer::send_raw(port, raw, &mut exe_payload, &mut cache, &protocol)
+            }),
+            TransferState::VerifyRaw => raw.map_or(TransferState::Failed, |raw| transfer::verify_raw(port, raw, policy)),
+            TransferState::Jump => raw.map_or(TransferState::Failed, |raw| transfer::jump(port, &raw.jump)),
             TransferState::WaitFileRequest => {
-                state = transfer::wait_file_request(&mut port, &mut requested_file);
+                state = transfer::wait_file_request(port, &mut framer, &mut file.name, policy);
+
+                // Old loaders do not announce when the console is reset,
+                // so it is assumed after a long time without any data.
+                if daemon && protocol.version == 0 && state == TransferState::WaitFileRequest {
+                    state = transfer::check_contact(port, policy);
+                }
+
                 prev_state = state;
                 state
             },
-            TransferState::SendFile => transfer::send_file(&mut port,
-                                                            &folder,
+            TransferState::SendFile => transfer::send_file(port,
+                                                            folder,
                                                             &mut sent_bytes,
-                                                            &mut requested_file,
-                                                            &mut file_data,
-                                                            &mut file_size),
-            TransferState::Finished => break
+                                                            &mut file,
+                                                            &mut cache,
+                                                            &mut window,
+                                                            &protocol),
+            TransferState::ReceiveFile => transfer::receive_file(port,
+                                                                  output_folder,
+                                                                  &mut file,
+                                                                  &mut window,
+                                                                  &protocol,
+                                                                  policy),
+            TransferState::HostFileRequest => {
+                state = transfer::serve_host_file(port,
+                                                  folder,
+                                                  output_folder,
+                                                  &mut file,
+                                                  &mut handles,
+                                                  &mut cache,
+                                                  &protocol);
+                // Data read from a handle is sent just like files.
+                prev_state = TransferState::SendFile;
+                state
+            },
+            TransferState::DirectoryRequest => {
+                state = transfer::serve_directory_request(port,
+                                                          folder,
+                                                          &mut file,
+                                                          &mut cache,
+                                                          &protocol);
+                // Directory listings are sent just like files.
+                prev_state = TransferState::SendFile;
+                state
+            },
+            TransferState::Finished | TransferState::Failed if daemon => {
+                println!("Waiting for the console to start a new session");
+                TransferState::FirstContact
+            },
+            TransferState::Finished => break,
+            TransferState::Failed => {
+                return Err(Error::other("The transfer was aborted"))
+            }
         };
     }
 
     Ok(())
 }
 
+/// This function performs a single operation on the console,
+/// given by a subcommand, once the handshake has finished.
+fn memory_comm(port : &mut Link, settings : &Settings, operation : &Operation) -> Result<()> {
+    use memory;
+
+    let protocol = memory::connect(port, settings.capabilities, &settings.policy)?;
+
+    memory::run(port, &protocol, &settings.policy, operation)
+}
+
 /// This function initializes a serial device.
 /// Command line parameters are extracted and parsed here.
-fn serial_init(addr : Option<&String>, port_name : &String, baud_rate : Option<&String>) -> Result<serial::SystemPort> {
+fn serial_init(_addr : Option<&String>, port_name : &String, baud_rate : Option<&String>) -> Result<serial::SystemPort> {
     use serial::SerialPort;
 
     // Try to open the serial device. If opened,
@@ -121,7 +638,7 @@ fn serial_init(addr : Option<&String>, port_name : &String, baud_rate : Option<&
                 // Parse user-specific baud rate.
                 Ok(s) => serial::BaudRate::from_speed(s),
                 // Could not parse input baud rate.
-                Err(_) => return Err(Error::new(ErrorKind::Other, "Invalid baudrate")),
+                Err(_) => return Err(Error::other("Invalid baudrate")),
             }
         }
     };
@@ -12,24 +12,118 @@ pub struct CmdLineArg {
 }
 
 /// This parameter allows defining serial port name.
-pub const PORT_NAME_ARG : &'static str = "--port-name";
+pub const PORT_NAME_ARG : &str = "--port-name";
 
 /// This parameter disables sending any information
-/// coming from the console to stdout.
-pub const DISABLE_OUTPUT_ARG : &'static str = "--disable-output";
+/// coming from the console to stdout. Debug text is
+/// still received, so it does not disturb transfers.
+pub const DISABLE_OUTPUT_ARG : &str = "--disable-output";
 
 /// This parameter allows defining a specific baud rate,
-pub const BAUDRATE_ARG : &'static str = "--baud-rate";
+pub const BAUDRATE_ARG : &str = "--baud-rate";
 
 /// This parameter allows using a TCP connection
 /// against a GUI front-end.
-pub const TCP_ARG : &'static str = "--tcp";
+pub const TCP_ARG : &str = "--tcp";
 
 /// This parameter defines what folder should
 /// be looked up in order to set up a working environment
-pub const CDIMG_FOLDER : &'static str = "--cdimg-folder";
-
-const CMD_LINE_ARGS : [CmdLineArg; 5] =
+pub const CDIMG_FOLDER : &str = "--cdimg-folder";
+
+/// This parameter defines what folder files
+/// sent by the console should be written to.
+pub const OUTPUT_FOLDER : &str = "--output-folder";
+
+/// This parameter keeps the legacy protocol, where
+/// messages are not framed, even if the console
+/// supports framed messages.
+pub const UNFRAMED_ARG : &str = "--unframed";
+
+/// This parameter defines how long to wait for the console
+/// on each phase of the transfer, in milliseconds.
+pub const TIMEOUTS_ARG : &str = "--timeouts";
+
+/// This parameter defines how many times a message is sent
+/// again, or waited for, before the transfer is aborted.
+pub const RETRIES_ARG : &str = "--retries";
+
+/// This parameter defines the factor applied to timeouts on each retry.
+pub const BACKOFF_ARG : &str = "--backoff";
+
+/// This parameter defines the maximum duration
+/// of the whole session, in seconds.
+pub const DEADLINE_ARG : &str = "--deadline";
+
+/// This parameter defines how long to wait
+/// before sending each packet to the console.
+pub const PACING_ARG : &str = "--pacing";
+
+/// This parameter keeps the application running
+/// across many console sessions, e.g.: after the
+/// console is reset or stops responding.
+pub const DAEMON_ARG : &str = "--daemon";
+
+/// This parameter sends the executable again, without
+/// waiting for a new session, every time it changes.
+pub const WATCH_ARG : &str = "--watch";
+
+/// This parameter defines an additional folder to monitor
+/// in watch mode, e.g.: the source code of the executable.
+pub const WATCH_FOLDER_ARG : &str = "--watch-folder";
+
+/// This parameter defines a command run in watch mode
+/// before the executable is sent again.
+pub const BUILD_COMMAND_ARG : &str = "--build-command";
+
+/// This parameter defines the serial control line
+/// wired to the reset signal of the console, if any.
+pub const RESET_LINE_ARG : &str = "--reset-line";
+
+/// This parameter sends a raw binary to a given
+/// address of the console RAM, instead of the
+/// executable defined by SYSTEM.CNF.
+pub const RAW_ARG : &str = "--raw";
+
+/// This parameter asks the console to verify
+/// a raw binary once it has been written.
+pub const VERIFY_ARG : &str = "--verify";
+
+/// This parameter defines where the console jumps
+/// to once a raw binary has been written, as well
+/// as the registers set before jumping.
+pub const JUMP_ARG : &str = "--jump";
+
+/// This parameter defines the size in bytes of each
+/// value read or written by the peek and poke subcommands.
+pub const VALUE_SIZE_ARG : &str = "--value-size";
+
+/// This parameter defines a file where symbols of the
+/// uploaded program are read from, so they can be used
+/// instead of addresses and to decode crash reports.
+/// If not given, they are looked for next to the executable.
+pub const SYMBOLS_ARG : &str = "--symbols";
+
+/// This parameter defines the time between two reads
+/// of the locations given to the monitor subcommand.
+pub const POLL_INTERVAL_ARG : &str = "--poll-interval";
+
+/// This parameter defines a regular expression which,
+/// once matched by a line of debug text, makes a test run pass.
+pub const PASS_PATTERN_ARG : &str = "--pass-pattern";
+
+/// This parameter defines a regular expression which,
+/// once matched by a line of debug text, makes a test run fail.
+pub const FAIL_PATTERN_ARG : &str = "--fail-pattern";
+
+/// This parameter defines how long a test
+/// run can take before it is considered failed.
+pub const TEST_TIMEOUT_ARG : &str = "--test-timeout";
+
+/// This parameter defines a file where a JUnit
+/// XML report of a test run is written.
+pub const JUNIT_ARG : &str = "--junit";
+
+const CMD_LINE_ARGS : [CmdLineArg; 27] =
 [
     CmdLineArg {
         arg_str : PORT_NAME_ARG,
@@ -42,7 +136,7 @@ const CMD_LINE_ARGS : [CmdLineArg; 5] =
         arg_str : DISABLE_OUTPUT_ARG,
         param_str : None,
         is_required : false,
-        explanation : "Disables incoming debug messages from the console"
+        explanation : "Disables printing debug messages sent by the console"
     },
 
     CmdLineArg {
@@ -65,23 +159,290 @@ const CMD_LINE_ARGS : [CmdLineArg; 5] =
         param_str : Some("[FOLDER]"),
         is_required : true,
         explanation : "Sets working directory"
+    },
+
+    CmdLineArg {
+        arg_str : OUTPUT_FOLDER,
+        param_str : Some("[FOLDER]"),
+        is_required : false,
+        explanation : "Sets folder where files sent by the console are written. \
+                      Write requests are rejected if not set"
+    },
+
+    CmdLineArg {
+        arg_str : UNFRAMED_ARG,
+        param_str : None,
+        is_required : false,
+        explanation : "Disables framed messages, for compatibility with \
+                      loaders with a faulty implementation"
+    },
+
+    CmdLineArg {
+        arg_str : TIMEOUTS_ARG,
+        param_str : Some("[PHASE=MS,...]"),
+        is_required : false,
+        explanation : "Sets timeouts in milliseconds for each phase: handshake, ack, data, \
+                      request and silence, e.g.: ack=500,data=1000. In daemon mode, a new session \
+                      is started if old loaders send nothing for the silence timeout, 60 s by default, \
+                      since they do not announce when the console is reset"
+    },
+
+    CmdLineArg {
+        arg_str : RETRIES_ARG,
+        param_str : Some("[COUNT]"),
+        is_required : false,
+        explanation : "Sets how many times a message is retried before aborting. Defaults to 8"
+    },
+
+    CmdLineArg {
+        arg_str : BACKOFF_ARG,
+        param_str : Some("[FACTOR]"),
+        is_required : false,
+        explanation : "Sets the factor applied to timeouts on each retry. Defaults to 2"
+    },
+
+    CmdLineArg {
+        arg_str : DEADLINE_ARG,
+        param_str : Some("[SECONDS]"),
+        is_required : false,
+        explanation : "Aborts the session if not finished after the given time"
+    },
+
+    CmdLineArg {
+        arg_str : PACING_ARG,
+        param_str : Some("[POLICY]"),
+        is_required : false,
+        explanation : "Sets delay between packets: none, fixed:MS or adaptive. \
+                      Defaults to adaptive, based on how long the console takes \
+                      to acknowledge data"
+    },
+
+    CmdLineArg {
+        arg_str : DAEMON_ARG,
+        param_str : None,
+        is_required : false,
+        explanation : "Keeps running after the console is reset or stops responding, \
+                      sending the executable again on each new session"
+    },
+
+    CmdLineArg {
+        arg_str : WATCH_ARG,
+        param_str : None,
+        is_required : false,
+        explanation : "Sends the executable again when it changes. Implies --daemon. \
+                      Only supported on Linux"
+    },
+
+    CmdLineArg {
+        arg_str : WATCH_FOLDER_ARG,
+        param_str : Some("[FOLDER]"),
+        is_required : false,
+        explanation : "Sets an additional folder monitored in watch mode, including its subfolders"
+    },
+
+    CmdLineArg {
+        arg_str : BUILD_COMMAND_ARG,
+        param_str : Some("[COMMAND]"),
+        is_required : false,
+        explanation : "Sets a command run in watch mode before sending the executable again. \
+                      Nothing is sent if it fails"
+    },
+
+    CmdLineArg {
+        arg_str : RESET_LINE_ARG,
+        param_str : Some("[dtr|rts]"),
+        is_required : false,
+        explanation : "Resets the console through the given control line in watch mode, \
+                      instead of asking the running executable to return to the loader"
+    },
+
+    CmdLineArg {
+        arg_str : RAW_ARG,
+        param_str : Some("[FILE@ADDRESS]"),
+        is_required : false,
+        explanation : "Writes a raw binary to the given RAM address instead of \
+                      the executable, e.g.: overlay.bin@0x80100000"
+    },
+
+    CmdLineArg {
+        arg_str : VERIFY_ARG,
+        param_str : None,
+        is_required : false,
+        explanation : "Verifies the raw binary once written"
+    },
+
+    CmdLineArg {
+        arg_str : JUMP_ARG,
+        param_str : Some("[ADDRESS,REG=VALUE,...]"),
+        is_required : false,
+        explanation : "Jumps to the given address once the raw binary is written, setting \
+                      a0-a3, gp and sp if given. Defaults to the address of the binary"
+    },
+
+    CmdLineArg {
+        arg_str : VALUE_SIZE_ARG,
+        param_str : Some("[1|2|4]"),
+        is_required : false,
+        explanation : "Sets the size in bytes of values read by peek or written by poke. Defaults to 1"
+    },
+
+    CmdLineArg {
+        arg_str : SYMBOLS_ARG,
+        param_str : Some("[FILE]"),
+        is_required : false,
+        explanation : "Loads symbols from an ELF, GNU ld or Psy-Q map file, so addresses can be given \
+                      as symbols, optionally followed by an offset, e.g.: player+0x10, or as struct \
+                      members if the ELF file holds debugging information, e.g.: player.health, and crash \
+                      reports sent by the console are shown with a backtrace. Defaults to any file \
+                      named as the executable, with an .elf or .map extension"
+    },
+
+    CmdLineArg {
+        arg_str : POLL_INTERVAL_ARG,
+        param_str : Some("[MS]"),
+        is_required : false,
+        explanation : "Sets the time between two reads when monitoring. Defaults to 500 ms"
+    },
+
+    CmdLineArg {
+        arg_str : PASS_PATTERN_ARG,
+        param_str : Some("[REGEX]"),
+        is_required : false,
+        explanation : "Makes a test run pass once a line of debug text matches the given pattern"
+    },
+
+    CmdLineArg {
+        arg_str : FAIL_PATTERN_ARG,
+        param_str : Some("[REGEX]"),
+        is_required : false,
+        explanation : "Makes a test run fail once a line of debug text matches the given pattern"
+    },
+
+    CmdLineArg {
+        arg_str : TEST_TIMEOUT_ARG,
+        param_str : Some("[SECONDS]"),
+        is_required : false,
+        explanation : "Makes a test run fail if not finished after the given time. Defaults to 120 seconds"
+    },
+
+    CmdLineArg {
+        arg_str : JUNIT_ARG,
+        param_str : Some("[FILE]"),
+        is_required : false,
+        explanation : "Writes a JUnit XML report of a test run into FILE"
     }
 ];
 
+/// This structure defines a subcommand, given before any
+/// other argument, which performs a single operation on the
+/// console instead of sending an executable to it.
+pub struct Subcommand {
+    pub name : &'static str,
+    params_str : &'static str,
+    explanation : &'static str,
+
+    /// Whether the console is needed, so PORT_NAME_ARG is required.
+    needs_console : bool
+}
+
+/// Key used to store the subcommand and its parameters
+/// inside the table returned by process_arguments().
+pub const SUBCOMMAND_KEY : &str = "subcommand";
+
+/// Character separating the subcommand and its parameters
+/// inside the table returned by process_arguments().
+const SUBCOMMAND_SEPARATOR : char = '\n';
+
+const SUBCOMMANDS : [Subcommand; 7] =
+[
+    Subcommand {
+        name : "peek",
+        params_str : "ADDRESS [COUNT]",
+        explanation : "Prints COUNT values read from console RAM. Defaults to 1 value",
+        needs_console : true
+    },
+
+    Subcommand {
+        name : "poke",
+        params_str : "ADDRESS VALUE...",
+        explanation : "Writes the given values into console RAM",
+        needs_console : true
+    },
+
+    Subcommand {
+        name : "dump",
+        params_str : "ADDRESS LENGTH [FILE]",
+        explanation : "Writes LENGTH bytes read from console RAM into FILE, \
+                      or prints them as a hex dump if not given",
+        needs_console : true
+    },
+
+    Subcommand {
+        name : "monitor",
+        params_str : "LOCATION[:FORMAT]...",
+        explanation : "Prints the given locations every time they change, where FORMAT is x, u, s \
+                      or fx (4.12 fixed-point), followed by its size in bits. Defaults to x32. \
+                      Struct members are given by name if known from debugging information, e.g.: \
+                      player.health, or as an offset from their symbol otherwise, e.g.: player+0x10",
+        needs_console : true
+    },
+
+    Subcommand {
+        name : "gdb",
+        params_str : "[ADDRESS]",
+        explanation : "Waits for GDB to connect to ADDRESS, and forwards its requests to the debug stub \
+                      on the console. Defaults to 127.0.0.1:3333",
+        needs_console : true
+    },
+
+    Subcommand {
+        name : "test",
+        params_str : "[NAME]",
+        explanation : "Sends the executable and serves its requests until the tests it runs pass \
+                      or fail, as reported by the console with \"*** EXIT CODE ***\" and \"TEST \
+                      PASS|FAIL|SKIP NAME[: MESSAGE]\" lines or found by --pass-pattern and \
+                      --fail-pattern, and exits with a matching status. NAME is used on the report, \
+                      and defaults to the executable name",
+        needs_console : true
+    },
+
+    Subcommand {
+        name : "symbol",
+        params_str : "NAME|ADDRESS...",
+        explanation : "Prints the address of each given symbol, or the symbol each given address \
+                      belongs to. The console is not needed",
+        needs_console : false
+    }
+];
+
+/// Returns the subcommand found by process_arguments(),
+/// if any, and its parameters.
+pub fn subcommand(arg_hash : &HashMap<String, String>) -> Option<(&str, Vec<&str>)> {
+    arg_hash.get(SUBCOMMAND_KEY).map(|subcommand| {
+        let mut words = subcommand.split(SUBCOMMAND_SEPARATOR);
+        let name = words.next().unwrap_or_default();
+
+        (name, words.collect())
+    })
+}
+
 fn show_help() {
     println!("rspsxserial command line arguments:");
 
     for arg in CMD_LINE_ARGS.iter() {
         let line = format!("{} {}\t{}.",
                                 arg.arg_str,
-                                match arg.param_str {
-                                    None => "",
-                                    Some(param_str) => param_str
-                                },
+                                arg.param_str.unwrap_or_default(),
                                 arg.explanation);
 
         println!("{}", line);
     }
+
+    println!("Subcommands, given before any other argument:");
+
+    for subcommand in SUBCOMMANDS.iter() {
+        println!("{} {}\t{}.", subcommand.name, subcommand.params_str, subcommand.explanation);
+    }
 }
 
 /// This function creates a Hashmap instance
@@ -104,7 +465,7 @@ pub fn process_arguments() -> Option<HashMap<String, String>> {
     {
         ParameterOption,
         ParameterValue
-    };
+    }
 
     impl std::fmt::Debug for ExpectedParameter {
         fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -115,15 +476,27 @@ pub fn process_arguments() -> Option<HashMap<String, String>> {
                         ExpectedParameter::ParameterValue => "ParameterValue"
                     })
         }
-    };
+    }
 
     let mut parameter_state = ExpectedParameter::ParameterOption;
 
     let mut arg_hash: HashMap<String, String> = HashMap::new();
     let mut parameter_name = String::new();
 
-    for arg in env::args_os().skip(1) {
-        let arg_str = arg.into_string().unwrap();
+    let mut args : Vec<String> = env::args_os().skip(1).map(|arg| arg.into_string().unwrap()).collect();
+
+    let subcommand = SUBCOMMANDS.iter().find(|s| s.name == args[0]);
+
+    // A subcommand, if any, is given first, followed by
+    // its parameters, and then by any other argument.
+    if subcommand.is_some() {
+        let params = args.iter().position(|arg| arg.starts_with("--")).unwrap_or(args.len());
+        let words : Vec<String> = args.drain(..params).collect();
+
+        arg_hash.insert(String::from(SUBCOMMAND_KEY), words.join(&SUBCOMMAND_SEPARATOR.to_string()));
+    }
+
+    for arg_str in args {
 
         match parameter_state {
             ExpectedParameter::ParameterOption => {
@@ -134,7 +507,7 @@ pub fn process_arguments() -> Option<HashMap<String, String>> {
 
                     for param in CMD_LINE_ARGS.iter() {
 
-                        if arg_str == param.arg_str.to_string() {
+                        if arg_str == param.arg_str {
                             parameter_name = arg_str;
 
                             match param.param_str {
@@ -177,7 +550,9 @@ pub fn process_arguments() -> Option<HashMap<String, String>> {
 
     // Check all needed parameters have been given
     for arg in CMD_LINE_ARGS.iter() {
-        if arg.is_required {
+        let needs_console = subcommand.is_none_or(|s| s.needs_console);
+
+        if arg.is_required && (needs_console || arg.arg_str != PORT_NAME_ARG) {
             let arg_string = arg.arg_str.to_string();
 
             if !arg_hash.contains_key(&arg_string) {
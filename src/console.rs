@@ -0,0 +1,292 @@
+use std::io::{self, Write};
+
+use crash::Decoder;
+use crc;
+use symbols::SymbolTable;
+
+/// Byte starting a debug text frame sent by the console when
+/// unframed. Stray text might contain it as well, so it is followed by:
+/// - DEBUG_SYNC (8-bit).
+/// - Text length (8-bit).
+/// - Text.
+/// - CRC-16 of the length and text (16-bit, little-endian).
+///
+/// Anything that does not make a valid frame is taken as protocol
+/// traffic, so debug text never gets mixed with it. Once framing is
+/// agreed, debug text travels inside link::DEBUG frames instead.
+pub const DEBUG_FRAME : u8 = b'$';
+
+/// Byte following DEBUG_FRAME, chosen so it is
+/// not found on text after a '$' character.
+const DEBUG_SYNC : u8 = 0xA5;
+
+/// Number of bytes in an unframed debug text
+/// frame other than the text itself.
+const DEBUG_FRAME_OVERHEAD : usize = 5;
+
+/// This structure handles debug text sent by the console, which
+/// is printed if enabled, and captured if requested. It is owned
+/// by the link, so it can be reached through link::Link::console().
+pub struct Console {
+    /// Whether debug text sent by the console is printed.
+    output_enabled : bool,
+
+    /// Finds crash reports on debug text sent by the console.
+    crash_decoder : Decoder,
+
+    /// Debug text sent by the console since the last call
+    /// to take_captured(), or None if not captured.
+    captured : Option<Vec<u8>>
+}
+
+impl Console {
+    pub fn new() -> Console {
+        Console {
+            output_enabled : true,
+            crash_decoder : Decoder::new(),
+            captured : None
+        }
+    }
+
+    /// Enables or disables printing debug text sent by the console.
+    pub fn set_output_enabled(&mut self, enabled : bool) {
+        self.output_enabled = enabled;
+    }
+
+    /// Enables or disables capturing debug text sent by the console.
+    pub fn set_capture(&mut self, enabled : bool) {
+        self.captured = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Returns debug text captured since the last call.
+    pub fn take_captured(&mut self) -> Vec<u8> {
+        self.captured.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Sets the symbols used to decode crash reports.
+    pub fn set_symbols(&mut self, symbols : Option<SymbolTable>) {
+        self.crash_decoder.set_symbols(symbols);
+    }
+
+    /// Prints debug text sent by the console, if enabled.
+    /// Crash reports found on it are always decoded and printed.
+    pub fn print(&mut self, text : &[u8]) {
+        if self.output_enabled {
+            let stdout = io::stdout();
+            let mut handle = stdout.lock();
+
+            handle.write_all(text).ok();
+            handle.flush().ok();
+        }
+
+        for report in self.crash_decoder.feed(text) {
+            print!("{}", report);
+        }
+
+        if let Some(ref mut captured) = self.captured {
+            captured.extend_from_slice(text);
+        }
+    }
+}
+
+/// This structure separates debug text frames from the rest of the
+/// data received from the console when unframed. See DEBUG_FRAME.
+///
+/// Bytes which might start a frame are held until the frame is
+/// complete, so they are never waited for. If the frame turns out
+/// not to be valid, or nothing else is received, they are released
+/// as any other data.
+pub struct Demux {
+    /// Bytes received since the last DEBUG_FRAME
+    /// which might still make a valid frame.
+    candidate : Vec<u8>
+}
+
+impl Demux {
+    pub fn new() -> Demux {
+        Demux {
+            candidate : Vec::new()
+        }
+    }
+
+    /// Returns whether bytes are being held until a frame is complete.
+    pub fn is_pending(&self) -> bool {
+        !self.candidate.is_empty()
+    }
+
+    /// This function prints all debug text frames found on data
+    /// received from the console. All other data is appended to
+    /// output. Returns whether any debug text was found.
+    pub fn feed(&mut self, data : &[u8], output : &mut Vec<u8>, console : &mut Console) -> bool {
+        let mut found = false;
+
+        for &byte in data {
+            if self.candidate.is_empty() && byte != DEBUG_FRAME {
+                output.push(byte);
+                continue
+            }
+
+            self.candidate.push(byte);
+
+            let len = self.candidate.len();
+
+            if len == 2 && byte != DEBUG_SYNC {
+                found |= self.reject(output, console);
+            }
+            else if len > 2 && len == self.candidate[2] as usize + DEBUG_FRAME_OVERHEAD {
+                let checksum = u16::from_le_bytes([self.candidate[len - 2], self.candidate[len - 1]]);
+
+                if checksum == crc::crc16(&self.candidate[2..len - 2]) {
+                    console.print(&self.candidate[3..len - 2]);
+                    self.candidate.clear();
+                    found = true;
+                }
+                else
+                {
+                    found |= self.reject(output, console);
+                }
+            }
+        }
+
+        found
+    }
+
+    /// This function releases all bytes held, e.g.:
+    /// when nothing else has been received for a while.
+    /// Returns whether any debug text was found on them.
+    pub fn flush(&mut self, output : &mut Vec<u8>, console : &mut Console) -> bool {
+        let mut found = false;
+
+        while self.is_pending() {
+            found |= self.reject(output, console);
+        }
+
+        found
+    }
+
+    /// This function releases the byte starting the candidate frame
+    /// as data, and looks for frames again on the bytes following it.
+    fn reject(&mut self, output : &mut Vec<u8>, console : &mut Console) -> bool {
+        let rest : Vec<u8> = self.candidate.drain(1..).collect();
+
+        self.candidate.clear();
+        output.push(DEBUG_FRAME);
+        self.feed(&rest, output, console)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a console which does not print debug text.
+    fn quiet() -> Console {
+        let mut console = Console::new();
+
+        console.set_output_enabled(false);
+        console
+    }
+
+    /// Returns an unframed debug text frame carrying text.
+    fn frame(text : &[u8]) -> Vec<u8> {
+        let mut frame = vec![DEBUG_FRAME, DEBUG_SYNC, text.len() as u8];
+
+        frame.extend_from_slice(text);
+
+        let checksum = crc::crc16(&frame[2..]);
+
+        frame.extend_from_slice(&checksum.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn stray_text_kept_as_data() {
+        let data = b"Price: $5 #cdrom:\\DATA.BIN;1@$";
+        let mut demux = Demux::new();
+        let mut console = quiet();
+        let mut output : Vec<u8> = Vec::new();
+
+        assert!(!demux.feed(data, &mut output, &mut console));
+        assert!(demux.is_pending());
+        assert!(!demux.flush(&mut output, &mut console));
+        assert_eq!(output, &data[..]);
+    }
+
+    #[test]
+    fn frames_taken_out() {
+        let mut data = b"ab".to_vec();
+
+        data.extend(frame(b"hello"));
+        data.extend_from_slice(b"#cdrom:\\DATA.BIN;1@");
+        data.extend(frame(b""));
+
+        let mut demux = Demux::new();
+        let mut console = quiet();
+        let mut output : Vec<u8> = Vec::new();
+
+        assert!(demux.feed(&data, &mut output, &mut console));
+        assert!(!demux.is_pending());
+        assert_eq!(output, b"ab#cdrom:\\DATA.BIN;1@");
+    }
+
+    #[test]
+    fn frames_split_across_reads() {
+        let data = frame(b"split text");
+        let mut demux = Demux::new();
+        let mut console = quiet();
+        let mut output : Vec<u8> = Vec::new();
+
+        for byte in &data[..data.len() - 1] {
+            assert!(!demux.feed(&[*byte], &mut output, &mut console));
+        }
+
+        assert!(demux.feed(&data[data.len() - 1..], &mut output, &mut console));
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn corrupted_frames_kept_as_data() {
+        let mut data = frame(b"text");
+        let last = data.len() - 1;
+
+        data[last] ^= 1;
+
+        let mut demux = Demux::new();
+        let mut console = quiet();
+        let mut output : Vec<u8> = Vec::new();
+
+        assert!(!demux.feed(&data, &mut output, &mut console));
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn requests_never_swallowed() {
+        // A long frame is never waited for, so the request
+        // following it is released once nothing else comes.
+        let mut data = vec![DEBUG_FRAME, DEBUG_SYNC, 0xFF];
+
+        data.extend_from_slice(b"#cdrom:\\DATA.BIN;1@");
+
+        let mut demux = Demux::new();
+        let mut console = quiet();
+        let mut output : Vec<u8> = Vec::new();
+
+        demux.feed(&data, &mut output, &mut console);
+        demux.flush(&mut output, &mut console);
+        assert_eq!(output, data);
+    }
+
+    #[test]
+    fn frames_found_after_rejected_bytes() {
+        let mut data = vec![DEBUG_FRAME];
+
+        data.extend(frame(b"text"));
+
+        let mut demux = Demux::new();
+        let mut console = quiet();
+        let mut output : Vec<u8> = Vec::new();
+
+        assert!(demux.feed(&data, &mut output, &mut console));
+        assert_eq!(output, [DEBUG_FRAME]);
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+
+use symbols::SymbolTable;
+
+/// Line printed by the console before a crash report, as debug text.
+/// The report is made of lines with any number of REGISTER=VALUE
+/// pairs, where values are given in hexadecimal and registers are
+/// either epc, cause, badvaddr, sr, hi, lo, or any general-purpose
+/// register given by its name (e.g.: "sp") or number (e.g.: "r29").
+/// Lines starting with "stack:" are followed by words read from the
+/// stack, starting at sp, also in hexadecimal. epc and cause are
+/// required. The report ends with REPORT_END.
+pub const REPORT_START : &str = "*** CRASH ***";
+
+/// Line printed by the console after a crash report.
+pub const REPORT_END : &str = "*** END ***";
+
+/// Names of general-purpose registers, by number.
+const REGISTER_NAMES : [&str; 32] =
+[
+    "zero", "at", "v0", "v1", "a0", "a1", "a2", "a3",
+    "t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7",
+    "s0", "s1", "s2", "s3", "s4", "s5", "s6", "s7",
+    "t8", "t9", "k0", "k1", "gp", "sp", "fp", "ra"
+];
+
+/// Indexes of some registers inside REGISTER_NAMES.
+const SP : usize = 29;
+const RA : usize = 31;
+
+/// Descriptions of each exception code found on the cause register.
+const EXCEPTIONS : [&str; 13] =
+[
+    "Interrupt",
+    "TLB modification",
+    "TLB miss on load",
+    "TLB miss on store",
+    "Address error on load (AdEL)",
+    "Address error on store (AdES)",
+    "Bus error on instruction fetch (IBE)",
+    "Bus error on data access (DBE)",
+    "Syscall",
+    "Breakpoint",
+    "Reserved instruction",
+    "Coprocessor unusable",
+    "Arithmetic overflow"
+];
+
+/// Bit of the cause register set when the exception
+/// was raised by the delay slot of a branch or jump.
+const BRANCH_DELAY : u32 = 1 << 31;
+
+/// Largest number of lines kept for a single report, so
+/// a missing REPORT_END does not keep lines forever.
+const MAX_REPORT_LINES : usize = 64;
+
+/// Largest number of bytes kept for a single line.
+const MAX_LINE_LENGTH : usize = 1024;
+
+/// Largest number of frames shown on a backtrace.
+const MAX_FRAMES : usize = 32;
+
+/// This structure holds the registers and
+/// stack contents found on a crash report.
+struct CrashReport {
+    epc : u32,
+    cause : u32,
+    registers : [Option<u32>; 32],
+    others : HashMap<String, u32>,
+    stack : Vec<u32>
+}
+
+impl CrashReport {
+    /// This function parses all lines found
+    /// between REPORT_START and REPORT_END.
+    fn parse(lines : &[String]) -> Result<CrashReport, String> {
+        let mut registers : [Option<u32>; 32] = [None; 32];
+        let mut others : HashMap<String, u32> = HashMap::new();
+        let mut stack : Vec<u32> = Vec::new();
+
+        let hex = |value : &str| {
+            let digits = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+
+            u32::from_str_radix(digits, 16).map_err(|_| format!("Invalid value {:?}", value))
+        };
+
+        for line in lines {
+            if let Some(words) = line.trim_start().strip_prefix("stack:") {
+                for word in words.split_whitespace() {
+                    stack.push(hex(word)?);
+                }
+
+                continue
+            }
+
+            for pair in line.split_whitespace() {
+                let (name, value) = match pair.split_once('=') {
+                    Some((name, value)) => (name.to_lowercase(), hex(value)?),
+                    None => return Err(format!("Invalid register {:?}, expected REGISTER=VALUE", pair))
+                };
+
+                let index = match name.as_str() {
+                    "s8" => Some(30),
+                    _ => REGISTER_NAMES.iter()
+                                       .position(|&r| r == name)
+                                       .or_else(|| name.strip_prefix('r').and_then(|n| n.parse().ok()).filter(|&n : &usize| n < 32))
+                };
+
+                match index {
+                    Some(index) => registers[index] = Some(value),
+                    None => { others.insert(name, value); }
+                }
+            }
+        }
+
+        let epc = others.get("epc").cloned().ok_or("Missing epc")?;
+        let cause = others.get("cause").cloned().ok_or("Missing cause")?;
+
+        Ok(CrashReport {
+            epc,
+            cause,
+            registers,
+            others,
+            stack
+        })
+    }
+
+    /// Returns the given address as text, followed
+    /// by the symbol it belongs to if known.
+    fn describe(address : u32, symbols : Option<&SymbolTable>) -> String {
+        match symbols {
+            Some(symbols) => symbols.describe(address),
+            None => format!("{:#010X}", address)
+        }
+    }
+
+    /// This function returns the report as readable text: the
+    /// exception, all registers and, if symbols are available,
+    /// a backtrace made of the exception address, the return
+    /// address and any word on the stack that looks like one.
+    fn decode(&self, symbols : Option<&SymbolTable>) -> String {
+        let code = ((self.cause >> 2) & 0x1F) as usize;
+
+        let mut text = format!("Crash: {} at {}",
+                               EXCEPTIONS.get(code).cloned().unwrap_or("Unknown exception"),
+                               CrashReport::describe(self.epc, symbols));
+
+        if self.cause & BRANCH_DELAY != 0 {
+            text += ", on the delay slot of the branch";
+        }
+
+        text += "\n";
+
+        // The bad address is only meaningful for address errors.
+        if let (4 | 5, Some(address)) = (code, self.others.get("badvaddr")) {
+            text += &format!("Bad address: {:#010X}\n", address);
+        }
+
+        let registers : Vec<String> = REGISTER_NAMES.iter()
+                                                    .zip(self.registers.iter())
+                                                    .filter_map(|(name, value)| value.map(|v| format!("{:>4}={:08X}", name, v)))
+                                                    .collect();
+
+        for line in registers.chunks(4) {
+            text += &format!("  {}\n", line.join("  "));
+        }
+
+        let symbols = match symbols {
+            Some(symbols) => symbols,
+            None => return text + "No symbols were loaded, so no backtrace is available\n"
+        };
+
+        text += "Backtrace (guessed from the stack, might be inaccurate):\n";
+        text += &format!("  #0  {}\n", symbols.describe(self.epc));
+
+        let mut frames = 1;
+
+        if let Some(ra) = self.registers[RA].filter(|&ra| CrashReport::is_return_address(ra, symbols)) {
+            text += &format!("  #1  {}  (ra)\n", symbols.describe(ra));
+            frames += 1;
+        }
+
+        let sp = self.registers[SP];
+
+        for (index, &word) in self.stack.iter().enumerate() {
+            if frames >= MAX_FRAMES {
+                break
+            }
+
+            if CrashReport::is_return_address(word, symbols) {
+                let location = match sp {
+                    Some(sp) => format!("{:#010X}", sp.wrapping_add(index as u32 * 4)),
+                    None => format!("sp+{:#X}", index * 4)
+                };
+
+                text += &format!("  #{:<2} {}  ({})\n", frames, symbols.describe(word), location);
+                frames += 1;
+            }
+        }
+
+        text
+    }
+
+    /// Returns whether the given word might be a return address:
+    /// aligned, and pointing after the start of a function, past
+    /// the call and its delay slot.
+    fn is_return_address(word : u32, symbols : &SymbolTable) -> bool {
+        word.is_multiple_of(4) && match symbols.lookup(word) {
+            Some((symbol, offset)) => !symbol.is_data && offset >= 8,
+            None => false
+        }
+    }
+}
+
+/// This structure finds crash reports on debug text
+/// sent by the console, which might arrive in pieces.
+pub struct Decoder {
+    /// Text received since the last line break.
+    line : Vec<u8>,
+
+    /// Lines of the report being received, if any.
+    report : Option<Vec<String>>,
+
+    /// Symbols used to name addresses, if available.
+    symbols : Option<SymbolTable>
+}
+
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder {
+            line : Vec::new(),
+            report : None,
+            symbols : None
+        }
+    }
+
+    /// Sets the symbols of the uploaded program.
+    pub fn set_symbols(&mut self, symbols : Option<SymbolTable>) {
+        self.symbols = symbols;
+    }
+
+    /// This function processes debug text sent by the console,
+    /// and returns any crash report it completed, decoded.
+    pub fn feed(&mut self, text : &[u8]) -> Vec<String> {
+        let mut decoded : Vec<String> = Vec::new();
+
+        for &byte in text {
+            if byte != b'\n' {
+                if self.line.len() < MAX_LINE_LENGTH {
+                    self.line.push(byte);
+                }
+
+                continue
+            }
+
+            let line = String::from_utf8_lossy(&self.line).trim_end().to_string();
+
+            self.line.clear();
+
+            if line.trim() == REPORT_START {
+                self.report = Some(Vec::new());
+            }
+            else if line.trim() == REPORT_END {
+                if let Some(lines) = self.report.take() {
+                    match CrashReport::parse(&lines) {
+                        Ok(report) => decoded.push(report.decode(self.symbols.as_ref())),
+                        Err(e) => println!("Could not decode crash report: {}", e)
+                    }
+                }
+            }
+            else if let Some(ref mut lines) = self.report {
+                if lines.len() < MAX_REPORT_LINES {
+                    lines.push(line);
+                }
+                else
+                {
+                    self.report = None;
+                }
+            }
+        }
+
+        decoded
+    }
+}
@@ -0,0 +1,87 @@
+/// This function calculates the CRC-16/CCITT-FALSE checksum
+/// of the given data, which is appended to each data packet.
+/// A bitwise implementation is used on purpose, so it can be
+/// easily mirrored by the loader running on the console.
+pub fn crc16(data : &[u8]) -> u16 {
+    const POLYNOMIAL : u16 = 0x1021;
+
+    let mut crc : u16 = 0xFFFF;
+
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ POLYNOMIAL
+            }
+            else
+            {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// This function calculates the CRC-32 (IEEE 802.3) checksum
+/// of the given data, which is used to verify whole images.
+pub fn crc32(data : &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// This function updates a CRC-32 checksum, previously returned
+/// by crc32() or this function, with more data. This allows
+/// calculating the checksum of data read in several steps.
+pub fn crc32_update(crc : u32, data : &[u8]) -> u32 {
+    const POLYNOMIAL : u32 = 0xEDB88320;
+
+    let mut crc : u32 = !crc;
+
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            }
+            else
+            {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Data used by the catalogue of parametrised CRC algorithms
+    /// to define the check value of each algorithm.
+    const CHECK_DATA : &[u8] = b"123456789";
+
+    #[test]
+    fn crc16_known_vectors() {
+        assert_eq!(crc16(CHECK_DATA), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+        assert_eq!(crc16(&[0]), 0xE1F0);
+    }
+
+    #[test]
+    fn crc32_known_vectors() {
+        assert_eq!(crc32(CHECK_DATA), 0xCBF43926);
+        assert_eq!(crc32(&[]), 0);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414FA339);
+    }
+
+    #[test]
+    fn crc32_update_in_steps() {
+        let (first, second) = CHECK_DATA.split_at(4);
+
+        assert_eq!(crc32_update(crc32(first), second), crc32(CHECK_DATA));
+        assert_eq!(crc32_update(crc32(&[]), CHECK_DATA), crc32(CHECK_DATA));
+    }
+}
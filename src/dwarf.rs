@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+
+/// Tags of the debugging information entries taken into account.
+const DW_TAG_CLASS_TYPE : u64 = 0x02;
+const DW_TAG_MEMBER : u64 = 0x0D;
+const DW_TAG_STRUCTURE_TYPE : u64 = 0x13;
+const DW_TAG_TYPEDEF : u64 = 0x16;
+const DW_TAG_UNION_TYPE : u64 = 0x17;
+const DW_TAG_CONST_TYPE : u64 = 0x26;
+const DW_TAG_VARIABLE : u64 = 0x34;
+const DW_TAG_VOLATILE_TYPE : u64 = 0x35;
+
+/// Attributes taken into account.
+const DW_AT_NAME : u64 = 0x03;
+const DW_AT_DATA_MEMBER_LOCATION : u64 = 0x38;
+const DW_AT_TYPE : u64 = 0x49;
+
+/// Form whose value is found on the abbreviation itself.
+const DW_FORM_IMPLICIT_CONST : u64 = 0x21;
+
+/// Expression operation used by DWARF 2 to give the
+/// location of a member, followed by its offset.
+const DW_OP_PLUS_UCONST : u8 = 0x23;
+
+/// Unit types holding debugging information entries
+/// without any other header field, as of DWARF 5.
+const DW_UT_COMPILE : u8 = 0x01;
+const DW_UT_PARTIAL : u8 = 0x03;
+
+/// Largest number of typedefs and qualifiers followed when
+/// looking for the structure behind a type, so malformed
+/// files cannot make the search run forever.
+const MAX_TYPE_ALIASES : usize = 16;
+
+/// Value of an attribute, as far as needed here.
+enum Value<'a> {
+    Unsigned(u64),
+    Signed(i64),
+    /// Offset of an entry from the start of .debug_info.
+    Reference(usize),
+    Text(&'a [u8]),
+    Block(&'a [u8]),
+    Other
+}
+
+/// This structure reads the values found on DWARF sections.
+/// All reads return None past the end of data.
+struct Reader<'a> {
+    data : &'a [u8],
+    pos : usize
+}
+
+impl<'a> Reader<'a> {
+    fn new(data : &'a [u8], pos : usize) -> Reader<'a> {
+        Reader { data, pos }
+    }
+
+    fn bytes(&mut self, length : usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(length)?)?;
+
+        self.pos += length;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes(4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    /// Reads an unsigned integer of the given size in bytes.
+    fn unsigned(&mut self, size : usize) -> Option<u64> {
+        let bytes = self.bytes(size)?;
+
+        Some(bytes.iter().rev().fold(0, |value, &b| (value << 8) | b as u64))
+    }
+
+    fn uleb128(&mut self) -> Option<u64> {
+        let mut value : u64 = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+
+            if shift < 64 {
+                value |= ((byte & 0x7F) as u64) << shift;
+            }
+
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                return Some(value)
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Option<i64> {
+        let mut value : i64 = 0;
+        let mut shift = 0;
+
+        loop {
+            let byte = self.u8()?;
+
+            if shift < 64 {
+                value |= ((byte & 0x7F) as i64) << shift;
+            }
+
+            shift += 7;
+
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+
+                return Some(value)
+            }
+        }
+    }
+
+    /// Reads a string ended by a zero byte.
+    fn text(&mut self) -> Option<&'a [u8]> {
+        let length = self.data.get(self.pos..)?.iter().position(|&b| b == 0)?;
+        let text = self.bytes(length)?;
+
+        self.pos += 1;
+        Some(text)
+    }
+}
+
+/// Returns the string found at offset inside a string section.
+fn string_at(strings : &[u8], offset : u64) -> Option<&[u8]> {
+    Reader::new(strings, offset as usize).text()
+}
+
+/// This structure describes an attribute of an abbreviation.
+struct Attribute {
+    name : u64,
+    form : u64,
+
+    /// Value given by the abbreviation itself, if any.
+    implicit_const : i64
+}
+
+/// This structure describes the tag and attributes shared
+/// by debugging information entries.
+struct Abbreviation {
+    tag : u64,
+    has_children : bool,
+    attributes : Vec<Attribute>
+}
+
+/// This function reads the abbreviation table found at offset.
+fn read_abbreviations(abbrev : &[u8], offset : usize) -> Option<HashMap<u64, Abbreviation>> {
+    let mut reader = Reader::new(abbrev, offset);
+    let mut table : HashMap<u64, Abbreviation> = HashMap::new();
+
+    loop {
+        let code = reader.uleb128()?;
+
+        if code == 0 {
+            return Some(table)
+        }
+
+        let tag = reader.uleb128()?;
+        let has_children = reader.u8()? != 0;
+        let mut attributes : Vec<Attribute> = Vec::new();
+
+        loop {
+            let name = reader.uleb128()?;
+            let form = reader.uleb128()?;
+
+            if name == 0 && form == 0 {
+                break
+            }
+
+            let implicit_const = if form == DW_FORM_IMPLICIT_CONST { reader.sleb128()? } else { 0 };
+
+            attributes.push(Attribute { name, form, implicit_const });
+        }
+
+        table.insert(code, Abbreviation { tag, has_children, attributes });
+    }
+}
+
+/// This structure holds the fields of a compilation unit
+/// header needed to read its entries.
+struct Unit {
+    /// Offset of the unit header from the start of .debug_info.
+    offset : usize,
+    version : u16,
+    address_size : usize
+}
+
+/// Sections referred to by the entries of .debug_info.
+struct Sections<'a> {
+    abbrev : &'a [u8],
+    strings : &'a [u8],
+    line_strings : &'a [u8]
+}
+
+/// This function reads the value of an attribute given its form.
+/// Forms not needed here are skipped. Returns None if the form is
+/// not known, since the rest of the entry cannot be read then.
+fn read_value<'a>(reader : &mut Reader<'a>,
+                  form : u64,
+                  implicit_const : i64,
+                  unit : &Unit,
+                  sections : &Sections<'a>) -> Option<Value<'a>> {
+    let value = match form {
+        // addr
+        0x01 => { reader.bytes(unit.address_size)?; Value::Other },
+        // block2, block4, block, block1, exprloc
+        0x03 => { let length = reader.u16()? as usize; Value::Block(reader.bytes(length)?) },
+        0x04 => { let length = reader.u32()? as usize; Value::Block(reader.bytes(length)?) },
+        0x09 | 0x18 => { let length = reader.uleb128()? as usize; Value::Block(reader.bytes(length)?) },
+        0x0A => { let length = reader.u8()? as usize; Value::Block(reader.bytes(length)?) },
+        // data1, data2, data4, data8, udata
+        0x0B => Value::Unsigned(reader.unsigned(1)?),
+        0x05 => Value::Unsigned(reader.unsigned(2)?),
+        0x06 => Value::Unsigned(reader.unsigned(4)?),
+        0x07 => Value::Unsigned(reader.unsigned(8)?),
+        0x0F => Value::Unsigned(reader.uleb128()?),
+        // sdata, implicit_const
+        0x0D => Value::Signed(reader.sleb128()?),
+        DW_FORM_IMPLICIT_CONST => Value::Signed(implicit_const),
+        // string, strp, line_strp
+        0x08 => Value::Text(reader.text()?),
+        0x0E => Value::Text(string_at(sections.strings, reader.u32()? as u64)?),
+        0x1F => Value::Text(string_at(sections.line_strings, reader.u32()? as u64)?),
+        // ref1, ref2, ref4, ref8, ref_udata: relative to the unit
+        0x11 => Value::Reference(unit.offset + reader.unsigned(1)? as usize),
+        0x12 => Value::Reference(unit.offset + reader.unsigned(2)? as usize),
+        0x13 => Value::Reference(unit.offset + reader.unsigned(4)? as usize),
+        0x14 => Value::Reference(unit.offset + reader.unsigned(8)? as usize),
+        0x15 => Value::Reference(unit.offset + reader.uleb128()? as usize),
+        // ref_addr: relative to the section, sized as an address by DWARF 2
+        0x10 if unit.version == 2 => Value::Reference(reader.unsigned(unit.address_size)? as usize),
+        0x10 => Value::Reference(reader.u32()? as usize),
+        // flag, strx1, addrx1
+        0x0C | 0x25 | 0x29 => { reader.bytes(1)?; Value::Other },
+        // strx2, addrx2
+        0x26 | 0x2A => { reader.bytes(2)?; Value::Other },
+        // strx3, addrx3
+        0x27 | 0x2B => { reader.bytes(3)?; Value::Other },
+        // sec_offset, ref_sup4, strp_sup, strx4, addrx4
+        0x17 | 0x1C | 0x1D | 0x28 | 0x2C => { reader.bytes(4)?; Value::Other },
+        // ref_sig8, ref_sup8
+        0x20 | 0x24 => { reader.bytes(8)?; Value::Other },
+        // data16
+        0x1E => { reader.bytes(16)?; Value::Other },
+        // strx, addrx, loclistx, rnglistx
+        0x1A | 0x1B | 0x22 | 0x23 => { reader.uleb128()?; Value::Other },
+        // flag_present
+        0x19 => Value::Other,
+        // indirect
+        0x16 => {
+            let form = reader.uleb128()?;
+
+            return read_value(reader, form, implicit_const, unit, sections)
+        },
+        _ => return None
+    };
+
+    Some(value)
+}
+
+/// This function returns the offset of a member given
+/// the value of its DW_AT_data_member_location attribute,
+/// either a constant or, as of DWARF 2, an expression.
+fn member_location(value : &Value) -> Option<u32> {
+    match *value {
+        Value::Unsigned(offset) => Some(offset as u32),
+        Value::Signed(offset) => Some(offset as u32),
+        Value::Block(expression) if expression.first() == Some(&DW_OP_PLUS_UCONST) => {
+            Reader::new(expression, 1).uleb128().map(|offset| offset as u32)
+        },
+        _ => None
+    }
+}
+
+/// This structure describes a member of a structure or union.
+struct Member {
+    /// Empty for anonymous structures and unions.
+    name : String,
+
+    /// Offset from the start of the structure or union.
+    offset : u32,
+
+    /// Offset of the entry describing its type.
+    type_offset : Option<usize>
+}
+
+/// Types, as far as needed to find struct members.
+enum Type {
+    /// Structure, union or class.
+    Aggregate(Vec<Member>),
+
+    /// Typedef or qualified type, e.g.: const or volatile.
+    Alias(Option<usize>)
+}
+
+/// This structure holds the types of the global variables of a
+/// program, read from the DWARF debugging information of its ELF
+/// file, so the address of struct members can be found by name.
+pub struct DebugInfo {
+    /// Offset of the entry describing the type
+    /// of each global variable, by name.
+    variables : HashMap<String, usize>,
+
+    /// Types, by the offset of the entry describing them.
+    types : HashMap<usize, Type>
+}
+
+impl DebugInfo {
+    /// This function reads the debugging information found on
+    /// the .debug_info section, given the .debug_abbrev, .debug_str
+    /// and .debug_line_str sections. Units which cannot be read,
+    /// e.g.: 64-bit DWARF, are skipped.
+    pub fn read(info : &[u8], abbrev : &[u8], strings : &[u8], line_strings : &[u8]) -> DebugInfo {
+        let sections = Sections { abbrev, strings, line_strings };
+        let mut debug_info = DebugInfo {
+            variables : HashMap::new(),
+            types : HashMap::new()
+        };
+
+        let mut offset = 0;
+
+        while offset < info.len() {
+            let mut reader = Reader::new(info, offset);
+
+            let length = match reader.u32() {
+                // 64-bit DWARF is not expected on 32-bit files.
+                Some(length) if length < 0xFFFF_FFF0 => length as usize,
+                _ => break
+            };
+
+            let end = std::cmp::min(reader.pos + length, info.len());
+
+            debug_info.read_unit(Reader::new(&info[..end], reader.pos), offset, &sections);
+
+            offset = end;
+        }
+
+        debug_info
+    }
+
+    /// This function reads the entries of the unit whose header
+    /// starts at offset, once its length has been read.
+    fn read_unit<'a>(&mut self, mut reader : Reader<'a>, offset : usize, sections : &Sections<'a>) -> Option<()> {
+        let version = reader.u16()?;
+
+        let (abbrev_offset, address_size) = match version {
+            2..=4 => {
+                let abbrev_offset = reader.u32()? as usize;
+
+                (abbrev_offset, reader.u8()? as usize)
+            },
+            5 => {
+                let unit_type = reader.u8()?;
+                let address_size = reader.u8()? as usize;
+                let abbrev_offset = reader.u32()? as usize;
+
+                if unit_type != DW_UT_COMPILE && unit_type != DW_UT_PARTIAL {
+                    return None
+                }
+
+                (abbrev_offset, address_size)
+            },
+            _ => return None
+        };
+
+        let abbreviations = read_abbreviations(sections.abbrev, abbrev_offset)?;
+        let unit = Unit { offset, version, address_size };
+
+        // Structure or union holding the members found on each level,
+        // if any. The first level belongs to the compilation unit.
+        let mut parents : Vec<Option<usize>> = Vec::new();
+
+        while reader.pos < reader.data.len() {
+            let entry_offset = reader.pos;
+            let code = reader.uleb128()?;
+
+            if code == 0 {
+                parents.pop();
+                continue
+            }
+
+            let abbreviation = abbreviations.get(&code)?;
+
+            let mut name : Option<&[u8]> = None;
+            let mut type_offset : Option<usize> = None;
+            let mut location : Option<u32> = None;
+
+            for attribute in &abbreviation.attributes {
+                let value = read_value(&mut reader, attribute.form, attribute.implicit_const, &unit, sections)?;
+
+                match (attribute.name, value) {
+                    (DW_AT_NAME, Value::Text(text)) => name = Some(text),
+                    (DW_AT_TYPE, Value::Reference(offset)) => type_offset = Some(offset),
+                    (DW_AT_DATA_MEMBER_LOCATION, ref value) => location = member_location(value),
+                    _ => {}
+                }
+            }
+
+            let name = name.map(|name| String::from_utf8_lossy(name).into_owned());
+
+            match abbreviation.tag {
+                DW_TAG_STRUCTURE_TYPE | DW_TAG_UNION_TYPE | DW_TAG_CLASS_TYPE => {
+                    self.types.insert(entry_offset, Type::Aggregate(Vec::new()));
+                },
+                DW_TAG_TYPEDEF | DW_TAG_CONST_TYPE | DW_TAG_VOLATILE_TYPE => {
+                    self.types.insert(entry_offset, Type::Alias(type_offset));
+                },
+                DW_TAG_MEMBER => {
+                    if let Some(&Some(parent)) = parents.last() {
+                        if let Some(Type::Aggregate(ref mut members)) = self.types.get_mut(&parent) {
+                            members.push(Member {
+                                name : name.unwrap_or_default(),
+                                // Members of unions have no location.
+                                offset : location.unwrap_or(0),
+                                type_offset
+                            });
+                        }
+                    }
+                },
+                // Only variables found at file scope are global.
+                // Definitions of variables declared elsewhere have
+                // neither name nor type, so the declaration is used.
+                DW_TAG_VARIABLE if parents.len() == 1 => {
+                    if let (Some(name), Some(type_offset)) = (name, type_offset) {
+                        self.variables.entry(name).or_insert(type_offset);
+                    }
+                },
+                _ => {}
+            }
+
+            if abbreviation.has_children {
+                let aggregate = match self.types.get(&entry_offset) {
+                    Some(Type::Aggregate(_)) => Some(entry_offset),
+                    _ => None
+                };
+
+                parents.push(aggregate);
+            }
+        }
+
+        Some(())
+    }
+
+    /// Returns the offset of the given member from the start of the
+    /// given global variable, where members of nested structures are
+    /// separated by dots, e.g.: "pos.x". Members of anonymous structures
+    /// and unions are found as members of the structure holding them.
+    pub fn member_offset(&self, variable : &str, path : &str) -> Option<u32> {
+        let mut type_offset = *self.variables.get(variable)?;
+        let mut offset : u32 = 0;
+
+        for name in path.split('.') {
+            let (member_offset, member_type) = self.find_member(type_offset, name, 0)?;
+
+            offset = offset.checked_add(member_offset)?;
+            type_offset = member_type?;
+        }
+
+        Some(offset)
+    }
+
+    /// Returns the members of the structure or union behind the
+    /// given type, once all typedefs and qualifiers are followed.
+    fn members(&self, mut type_offset : usize) -> Option<&[Member]> {
+        for _ in 0..MAX_TYPE_ALIASES {
+            match self.types.get(&type_offset)? {
+                Type::Aggregate(ref members) => return Some(members),
+                Type::Alias(aliased) => type_offset = (*aliased)?
+            }
+        }
+
+        None
+    }
+
+    /// Returns the offset and type of the member with the given name,
+    /// looking into anonymous members up to MAX_TYPE_ALIASES levels.
+    fn find_member(&self, type_offset : usize, name : &str, depth : usize) -> Option<(u32, Option<usize>)> {
+        let members = self.members(type_offset)?;
+
+        if let Some(member) = members.iter().find(|m| m.name == name) {
+            return Some((member.offset, member.type_offset))
+        }
+
+        if depth >= MAX_TYPE_ALIASES {
+            return None
+        }
+
+        members.iter()
+               .filter(|m| m.name.is_empty())
+               .filter_map(|m| {
+                   let (offset, member_type) = self.find_member(m.type_offset?, name, depth + 1)?;
+
+                   Some((m.offset.checked_add(offset)?, member_type))
+               })
+               .next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leb128() {
+        let data = [0xE5, 0x8E, 0x26, 0x7F, 0x80, 0x7F, 0x80];
+        let mut reader = Reader::new(&data, 0);
+
+        assert_eq!(reader.uleb128(), Some(624485));
+        assert_eq!(reader.sleb128(), Some(-1));
+        assert_eq!(reader.sleb128(), Some(-128));
+        assert_eq!(reader.uleb128(), None);
+    }
+
+    #[test]
+    fn member_locations() {
+        assert_eq!(member_location(&Value::Unsigned(8)), Some(8));
+        assert_eq!(member_location(&Value::Block(&[DW_OP_PLUS_UCONST, 0x90, 0x01])), Some(0x90));
+        assert_eq!(member_location(&Value::Block(&[0x10, 8])), None);
+    }
+}
@@ -0,0 +1,268 @@
+use std::{
+    collections::VecDeque,
+    fmt
+};
+
+use link::HELLO;
+
+/// Byte starting a request sent by the console.
+pub const REQUEST_START : u8 = b'#';
+
+/// Byte ending a request sent by the console.
+pub const REQUEST_END : u8 = b'@';
+
+/// Maximum number of bytes between the start and
+/// end bytes of a request. Longer requests are rejected.
+pub const MAX_REQUEST_LENGTH : usize = 255;
+
+/// This enum defines the reasons why a request
+/// sent by the console could not be decoded.
+pub enum FrameError {
+    /// No end byte was found after MAX_REQUEST_LENGTH bytes.
+    TooLong,
+
+    /// A new request started before the previous one ended.
+    Truncated(Vec<u8>),
+
+    /// The request is not valid UTF-8 text.
+    InvalidText(Vec<u8>)
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f : &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FrameError::TooLong =>
+                write!(f, "request longer than {} bytes", MAX_REQUEST_LENGTH),
+            FrameError::Truncated(ref data) =>
+                write!(f, "request {:?} was not terminated", String::from_utf8_lossy(data)),
+            FrameError::InvalidText(ref data) =>
+                write!(f, "request {:?} is not valid text", String::from_utf8_lossy(data))
+        }
+    }
+}
+
+/// Where the framer is inside the received data.
+enum FramerState {
+    /// Looking for the start byte. Any other byte is ignored.
+    Idle,
+
+    /// Collecting bytes until the end byte is found.
+    InRequest,
+
+    /// Ignoring the rest of a request which was too long.
+    Discarding
+}
+
+/// This structure extracts requests sent by the console, with
+/// the form "#REQUEST@", from data received in any number of
+/// reads. Requests might be split across reads, several of them
+/// might arrive in a single read, and any byte received outside
+/// a request is ignored.
+pub struct RequestFramer {
+    state : FramerState,
+    buffer : Vec<u8>,
+    requests : VecDeque<Result<String, FrameError>>,
+
+    /// Number of bytes ignored since the last request.
+    ignored : usize,
+
+    /// Last byte received outside a request.
+    last_ignored : u8,
+
+    /// Whether the console announced it has been reset.
+    hello : bool
+}
+
+impl RequestFramer {
+    pub fn new() -> RequestFramer {
+        RequestFramer {
+            state : FramerState::Idle,
+            buffer : Vec::with_capacity(MAX_REQUEST_LENGTH),
+            requests : VecDeque::new(),
+            ignored : 0,
+            last_ignored : 0,
+            hello : false
+        }
+    }
+
+    /// Drops any partial or pending request, e.g.:
+    /// when a new session starts.
+    pub fn reset(&mut self) {
+        *self = RequestFramer::new();
+    }
+
+    /// This function processes data received from the console.
+    /// Complete requests, and malformed ones, are queued until
+    /// retrieved by next_request().
+    pub fn push(&mut self, data : &[u8]) {
+        for &byte in data {
+            match self.state {
+                FramerState::Idle => {
+                    if byte == REQUEST_START {
+                        self.start();
+                    }
+                    else
+                    {
+                        if self.last_ignored == HELLO && byte == 0 {
+                            self.hello = true;
+                        }
+
+                        self.last_ignored = byte;
+                        self.ignored += 1;
+                    }
+                },
+                FramerState::InRequest => {
+                    if byte == REQUEST_END {
+                        let request = std::mem::take(&mut self.buffer);
+
+                        self.requests.push_back(String::from_utf8(request)
+                                                       .map_err(|e| FrameError::InvalidText(e.into_bytes())));
+                        self.state = FramerState::Idle;
+                    }
+                    else if byte == REQUEST_START {
+                        let request = std::mem::take(&mut self.buffer);
+
+                        self.requests.push_back(Err(FrameError::Truncated(request)));
+                        self.start();
+                    }
+                    else if self.buffer.len() == MAX_REQUEST_LENGTH {
+                        self.buffer.clear();
+                        self.requests.push_back(Err(FrameError::TooLong));
+                        self.state = FramerState::Discarding;
+                    }
+                    else
+                    {
+                        self.buffer.push(byte);
+                    }
+                },
+                FramerState::Discarding => {
+                    if byte == REQUEST_START {
+                        self.start();
+                    }
+                    else if byte == REQUEST_END {
+                        self.state = FramerState::Idle;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns whether the console announced it has been reset
+    /// since the last call. See link::HELLO for further details.
+    pub fn take_hello(&mut self) -> bool {
+        std::mem::take(&mut self.hello)
+    }
+
+    /// Returns the oldest request not retrieved yet, if any.
+    pub fn next_request(&mut self) -> Option<Result<String, FrameError>> {
+        self.requests.pop_front()
+    }
+
+    fn start(&mut self) {
+        if self.ignored != 0 {
+            println!("Ignored {} bytes received outside a request", self.ignored);
+            self.ignored = 0;
+        }
+
+        self.buffer.clear();
+        self.last_ignored = 0;
+        self.state = FramerState::InRequest;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns all requests queued by the framer, as text
+    /// if valid or as the error message otherwise.
+    fn requests(framer : &mut RequestFramer) -> Vec<Result<String, String>> {
+        std::iter::from_fn(|| framer.next_request())
+            .map(|r| r.map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn single_request() {
+        let mut framer = RequestFramer::new();
+
+        framer.push(b"#cdrom:\\MAIN.EXE;1@");
+
+        assert_eq!(requests(&mut framer), [Ok(String::from("cdrom:\\MAIN.EXE;1"))]);
+    }
+
+    #[test]
+    fn split_requests() {
+        let mut framer = RequestFramer::new();
+
+        for byte in b"noise#first@#sec".iter() {
+            framer.push(&[*byte]);
+        }
+
+        framer.push(b"ond@#thi");
+
+        assert_eq!(requests(&mut framer), [Ok(String::from("first")), Ok(String::from("second"))]);
+
+        framer.push(b"rd@");
+
+        assert_eq!(requests(&mut framer), [Ok(String::from("third"))]);
+    }
+
+    #[test]
+    fn oversize_request() {
+        let mut framer = RequestFramer::new();
+        let mut data = vec![REQUEST_START];
+
+        data.extend(std::iter::repeat_n(b'a', MAX_REQUEST_LENGTH + 10));
+        data.extend_from_slice(b"@#next@");
+        framer.push(&data);
+
+        let requests = requests(&mut framer);
+
+        assert!(requests[0].is_err());
+        assert_eq!(requests[1..], [Ok(String::from("next"))]);
+
+        // The longest allowed request is still accepted.
+        let mut data = vec![REQUEST_START];
+
+        data.extend(std::iter::repeat_n(b'a', MAX_REQUEST_LENGTH));
+        data.push(REQUEST_END);
+        framer.push(&data);
+
+        assert_eq!(framer.next_request().and_then(|r| r.ok()).map(|r| r.len()), Some(MAX_REQUEST_LENGTH));
+    }
+
+    #[test]
+    fn truncated_request() {
+        let mut framer = RequestFramer::new();
+
+        framer.push(b"#cdrom:\\MA#cdrom:\\MAIN.EXE;1@");
+
+        let requests = requests(&mut framer);
+
+        assert_eq!(requests.len(), 2);
+        assert!(matches!(requests[0], Err(ref e) if e.contains("cdrom:\\\\MA")));
+        assert_eq!(requests[1], Ok(String::from("cdrom:\\MAIN.EXE;1")));
+    }
+
+    #[test]
+    fn invalid_text() {
+        let mut framer = RequestFramer::new();
+
+        framer.push(b"#\xFF\xFE@");
+
+        assert!(matches!(framer.next_request(), Some(Err(FrameError::InvalidText(_)))));
+    }
+
+    #[test]
+    fn hello() {
+        let mut framer = RequestFramer::new();
+
+        framer.push(&[HELLO]);
+        assert!(!framer.take_hello());
+
+        framer.push(&[0]);
+        assert!(framer.take_hello());
+        assert!(!framer.take_hello());
+    }
+}
@@ -0,0 +1,785 @@
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    time::Duration
+};
+
+use crc;
+use link::{self, Link};
+use memory;
+use protocol::{self, Protocol};
+use retry::RetryPolicy;
+use transfer::{self, ACK};
+
+/// Address GDB connects to, unless given on the command line.
+pub const DEFAULT_ADDRESS : &str = "127.0.0.1:3333";
+
+/// Command sent to the debug stub so it replies with the registers
+/// of the stopped program, as STUB_REGISTERS values (32-bit,
+/// little-endian): r0-r31, sr, lo, hi, badvaddr, cause and pc.
+/// They are followed by their CRC-16 (16-bit, little-endian)
+/// if checksums are enabled.
+const READ_REGISTERS_COMMAND : u8 = b'R';
+
+/// Command sent to the debug stub so it sets a register of the
+/// stopped program. It is followed by the register index (8-bit),
+/// as sent by READ_REGISTERS_COMMAND, and its value (32-bit,
+/// little-endian). The stub replies with ACK.
+const WRITE_REGISTER_COMMAND : u8 = b'W';
+
+/// Command sent to the debug stub so the stopped program is resumed.
+/// The instruction cache is flushed first, so breakpoints written
+/// into code are taken into account.
+const CONTINUE_COMMAND : u8 = b'C';
+
+/// Command sent to the debug stub so the running program is stopped.
+/// STOP_REPORT is sent once it has stopped.
+const INTERRUPT_COMMAND : u8 = b'I';
+
+/// Byte sent by the debug stub once the program has stopped, either
+/// because of an exception or INTERRUPT_COMMAND. It is followed by the
+/// exception code found on the cause register (8-bit), or 0 if stopped
+/// by INTERRUPT_COMMAND.
+const STOP_REPORT : u8 = b'T';
+
+/// Number of registers sent by the debug stub.
+const STUB_REGISTERS : usize = 38;
+
+/// Number of registers expected by GDB for MIPS targets, including
+/// floating-point ones, which are reported as unavailable.
+const GDB_REGISTERS : usize = 72;
+
+/// Index of the program counter among registers.
+const PC : usize = 37;
+
+/// MIPS instruction raising a breakpoint exception.
+const BREAK_INSTRUCTION : u32 = 0x0000_000D;
+
+/// Time to wait for the program to stop, or for GDB to
+/// interrupt it, before checking the other side again.
+const POLL_TIME : Duration = Duration::from_millis(20);
+
+/// Byte sent by GDB to interrupt the running program.
+const GDB_INTERRUPT : u8 = 0x03;
+
+/// Signals reported to GDB.
+const SIGINT : u8 = 2;
+const SIGILL : u8 = 4;
+const SIGTRAP : u8 = 5;
+const SIGFPE : u8 = 8;
+const SIGBUS : u8 = 10;
+
+/// This structure translates requests sent by GDB
+/// into requests to the debug stub on the console.
+struct Bridge<'a> {
+    port : &'a mut Link,
+    protocol : &'a Protocol,
+    policy : &'a RetryPolicy,
+
+    /// Original instructions replaced by breakpoints, by address.
+    breakpoints : HashMap<u32, u32>,
+
+    /// Signal that stopped the program the last time.
+    signal : u8
+}
+
+/// This function waits for GDB to connect to the given address,
+/// and then serves its requests until it kills the program.
+/// GDB can connect again after detaching.
+pub fn run(port : &mut Link, protocol : &Protocol, policy : &RetryPolicy, address : &str) -> io::Result<()> {
+    if !protocol.supports(protocol::DEBUG_STUB) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "The console does not support debugging"))
+    }
+
+    let listener = TcpListener::bind(address)?;
+
+    let mut bridge = Bridge {
+        port,
+        protocol,
+        policy,
+        breakpoints : HashMap::new(),
+        signal : SIGTRAP
+    };
+
+    loop {
+        println!("Waiting for GDB on {}", address);
+
+        let (mut stream, peer) = listener.accept()?;
+
+        println!("GDB connected from {}", peer);
+
+        stream.set_nodelay(true)?;
+
+        // GDB expects the program to be stopped once connected.
+        bridge.signal = bridge.interrupt()?;
+
+        match bridge.serve(&mut stream) {
+            Ok(true) => return Ok(()),
+            Ok(false) => println!("GDB detached"),
+            Err(e) => println!("GDB disconnected: {}", e)
+        }
+    }
+}
+
+impl<'a> Bridge<'a> {
+    /// This function serves requests sent by GDB until it detaches,
+    /// where false is returned, or kills the program, where true
+    /// is returned instead.
+    fn serve(&mut self, stream : &mut TcpStream) -> io::Result<bool> {
+        loop {
+            let (command, args) = split_packet(&read_packet(stream)?);
+            let (command, args) = (command.as_str(), args.as_str());
+
+            let reply = match command {
+                "?" => format!("S{:02x}", self.signal),
+                "g" => self.read_registers(),
+                "G" => self.write_registers(args),
+                "p" => self.read_register(args),
+                "P" => self.write_register(args),
+                "m" => self.read_memory(args),
+                "M" => self.write_memory(args),
+                "Z" | "z" => self.set_breakpoint(command == "Z", args),
+                "c" | "s" => {
+                    match self.resume(command == "s", args, stream) {
+                        Ok(signal) => format!("S{:02x}", signal),
+                        Err(e) => {
+                            println!("Could not resume the program: {}", e);
+                            String::from("E01")
+                        }
+                    }
+                },
+                "D" | "k" => {
+                    // The program keeps running without breakpoints.
+                    self.clear_breakpoints();
+                    self.command(&[CONTINUE_COMMAND]).ok();
+
+                    if command == "D" {
+                        send_packet(stream, "OK")?;
+                    }
+
+                    return Ok(command == "k")
+                },
+                "H" => String::from("OK"),
+                "q" if args.starts_with("Supported") => String::from("PacketSize=1000"),
+                "q" if args == "Attached" => String::from("1"),
+                "q" if args == "C" => String::from("QC1"),
+                "q" if args == "fThreadInfo" => String::from("m1"),
+                "q" if args == "sThreadInfo" => String::from("l"),
+                // Empty replies tell GDB the request is not supported.
+                _ => String::new()
+            };
+
+            send_packet(stream, &reply)?;
+        }
+    }
+
+    /// Sends a command to the debug stub.
+    fn command(&mut self, command : &[u8]) -> io::Result<()> {
+        (*self.port).send(link::COMMAND, command)
+    }
+
+    /// This function stops the running program, and
+    /// returns the signal to be reported to GDB.
+    fn interrupt(&mut self) -> io::Result<u8> {
+        let mut retries = 0;
+
+        loop {
+            self.command(&[INTERRUPT_COMMAND])?;
+
+            match self.wait_stop(self.policy.timeout(self.policy.ack, retries)) {
+                Ok(Some(signal)) => return Ok(signal),
+                Ok(None) => retries += 1,
+                Err(e) => return Err(e)
+            }
+
+            if !self.policy.allows(retries) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "The program could not be stopped"))
+            }
+        }
+    }
+
+    /// This function waits up to timeout for STOP_REPORT, and returns
+    /// the signal to be reported to GDB, or None if not received.
+    fn wait_stop(&mut self, timeout : Duration) -> io::Result<Option<u8>> {
+        let mut buffer : [u8; 1] = [0];
+
+        match transfer::wait_ack(self.port, &mut buffer, timeout) {
+            Ok(1) if buffer[0] == STOP_REPORT => {
+                let mut cause : [u8; 1] = [0];
+
+                (*self.port).set_timeout(self.policy.ack)?;
+                (*self.port).read_exact(&mut cause)?;
+
+                Ok(Some(signal(cause[0])))
+            },
+            Ok(_) => Ok(None),
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// This function reads all registers sent by the debug stub.
+    fn registers(&mut self) -> io::Result<Vec<u32>> {
+        let protocol = self.protocol;
+
+        let data = memory::retry(self.port, self.policy, |port, timeout| {
+            (*port).send(link::COMMAND, &[READ_REGISTERS_COMMAND])?;
+            (*port).set_timeout(timeout)?;
+
+            let mut data : Vec<u8> = vec![0; STUB_REGISTERS * 4];
+
+            (*port).read_exact(&mut data)?;
+
+            if protocol.supports(protocol::CHECKSUMS) {
+                let mut checksum : [u8; 2] = [0; 2];
+
+                (*port).read_exact(&mut checksum)?;
+
+                if u16::from_le_bytes(checksum) != crc::crc16(&data) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "Corrupted registers received"))
+                }
+            }
+
+            Ok(data)
+        })?;
+
+        Ok(data.chunks(4).map(|r| u32::from_le_bytes([r[0], r[1], r[2], r[3]])).collect())
+    }
+
+    /// This function sets a register of the stopped program.
+    fn set_register(&mut self, index : usize, value : u32) -> io::Result<()> {
+        let mut command : Vec<u8> = vec![WRITE_REGISTER_COMMAND, index as u8];
+
+        command.extend_from_slice(&value.to_le_bytes());
+
+        memory::retry(self.port, self.policy, |port, timeout| {
+            (*port).send(link::COMMAND, &command)?;
+            (*port).set_timeout(timeout)?;
+
+            let mut reply : [u8; 1] = [0];
+
+            (*port).read_exact(&mut reply)?;
+
+            match reply[0] {
+                ACK => Ok(()),
+                _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected reply {:#X} to register write", reply[0])))
+            }
+        })
+    }
+
+    fn read_registers(&mut self) -> String {
+        match self.registers() {
+            Ok(registers) => {
+                let mut reply : String = registers.iter().map(|&r| hex(&r.to_le_bytes())).collect();
+
+                for _ in STUB_REGISTERS..GDB_REGISTERS {
+                    reply += "xxxxxxxx";
+                }
+
+                reply
+            },
+            Err(_) => String::from("E01")
+        }
+    }
+
+    fn write_registers(&mut self, args : &str) -> String {
+        let values = match unhex(args) {
+            Some(values) => values,
+            None => return String::from("E01")
+        };
+
+        for (index, value) in values.chunks_exact(4).take(STUB_REGISTERS).enumerate() {
+            if self.set_register(index, u32::from_le_bytes([value[0], value[1], value[2], value[3]])).is_err() {
+                return String::from("E01")
+            }
+        }
+
+        String::from("OK")
+    }
+
+    fn read_register(&mut self, args : &str) -> String {
+        match usize::from_str_radix(args, 16) {
+            Ok(index) if index < STUB_REGISTERS => match self.registers() {
+                Ok(registers) => hex(&registers[index].to_le_bytes()),
+                Err(_) => String::from("E01")
+            },
+            Ok(_) => String::from("xxxxxxxx"),
+            Err(_) => String::from("E01")
+        }
+    }
+
+    fn write_register(&mut self, args : &str) -> String {
+        let (index, value) = match args.split_once('=') {
+            Some((index, value)) => (usize::from_str_radix(index, 16).ok(), unhex(value)),
+            None => return String::from("E01")
+        };
+
+        match (index, value) {
+            (Some(index), Some(ref value)) if index < STUB_REGISTERS && value.len() == 4 => {
+                match self.set_register(index, u32::from_le_bytes([value[0], value[1], value[2], value[3]])) {
+                    Ok(_) => String::from("OK"),
+                    Err(_) => String::from("E01")
+                }
+            },
+            // Registers not handled by the debug stub are ignored.
+            (Some(_), Some(_)) => String::from("OK"),
+            _ => String::from("E01")
+        }
+    }
+
+    fn read_memory(&mut self, args : &str) -> String {
+        match parse_range(args) {
+            Some((address, length)) => match memory::read(self.port, self.protocol, self.policy, address, 1, length) {
+                Ok(data) => hex(&self.hide_breakpoints(address, data)),
+                Err(_) => String::from("E01")
+            },
+            None => String::from("E01")
+        }
+    }
+
+    fn write_memory(&mut self, args : &str) -> String {
+        let (range, data) = match args.split_once(':') {
+            Some((range, data)) => (parse_range(range), unhex(data)),
+            None => return String::from("E01")
+        };
+
+        match (range, data) {
+            (Some((address, length)), Some(data)) if data.len() == length => {
+                match memory::write(self.port, self.protocol, self.policy, address, 1, &data) {
+                    Ok(_) => String::from("OK"),
+                    Err(_) => String::from("E01")
+                }
+            },
+            _ => String::from("E01")
+        }
+    }
+
+    /// Returns the given data read from memory, where breakpoints
+    /// are replaced by the instructions they replaced.
+    fn hide_breakpoints(&self, address : u32, mut data : Vec<u8>) -> Vec<u8> {
+        for (&breakpoint, &instruction) in self.breakpoints.iter() {
+            let offset = breakpoint.wrapping_sub(address) as usize;
+
+            if offset + 4 <= data.len() {
+                data[offset..offset + 4].copy_from_slice(&instruction.to_le_bytes());
+            }
+        }
+
+        data
+    }
+
+    /// This function handles Z and z requests. Only software
+    /// breakpoints are supported, which are implemented by
+    /// replacing instructions with BREAK_INSTRUCTION.
+    fn set_breakpoint(&mut self, insert : bool, args : &str) -> String {
+        let mut fields = args.split(',');
+
+        let address = match (fields.next(), fields.next().map(|a| u32::from_str_radix(a, 16))) {
+            (Some("0"), Some(Ok(address))) if address % 4 == 0 => address,
+            _ => return String::new()
+        };
+
+        let result = if insert {
+            self.insert_breakpoint(address)
+        }
+        else
+        {
+            self.remove_breakpoint(address)
+        };
+
+        match result {
+            Ok(_) => String::from("OK"),
+            Err(_) => String::from("E01")
+        }
+    }
+
+    fn insert_breakpoint(&mut self, address : u32) -> io::Result<()> {
+        if !self.breakpoints.contains_key(&address) {
+            let instruction = self.read_word(address)?;
+
+            memory::write(self.port, self.protocol, self.policy, address, 4, &BREAK_INSTRUCTION.to_le_bytes())?;
+
+            self.breakpoints.insert(address, instruction);
+        }
+
+        Ok(())
+    }
+
+    fn remove_breakpoint(&mut self, address : u32) -> io::Result<()> {
+        if let Some(instruction) = self.breakpoints.remove(&address) {
+            memory::write(self.port, self.protocol, self.policy, address, 4, &instruction.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn clear_breakpoints(&mut self) {
+        let addresses : Vec<u32> = self.breakpoints.keys().cloned().collect();
+
+        for address in addresses {
+            if let Err(e) = self.remove_breakpoint(address) {
+                println!("Could not remove breakpoint at {:#X}: {}", address, e);
+            }
+        }
+    }
+
+    fn read_word(&mut self, address : u32) -> io::Result<u32> {
+        let data = memory::read(self.port, self.protocol, self.policy, address, 4, 1)?;
+
+        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    /// This function resumes the program, from the given address if
+    /// any, until it stops again, or only runs a single instruction if
+    /// step is true. GDB can interrupt the program in the meantime.
+    /// Returns the signal to be reported to GDB.
+    fn resume(&mut self, step : bool, args : &str, stream : &mut TcpStream) -> io::Result<u8> {
+        if let Ok(address) = u32::from_str_radix(args, 16) {
+            self.set_register(PC, address)?;
+        }
+
+        let registers = self.registers()?;
+        let pc = registers[PC];
+
+        // A breakpoint at the current instruction would stop the
+        // program right away, so it is stepped over first.
+        if let Some(instruction) = self.breakpoints.get(&pc).cloned() {
+            self.remove_breakpoint(pc)?;
+
+            let signal = self.step(pc, instruction, &registers, stream);
+
+            self.insert_breakpoint(pc)?;
+
+            match signal? {
+                SIGTRAP if !step => {},
+                signal => {
+                    self.signal = signal;
+                    return Ok(signal)
+                }
+            }
+        }
+        else if step {
+            let instruction = self.read_word(pc)?;
+
+            self.signal = self.step(pc, instruction, &registers, stream)?;
+            return Ok(self.signal)
+        }
+
+        self.signal = self.run_until_stop(stream)?;
+
+        Ok(self.signal)
+    }
+
+    /// This function runs a single instruction, found at pc, by
+    /// placing temporary breakpoints at every instruction that
+    /// might be run after it.
+    fn step(&mut self, pc : u32, instruction : u32, registers : &[u32], stream : &mut TcpStream) -> io::Result<u8> {
+        let mut temporary : Vec<u32> = Vec::new();
+
+        for address in next_addresses(pc, instruction, registers) {
+            if address != pc && !self.breakpoints.contains_key(&address) {
+                self.insert_breakpoint(address)?;
+                temporary.push(address);
+            }
+        }
+
+        let signal = self.run_until_stop(stream);
+
+        for address in temporary {
+            self.remove_breakpoint(address)?;
+        }
+
+        signal
+    }
+
+    /// This function resumes the program and waits until it stops,
+    /// interrupting it if requested by GDB.
+    fn run_until_stop(&mut self, stream : &mut TcpStream) -> io::Result<u8> {
+        self.command(&[CONTINUE_COMMAND])?;
+
+        stream.set_nonblocking(true)?;
+
+        let result = loop {
+            match self.wait_stop(POLL_TIME) {
+                Ok(Some(signal)) => break Ok(signal),
+                Ok(None) => {},
+                Err(e) => break Err(e)
+            }
+
+            let mut byte : [u8; 1] = [0];
+
+            match stream.read(&mut byte) {
+                Ok(1) if byte[0] == GDB_INTERRUPT => break self.interrupt(),
+                Ok(0) => break Err(io::Error::from(io::ErrorKind::ConnectionAborted)),
+                Ok(_) => {},
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => break Err(e)
+            }
+        };
+
+        stream.set_nonblocking(false)?;
+
+        result
+    }
+}
+
+/// Returns the signal reported to GDB for the given exception code.
+fn signal(cause : u8) -> u8 {
+    match cause {
+        0 => SIGINT,
+        4..=7 => SIGBUS,
+        10 | 11 => SIGILL,
+        12 => SIGFPE,
+        _ => SIGTRAP
+    }
+}
+
+/// This function returns the addresses of all instructions that
+/// might be run after the one found at pc, besides the delay slot
+/// of branches and jumps, given the registers of the program.
+fn next_addresses(pc : u32, instruction : u32, registers : &[u32]) -> Vec<u32> {
+    let rs = ((instruction >> 21) & 0x1F) as usize;
+    let branch = pc.wrapping_add(4).wrapping_add((((instruction & 0xFFFF) as i16 as i32) << 2) as u32);
+
+    match instruction >> 26 {
+        // JR and JALR.
+        0 if instruction & 0x3F == 8 || instruction & 0x3F == 9 => vec![registers[rs]],
+
+        // J and JAL.
+        2 | 3 => vec![(pc.wrapping_add(4) & 0xF000_0000) | ((instruction & 0x03FF_FFFF) << 2)],
+
+        // REGIMM branches, BEQ, BNE, BLEZ and BGTZ.
+        1 | 4..=7 => vec![branch, pc.wrapping_add(8)],
+
+        // Coprocessor branches.
+        16..=19 if rs == 8 => vec![branch, pc.wrapping_add(8)],
+
+        _ => vec![pc.wrapping_add(4)]
+    }
+}
+
+/// This function parses a memory range sent by GDB as ADDRESS,LENGTH.
+fn parse_range(range : &str) -> Option<(u32, usize)> {
+    let (address, length) = range.split_once(',')?;
+
+    let (address, length) = (u32::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?);
+
+    memory::check_range(address, length).ok().map(|_| (address, length))
+}
+
+/// This function splits a packet sent by GDB into its command, made
+/// of its first byte, and its arguments. The command is split from
+/// the raw packet, since its first byte might not be a character on
+/// its own. Invalid characters are replaced, so they match nothing.
+fn split_packet(packet : &[u8]) -> (String, String) {
+    let (command, args) = packet.split_at(std::cmp::min(1, packet.len()));
+
+    (String::from_utf8_lossy(command).into_owned(), String::from_utf8_lossy(args).into_owned())
+}
+
+fn hex(data : &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// This function decodes hex text sent by GDB. Returns None unless
+/// the text is made of pairs of hex digits; signs are not accepted.
+fn unhex(text : &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None
+    }
+
+    (0..text.len()).step_by(2).map(|pos| u8::from_str_radix(text.get(pos..pos + 2)?, 16).ok()).collect()
+}
+
+/// This function waits for the next packet sent by GDB, made of
+/// '$', its data, '#' and a two-digit checksum. Each packet is
+/// acknowledged with '+', or '-' if corrupted so GDB sends it again.
+fn read_packet(stream : &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut byte : [u8; 1] = [0];
+
+    loop {
+        // Acknowledgements and interruptions received
+        // while the program is stopped are ignored.
+        loop {
+            stream.read_exact(&mut byte)?;
+
+            if byte[0] == b'$' {
+                break
+            }
+        }
+
+        let mut data : Vec<u8> = Vec::new();
+
+        loop {
+            stream.read_exact(&mut byte)?;
+
+            if byte[0] == b'#' {
+                break
+            }
+
+            data.push(byte[0]);
+        }
+
+        let mut checksum : [u8; 2] = [0; 2];
+
+        stream.read_exact(&mut checksum)?;
+
+        let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+
+        if expected == Some(data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))) {
+            stream.write_all(b"+")?;
+
+            return Ok(data)
+        }
+
+        stream.write_all(b"-")?;
+    }
+}
+
+fn send_packet(stream : &mut TcpStream, data : &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+
+    stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PC_ADDRESS : u32 = 0x8001_0000;
+
+    /// Returns a connected pair of streams, the first one
+    /// standing for GDB and the second one for the bridge.
+    fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Could not listen");
+        let gdb = TcpStream::connect(listener.local_addr().expect("Could not get address")).expect("Could not connect");
+        let (bridge, _) = listener.accept().expect("Could not accept connection");
+
+        (gdb, bridge)
+    }
+
+    fn registers() -> Vec<u32> {
+        let mut registers : Vec<u32> = (0..STUB_REGISTERS as u32).map(|r| 0x8000_0000 | (r << 4)).collect();
+
+        registers[PC] = PC_ADDRESS;
+        registers
+    }
+
+    #[test]
+    fn unhex_valid_text() {
+        assert_eq!(unhex(""), Some(vec![]));
+        assert_eq!(unhex("00ff7A"), Some(vec![0x00, 0xFF, 0x7A]));
+    }
+
+    #[test]
+    fn unhex_invalid_text() {
+        assert_eq!(unhex("0"), None);
+        assert_eq!(unhex("zz"), None);
+        assert_eq!(unhex("+1"), None);
+        // Characters taking more than one byte never panic.
+        assert_eq!(unhex("0é0"), None);
+        assert_eq!(unhex("éé"), None);
+        assert_eq!(unhex("é"), None);
+    }
+
+    #[test]
+    fn split_packets() {
+        assert_eq!(split_packet(b"m80010000,4"), (String::from("m"), String::from("80010000,4")));
+        assert_eq!(split_packet(b"g"), (String::from("g"), String::new()));
+        assert_eq!(split_packet(b""), (String::new(), String::new()));
+    }
+
+    #[test]
+    fn split_non_ascii_packets() {
+        // The first byte of a character taking two bytes.
+        let (command, args) = split_packet("émoji".as_bytes());
+
+        assert_eq!(command, "\u{FFFD}");
+        assert_eq!(args, "\u{FFFD}moji");
+
+        let (command, args) = split_packet(&[b'M', 0xFF, b'0']);
+
+        assert_eq!(command, "M");
+        assert_eq!(args, "\u{FFFD}0");
+    }
+
+    #[test]
+    fn packets_with_bad_checksums_sent_again() {
+        let (mut gdb, mut bridge) = connection();
+
+        // Stray acknowledgements and a corrupted packet,
+        // followed by the same packet sent again.
+        gdb.write_all(b"++$m0,4#00$m0,4#").expect("Could not send packet");
+        gdb.write_all(format!("{:02x}", b"m0,4".iter().fold(0u8, |sum, &b| sum.wrapping_add(b))).as_bytes())
+           .expect("Could not send packet");
+
+        assert_eq!(read_packet(&mut bridge).expect("Could not read packet"), b"m0,4");
+
+        let mut replies : [u8; 2] = [0; 2];
+
+        gdb.read_exact(&mut replies).expect("Could not read acknowledgements");
+        assert_eq!(&replies, b"-+");
+    }
+
+    #[test]
+    fn packets_with_invalid_checksums_rejected() {
+        let (mut gdb, mut bridge) = connection();
+
+        gdb.write_all("$?#é$?#3f".as_bytes()).expect("Could not send packet");
+
+        assert_eq!(read_packet(&mut bridge).expect("Could not read packet"), b"?");
+
+        let mut replies : [u8; 2] = [0; 2];
+
+        gdb.read_exact(&mut replies).expect("Could not read acknowledgements");
+        assert_eq!(&replies, b"-+");
+    }
+
+    #[test]
+    fn packets_sent_with_checksum() {
+        let (mut gdb, mut bridge) = connection();
+        let mut packet : [u8; 6] = [0; 6];
+
+        send_packet(&mut bridge, "OK").expect("Could not send packet");
+        gdb.read_exact(&mut packet).expect("Could not read packet");
+        assert_eq!(&packet, b"$OK#9a");
+    }
+
+    #[test]
+    fn next_addresses_of_jumps() {
+        let registers = registers();
+
+        // JR $ra and JALR $t9.
+        assert_eq!(next_addresses(PC_ADDRESS, 0x03E0_0008, &registers), [registers[31]]);
+        assert_eq!(next_addresses(PC_ADDRESS, 0x0320_F809, &registers), [registers[25]]);
+
+        // J and JAL to 0x80010100.
+        assert_eq!(next_addresses(PC_ADDRESS, 0x0800_4040, &registers), [0x8001_0100]);
+        assert_eq!(next_addresses(PC_ADDRESS, 0x0C00_4040, &registers), [0x8001_0100]);
+    }
+
+    #[test]
+    fn next_addresses_of_branches() {
+        let registers = registers();
+
+        // BEQ $0, $0 back to itself.
+        assert_eq!(next_addresses(PC_ADDRESS, 0x1000_FFFF, &registers), [PC_ADDRESS, PC_ADDRESS + 8]);
+        // BNE forward.
+        assert_eq!(next_addresses(PC_ADDRESS, 0x1422_0010, &registers), [PC_ADDRESS + 4 + 0x40, PC_ADDRESS + 8]);
+        // BLTZ, a REGIMM branch.
+        assert_eq!(next_addresses(PC_ADDRESS, 0x0440_0003, &registers), [PC_ADDRESS + 16, PC_ADDRESS + 8]);
+        // BC2T, a coprocessor branch.
+        assert_eq!(next_addresses(PC_ADDRESS, 0x4901_0005, &registers), [PC_ADDRESS + 24, PC_ADDRESS + 8]);
+    }
+
+    #[test]
+    fn next_addresses_of_other_instructions() {
+        let registers = registers();
+
+        // ADDIU, MFC0 and a BREAK.
+        for instruction in [0x27BD_FFE8, 0x4002_6000, BREAK_INSTRUCTION] {
+            assert_eq!(next_addresses(PC_ADDRESS, instruction, &registers), [PC_ADDRESS + 4]);
+        }
+    }
+}
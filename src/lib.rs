@@ -0,0 +1,36 @@
+//! Host side of the serial protocol used to send executables and
+//! files to a PlayStation running a loader. Besides the command line
+//! tool, the console RAM can be read and written from other programs
+//! through memory::connect(), memory::read() and memory::write().
+//! Symbols of the uploaded program, read from its ELF file or map
+//! file, are available through symbols::SymbolTable, so addresses
+//! can be given as symbols with symbols::resolve().
+
+extern crate serial;
+extern crate regex;
+#[macro_use] extern crate lazy_static;
+#[cfg(target_os = "linux")] extern crate inotify;
+// Only needed by the command line tool, so they are
+// not part of the library interface.
+#[doc(hidden)] pub mod cmdline;
+#[doc(hidden)] pub mod app;
+mod transfer;
+pub mod protocol;
+mod crc;
+mod lz;
+mod payload;
+mod pcdrv;
+mod console;
+mod framer;
+pub mod link;
+pub mod retry;
+pub mod pacing;
+mod watch;
+mod raw;
+pub mod memory;
+pub mod symbols;
+mod dwarf;
+pub mod monitor;
+mod gdb;
+mod crash;
+mod runner;
@@ -0,0 +1,535 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    time::{Duration, Instant}
+};
+
+use serial::{self, SerialPort};
+
+use console::{self, Console};
+use crc;
+use pacing::{Pacing, Pacer};
+
+/// Byte ending each frame. Since frames are COBS-encoded, it never
+/// appears inside them, so both sides can always find where the next
+/// frame starts, no matter how much noise has been received.
+const FRAME_DELIMITER : u8 = 0;
+
+/// Largest payload carried by a single frame.
+pub const MAX_FRAME_PAYLOAD : usize = 4096;
+
+/// Frame carrying a request sent by the console,
+/// without the '#' and '@' bytes used when unframed.
+pub const REQUEST : u8 = 1;
+
+/// Frame carrying a reply or acknowledgement,
+/// e.g.: ACK, NAK and its packet index, ERROR_REPLY.
+pub const REPLY : u8 = 2;
+
+/// Frame carrying data, e.g.: sizes, packets or directory entries.
+pub const DATA : u8 = 3;
+
+/// Frame carrying debug text sent by the console.
+pub const DEBUG : u8 = 4;
+
+/// Frame carrying a command sent by the host, e.g.:
+/// asking the console to return to the loader.
+pub const COMMAND : u8 = 5;
+
+/// Byte sent by loaders when they start, followed by a zero byte,
+/// so the host starts a new session even if it was in the middle
+/// of another one. It is never framed, since framing is agreed
+/// during the handshake, and its value matches the initial
+/// transmission sent by the host.
+pub const HELLO : u8 = 99;
+
+/// Serial control lines that can be wired to the
+/// reset signal of the console.
+#[derive(Copy, Clone)]
+pub enum ResetLine {
+    Dtr,
+    Rts
+}
+
+impl ResetLine {
+    pub fn parse(line : &str) -> Option<ResetLine> {
+        match line {
+            "dtr" => Some(ResetLine::Dtr),
+            "rts" => Some(ResetLine::Rts),
+            _ => None
+        }
+    }
+}
+
+/// This function encodes data using Consistent Overhead Byte
+/// Stuffing, so the result does not contain FRAME_DELIMITER.
+fn cobs_encode(data : &[u8], output : &mut Vec<u8>) {
+    let mut code_pos = output.len();
+    let mut code : u8 = 1;
+
+    output.push(0);
+
+    for &byte in data {
+        if byte != 0 {
+            output.push(byte);
+            code += 1;
+        }
+
+        if byte == 0 || code == 0xFF {
+            output[code_pos] = code;
+            code_pos = output.len();
+            code = 1;
+            output.push(0);
+        }
+    }
+
+    output[code_pos] = code;
+}
+
+/// This function decodes data encoded by cobs_encode().
+/// Returns None if data is not valid.
+fn cobs_decode(data : &[u8]) -> Option<Vec<u8>> {
+    let mut output : Vec<u8> = Vec::with_capacity(data.len());
+    let mut pos = 0;
+
+    while pos < data.len() {
+        let code = data[pos] as usize;
+        let end = pos + code;
+
+        if code == 0 || end > data.len() {
+            return None
+        }
+
+        output.extend_from_slice(&data[pos + 1..end]);
+        pos = end;
+
+        if code != 0xFF && pos < data.len() {
+            output.push(0);
+        }
+    }
+
+    Some(output)
+}
+
+/// This structure carries all messages exchanged with the console.
+///
+/// Old loaders use raw bytes with no framing at all, so any noise
+/// on the line desynchronises both sides. Once framing is agreed
+/// during the handshake, every message travels instead inside a
+/// frame made of:
+/// - Type (8-bit), e.g.: REQUEST, REPLY, DATA or DEBUG.
+/// - Payload length (16-bit, little-endian).
+/// - Payload.
+/// - CRC-16 of all previous fields (16-bit, little-endian).
+///
+/// Frames are COBS-encoded and followed by FRAME_DELIMITER.
+/// Corrupted frames are discarded, so the console or the host
+/// time out and ask for the message again.
+pub struct Link {
+    port : serial::SystemPort,
+
+    /// Whether messages are sent and received inside frames.
+    framed : bool,
+
+    /// Bytes received which do not make a whole frame yet.
+    received : Vec<u8>,
+
+    /// Payload of the last received frame, not read yet,
+    /// or data received when unframed, not read yet.
+    pending : VecDeque<u8>,
+
+    /// Separates debug text from data received when unframed.
+    demux : console::Demux,
+
+    /// Handles debug text sent by the console.
+    console : Console,
+
+    /// Pacing applied to all data sent to the console.
+    pacer : Pacer,
+
+    /// Time when data was last received from the console.
+    last_received : Instant
+}
+
+impl Link {
+    pub fn new(port : serial::SystemPort) -> Link {
+        Link {
+            port,
+            framed : false,
+            received : Vec::new(),
+            pending : VecDeque::new(),
+            demux : console::Demux::new(),
+            console : Console::new(),
+            pacer : Pacer::new(Pacing::None, 0),
+            last_received : Instant::now()
+        }
+    }
+
+    /// Sets the pacing policy applied to all data sent to the
+    /// console, given the speed of the serial link in bits per second.
+    pub fn set_pacing(&mut self, pacing : Pacing, baud_rate : u64) {
+        self.pacer = Pacer::new(pacing, baud_rate);
+    }
+
+    /// This function resets pacing when a new session starts.
+    /// See Pacer::start() for further details.
+    pub fn start_pacing(&mut self, legacy : bool) {
+        self.pacer.start(legacy);
+    }
+
+    /// Enables or disables framing. Any data
+    /// received but not read yet is dropped.
+    pub fn set_framed(&mut self, framed : bool) {
+        self.framed = framed;
+        self.received.clear();
+        self.pending.clear();
+        self.demux = console::Demux::new();
+    }
+
+    /// Returns whether messages are sent and received inside frames.
+    pub fn is_framed(&self) -> bool {
+        self.framed
+    }
+
+    /// Returns the handler of debug text sent by the console,
+    /// e.g.: to set the symbols used to decode crash reports.
+    pub fn console(&mut self) -> &mut Console {
+        &mut self.console
+    }
+
+    /// Returns the time elapsed since data, including
+    /// debug text, was last received from the console.
+    pub fn silence(&self) -> Duration {
+        self.last_received.elapsed()
+    }
+
+    /// Drops any data sent by the console but not read yet,
+    /// e.g.: replies to a previous session.
+    pub fn clear_input(&mut self) {
+        let mut buffer : [u8; 256] = [0; 256];
+
+        if self.port.set_timeout(Duration::from_millis(10)).is_ok() {
+            while let Ok(n) = self.port.read(&mut buffer) {
+                if n == 0 {
+                    break
+                }
+            }
+        }
+
+        self.received.clear();
+        self.pending.clear();
+        self.demux = console::Demux::new();
+    }
+
+    /// This function resets the console by
+    /// asserting the given line for a short time.
+    pub fn pulse(&mut self, line : ResetLine) -> serial::Result<()> {
+        const PULSE_TIME : Duration = Duration::from_millis(100);
+
+        let set = |port : &mut serial::SystemPort, level| match line {
+            ResetLine::Dtr => port.set_dtr(level),
+            ResetLine::Rts => port.set_rts(level)
+        };
+
+        set(&mut self.port, true)?;
+        std::thread::sleep(PULSE_TIME);
+        set(&mut self.port, false)
+    }
+
+    /// Sets the time to wait for data from the console.
+    pub fn set_timeout(&mut self, timeout : Duration) -> serial::Result<()> {
+        self.port.set_timeout(timeout)
+    }
+
+    /// This function sends a message of the given type. Type is
+    /// ignored when unframed, so data is sent as is.
+    pub fn send(&mut self, kind : u8, data : &[u8]) -> io::Result<()> {
+        if !self.framed {
+            return self.port.write_all(data)
+        }
+
+        for chunk in data.chunks(MAX_FRAME_PAYLOAD) {
+            let mut frame : Vec<u8> = Vec::with_capacity(chunk.len() + 5);
+
+            frame.push(kind);
+            frame.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            frame.extend_from_slice(chunk);
+
+            let checksum = crc::crc16(&frame);
+
+            frame.extend_from_slice(&checksum.to_le_bytes());
+
+            let mut encoded : Vec<u8> = Vec::with_capacity(frame.len() + frame.len() / 254 + 2);
+
+            cobs_encode(&frame, &mut encoded);
+            encoded.push(FRAME_DELIMITER);
+
+            self.port.write_all(&encoded)?;
+        }
+
+        Ok(())
+    }
+
+    /// This function waits for the next valid frame, only available
+    /// when framed. Debug text frames are printed as they arrive, and
+    /// corrupted frames are discarded. Returns the frame type and its
+    /// payload, or an error if nothing is received before timeout.
+    pub fn receive(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        loop {
+            match self.receive_any()? {
+                (DEBUG, _) => {},
+                frame => return Ok(frame)
+            }
+        }
+    }
+
+    /// This function behaves as receive(), but debug text
+    /// frames are also returned once they have been printed.
+    pub fn receive_any(&mut self) -> io::Result<(u8, Vec<u8>)> {
+        loop {
+            if let Some(end) = self.received.iter().position(|&b| b == FRAME_DELIMITER) {
+                let encoded : Vec<u8> = self.received.drain(..=end).collect();
+
+                match Link::decode(&encoded[..end]) {
+                    Some((DEBUG, text)) => {
+                        self.console.print(&text);
+                        return Ok((DEBUG, text))
+                    },
+                    Some(frame) => return Ok(frame),
+                    None if end == 0 => {},
+                    // The console has been reset. It is
+                    // returned as a reply, as when unframed.
+                    None if encoded[..end] == [HELLO] => return Ok((REPLY, vec![HELLO])),
+                    None => println!("Discarded corrupted frame ({} bytes)", end)
+                }
+            }
+            else
+            {
+                let mut buffer : [u8; 256] = [0; 256];
+
+                match self.read_port(&mut buffer)? {
+                    0 => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+                    n => self.received.extend_from_slice(&buffer[..n])
+                }
+            }
+        }
+    }
+
+    /// This function behaves as read() when unframed, but returns
+    /// 0 once debug text has been received, even if no other data
+    /// has, so the caller can follow the output. When framed, use
+    /// receive_any() instead.
+    pub fn read_any(&mut self, buffer : &mut [u8]) -> io::Result<usize> {
+        self.read_unframed(buffer, true)
+    }
+
+    /// This function reads data received when unframed, once debug
+    /// text has been taken out of it. See console::DEBUG_FRAME.
+    /// Bytes held by the demultiplexer are released if nothing
+    /// else is received before timeout.
+    fn read_unframed(&mut self, buffer : &mut [u8], return_on_text : bool) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            // No more bytes than asked for are read, so no data
+            // that might be framed later on is taken in advance.
+            let mut raw : Vec<u8> = vec![0; std::cmp::min(buffer.len(), 256)];
+            let mut data : Vec<u8> = Vec::new();
+
+            let found_text = match self.read_port(&mut raw) {
+                Ok(0) | Err(_) if self.demux.is_pending() => self.demux.flush(&mut data, &mut self.console),
+                Ok(0) => return Ok(0),
+                Ok(n) => self.demux.feed(&raw[..n], &mut data, &mut self.console),
+                Err(e) => return Err(e)
+            };
+
+            self.pending.extend(data);
+
+            if found_text && return_on_text && self.pending.is_empty() {
+                return Ok(0)
+            }
+        }
+
+        let n = std::cmp::min(buffer.len(), self.pending.len());
+
+        for (dst, src) in buffer.iter_mut().zip(self.pending.drain(..n)) {
+            *dst = src;
+        }
+
+        Ok(n)
+    }
+
+    /// This function reads data from the serial port, so the
+    /// time taken by the console to reply is tracked for pacing.
+    fn read_port(&mut self, buffer : &mut [u8]) -> io::Result<usize> {
+        let n = self.port.read(buffer)?;
+
+        if n != 0 {
+            self.pacer.received();
+            self.last_received = Instant::now();
+        }
+
+        Ok(n)
+    }
+
+    fn decode(encoded : &[u8]) -> Option<(u8, Vec<u8>)> {
+        let frame = cobs_decode(encoded)?;
+
+        if frame.len() < 5 {
+            return None
+        }
+
+        let length = u16::from_le_bytes([frame[1], frame[2]]) as usize;
+
+        if length != frame.len() - 5 {
+            return None
+        }
+
+        let checksum = u16::from_le_bytes([frame[length + 3], frame[length + 4]]);
+
+        if checksum != crc::crc16(&frame[..length + 3]) {
+            return None
+        }
+
+        Some((frame[0], frame[3..length + 3].to_vec()))
+    }
+}
+
+/// Data read from the link is taken from the payload of
+/// all received frames, one after another, when framed.
+/// If the console announces it has been reset instead, an
+/// error of kind ConnectionReset is returned. See HELLO.
+/// Otherwise, it is read as is, once debug text has been
+/// taken out of it.
+impl Read for Link {
+    fn read(&mut self, buffer : &mut [u8]) -> io::Result<usize> {
+        if !self.framed {
+            return self.read_unframed(buffer, false)
+        }
+
+        while self.pending.is_empty() {
+            match self.receive()? {
+                (REPLY, ref payload) if payload[..] == [HELLO] => {
+                    return Err(io::Error::from(io::ErrorKind::ConnectionReset))
+                },
+                (_, payload) => self.pending.extend(payload)
+            }
+        }
+
+        let n = std::cmp::min(buffer.len(), self.pending.len());
+
+        for (dst, src) in buffer.iter_mut().zip(self.pending.drain(..n)) {
+            *dst = src;
+        }
+
+        Ok(n)
+    }
+}
+
+/// Data written into the link is sent as DATA frames
+/// when framed, where each call builds a single frame.
+/// Each call is considered a packet, so pacing is applied.
+impl Write for Link {
+    fn write(&mut self, data : &[u8]) -> io::Result<usize> {
+        self.pacer.pause();
+        self.send(DATA, data)?;
+        self.pacer.sent(data.len());
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data : &[u8]) -> Vec<u8> {
+        let mut output : Vec<u8> = Vec::new();
+
+        cobs_encode(data, &mut output);
+        output
+    }
+
+    fn round_trip(data : &[u8]) {
+        let encoded = encode(data);
+
+        assert!(!encoded.contains(&FRAME_DELIMITER));
+        assert_eq!(cobs_decode(&encoded).as_deref(), Some(data));
+    }
+
+    /// Returns a frame as built by Link::send(), before COBS encoding.
+    fn frame(kind : u8, payload : &[u8]) -> Vec<u8> {
+        let mut frame = vec![kind];
+
+        frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        frame.extend_from_slice(payload);
+
+        let checksum = crc::crc16(&frame);
+
+        frame.extend_from_slice(&checksum.to_le_bytes());
+        frame
+    }
+
+    #[test]
+    fn cobs_known_vectors() {
+        assert_eq!(encode(&[]), [0x01]);
+        assert_eq!(encode(&[0x00]), [0x01, 0x01]);
+        assert_eq!(encode(&[0x00, 0x00]), [0x01, 0x01, 0x01]);
+        assert_eq!(encode(&[0x11, 0x22, 0x00, 0x33]), [0x03, 0x11, 0x22, 0x02, 0x33]);
+        assert_eq!(encode(&[0x11, 0x00, 0x00, 0x00]), [0x02, 0x11, 0x01, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn cobs_round_trips() {
+        round_trip(&[]);
+        round_trip(&[0; 10]);
+        round_trip(&[0x11, 0x00, 0x22, 0x00]);
+
+        // Runs of non-zero bytes around the longest
+        // one carried by a single code byte (254).
+        for len in [253, 254, 255, 508, 509] {
+            let run : Vec<u8> = (0..len).map(|i| (i % 255) as u8 + 1).collect();
+
+            round_trip(&run);
+            round_trip(&[&[0], run.as_slice(), &[0]].concat());
+        }
+
+        let all : Vec<u8> = (0..4096).map(|i| (i * 7) as u8).collect();
+
+        round_trip(&all);
+    }
+
+    #[test]
+    fn cobs_invalid_data() {
+        assert_eq!(cobs_decode(&[0x00]), None);
+        assert_eq!(cobs_decode(&[0x05, 0x11, 0x22]), None);
+    }
+
+    #[test]
+    fn decode_valid_frames() {
+        for payload in [&b""[..], b"\x00\x00", b"cdrom:\\MAIN.EXE;1"] {
+            let encoded = encode(&frame(DATA, payload));
+
+            assert_eq!(Link::decode(&encoded), Some((DATA, payload.to_vec())));
+        }
+    }
+
+    #[test]
+    fn decode_corrupted_frames() {
+        let mut corrupted = frame(REPLY, b"ack");
+
+        corrupted[4] ^= 0x01;
+        assert_eq!(Link::decode(&encode(&corrupted)), None);
+
+        // Length not matching the payload.
+        let mut short = frame(REPLY, b"ack");
+
+        short[1] = 2;
+        assert_eq!(Link::decode(&encode(&short)), None);
+
+        // Shorter than the header and checksum.
+        assert_eq!(Link::decode(&encode(&[REPLY, 0, 0, 0])), None);
+    }
+}
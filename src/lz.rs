@@ -0,0 +1,259 @@
+/// Maximum distance of a back-reference.
+const WINDOW_SIZE : usize = 4096;
+
+/// Shortest match encoded as a back-reference.
+const MIN_MATCH : usize = 3;
+
+/// Longest match encoded as a back-reference.
+const MAX_MATCH : usize = MIN_MATCH + 15;
+
+/// Maximum number of previous positions checked
+/// when looking for a match, trading speed for ratio.
+const MAX_CHAIN : usize = 64;
+
+/// Number of bits used for the match finder hash table.
+const HASH_BITS : u32 = 15;
+
+/// Marks an empty hash table or chain entry.
+const NONE : usize = usize::MAX;
+
+fn hash(data : &[u8]) -> usize {
+    let value = (data[0] as u32) | ((data[1] as u32) << 8) | ((data[2] as u32) << 16);
+
+    (value.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// This structure finds previous occurrences of the
+/// data being compressed by chaining all positions
+/// that share the same hash for their first bytes.
+struct MatchFinder<'a> {
+    data : &'a [u8],
+    head : Vec<usize>,
+    prev : Vec<usize>
+}
+
+impl<'a> MatchFinder<'a> {
+    fn new(data : &'a [u8]) -> MatchFinder<'a> {
+        MatchFinder {
+            data,
+            head : vec![NONE; 1 << HASH_BITS],
+            prev : vec![NONE; data.len()]
+        }
+    }
+
+    fn insert(&mut self, pos : usize) {
+        if pos + MIN_MATCH <= self.data.len() {
+            let h = hash(&self.data[pos..]);
+
+            self.prev[pos] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+
+    /// Returns the length and distance of the
+    /// longest match found for the given position.
+    fn find(&self, pos : usize) -> (usize, usize) {
+        let max_len = std::cmp::min(MAX_MATCH, self.data.len() - pos);
+        let mut best = (0, 0);
+
+        if max_len < MIN_MATCH {
+            return best
+        }
+
+        let mut candidate = self.head[hash(&self.data[pos..])];
+        let mut depth = 0;
+
+        while candidate != NONE && pos - candidate <= WINDOW_SIZE && depth < MAX_CHAIN {
+            let len = self.data[candidate..]
+                          .iter()
+                          .zip(&self.data[pos..pos + max_len])
+                          .take_while(|&(a, b)| a == b)
+                          .count();
+
+            if len > best.0 {
+                best = (len, pos - candidate);
+
+                if len == max_len {
+                    break
+                }
+            }
+
+            candidate = self.prev[candidate];
+            depth += 1;
+        }
+
+        best
+    }
+}
+
+/// This function compresses data sent to the console with LZSS.
+///
+/// Compressed data is made of groups of up to eight items, each
+/// group preceded by a flag byte whose bits, starting from the
+/// least significant one, describe each item:
+///
+/// - 1: literal byte, copied as is.
+/// - 0: back-reference, two bytes `lo` and `hi`, which copies
+///   `(hi & 0x0F) + 3` bytes starting `(((hi >> 4) << 8) | lo) + 1`
+///   bytes behind the current output position. Both ranges may
+///   overlap, so the decoder must copy byte by byte.
+///
+/// This keeps the decoder on the console small and fast,
+/// as it only needs the output buffer and no extra memory.
+pub fn compress(data : &[u8]) -> Vec<u8> {
+    let mut output : Vec<u8> = Vec::with_capacity(data.len() / 2);
+    let mut finder = MatchFinder::new(data);
+    let mut pos = 0;
+    let mut flag_pos = 0;
+    let mut flag_bit = 8;
+
+    while pos < data.len() {
+        if flag_bit == 8 {
+            flag_pos = output.len();
+            output.push(0);
+            flag_bit = 0;
+        }
+
+        let (len, distance) = finder.find(pos);
+
+        if len >= MIN_MATCH {
+            let offset = distance - 1;
+
+            output.push((offset & 0xFF) as u8);
+            output.push((((offset >> 8) << 4) | (len - MIN_MATCH)) as u8);
+
+            for p in pos..pos + len {
+                finder.insert(p);
+            }
+
+            pos += len;
+        }
+        else
+        {
+            output[flag_pos] |= 1 << flag_bit;
+            output.push(data[pos]);
+            finder.insert(pos);
+            pos += 1;
+        }
+
+        flag_bit += 1;
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// This function mirrors the decoder of the loader running on
+    /// the console, following the format described by compress().
+    fn decompress(data : &[u8]) -> Vec<u8> {
+        let mut output : Vec<u8> = Vec::new();
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let flags = data[pos];
+
+            pos += 1;
+
+            for bit in 0..8 {
+                if pos >= data.len() {
+                    break
+                }
+
+                if flags & (1 << bit) != 0 {
+                    output.push(data[pos]);
+                    pos += 1;
+                }
+                else
+                {
+                    let (lo, hi) = (data[pos] as usize, data[pos + 1] as usize);
+                    let len = (hi & 0x0F) + MIN_MATCH;
+                    let distance = (((hi >> 4) << 8) | lo) + 1;
+
+                    assert!(distance <= output.len(), "Back-reference before the start of the output");
+
+                    // Byte by byte, as both ranges might overlap.
+                    for _ in 0..len {
+                        output.push(output[output.len() - distance]);
+                    }
+
+                    pos += 2;
+                }
+            }
+        }
+
+        output
+    }
+
+    /// Returns data which does not compress, generated
+    /// by a linear congruential generator.
+    fn noise(len : usize) -> Vec<u8> {
+        let mut state : u32 = 12345;
+
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect()
+    }
+
+    fn round_trip(data : &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+
+        assert_eq!(decompress(&compressed), data);
+
+        compressed
+    }
+
+    #[test]
+    fn empty_input() {
+        assert!(round_trip(&[]).is_empty());
+    }
+
+    #[test]
+    fn incompressible_input() {
+        let data = noise(10000);
+        let compressed = round_trip(&data);
+
+        // At worst, a flag byte is added for every eight literals.
+        assert!(compressed.len() <= data.len() + data.len().div_ceil(8));
+    }
+
+    #[test]
+    fn long_runs() {
+        let data = vec![0u8; 100000];
+        let compressed = round_trip(&data);
+
+        // Each back-reference should cover MAX_MATCH bytes.
+        assert!(compressed.len() < data.len() / MAX_MATCH * 3);
+    }
+
+    #[test]
+    fn overlapping_matches() {
+        // A single literal, then a back-reference one byte behind
+        // which overlaps the bytes it writes itself.
+        assert_eq!(compress(b"aaaa"), [0x01, b'a', 0x00, 0x00]);
+
+        // A repeated pattern shorter than each match.
+        let data : Vec<u8> = b"abc".iter().cycle().take(1000).cloned().collect();
+
+        assert!(round_trip(&data).len() < 250);
+    }
+
+    #[test]
+    fn matches_at_the_window_limits() {
+        // The second copy is too far to be referenced as a whole,
+        // but the end of the block is still within the window.
+        let block = noise(WINDOW_SIZE + 100);
+
+        round_trip(&[block.as_slice(), block.as_slice()].concat());
+
+        // The farthest distance which can be encoded.
+        let block = noise(WINDOW_SIZE - MAX_MATCH);
+        let data = [block.as_slice(), &block[..MAX_MATCH], block.as_slice()].concat();
+
+        assert!(round_trip(&data).len() < block.len() * 2);
+    }
+}
@@ -1,20 +1,15 @@
-extern crate serial;
-extern crate regex;
-#[macro_use] extern crate lazy_static;
-mod cmdline;
-mod app;
-mod transfer;
+extern crate rspsxserial;
+
+use rspsxserial::{app, cmdline};
 
 /// Main function.
 fn main() {
     // Read command line arguments.
-    match cmdline::process_arguments() {
-        None => return,
-        Some(hash) => {
-            // Execute application logic.
-            match app::app(hash) {
-                _ => return
-            }
+    if let Some(hash) = cmdline::process_arguments() {
+        // Execute application logic.
+        if let Err(e) = app::app(hash) {
+            println!("{}", e);
+            std::process::exit(1);
         }
     }
 }
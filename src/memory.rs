@@ -0,0 +1,400 @@
+use std::{
+    fs,
+    io::{self, Read},
+    time::Duration
+};
+
+use crc;
+use gdb;
+use link::{self, Link};
+use protocol::{self, Protocol};
+use raw;
+use retry::RetryPolicy;
+use monitor::{self, Item};
+use symbols::{self, SymbolTable};
+use transfer::{ACK, NAK};
+
+/// Command sent to the console so it replies with values read from
+/// its RAM. It can be sent instead of the EXE header or the reply to
+/// the image hash. It is followed by:
+/// - Address (32-bit, little-endian).
+/// - Size of each value in bytes (8-bit): 1, 2 or 4.
+/// - Number of values (32-bit, little-endian).
+///
+/// The console replies with the values (little-endian), followed
+/// by their CRC-16 (16-bit, little-endian) if checksums are enabled.
+/// Values are read with loads of the given size, so hardware
+/// registers can also be read.
+const PEEK_COMMAND : u8 = b'p';
+
+/// Command sent to the console so it writes values into its RAM.
+/// It is followed by the same fields as PEEK_COMMAND, then by the
+/// values (little-endian), and then by their CRC-16 (16-bit,
+/// little-endian) if checksums are enabled. The console replies
+/// with ACK once they have been written, or with NAK if the
+/// checksum did not match.
+const POKE_COMMAND : u8 = b'k';
+
+/// Largest number of bytes read or written by a single command.
+const MAX_CHUNK_SIZE : usize = 2048;
+
+/// Number of bytes shown on each line of a hex dump.
+const HEX_DUMP_WIDTH : usize = 16;
+
+/// This enum defines an operation on the console,
+/// given as a subcommand on the command line.
+pub enum Operation {
+    /// Prints a number of values read from an address.
+    Peek { address : u32, size : usize, count : usize },
+
+    /// Writes values, already encoded as little-endian bytes, into an address.
+    Poke { address : u32, size : usize, values : Vec<u8> },
+
+    /// Reads a range into a file, or prints it as a hex dump.
+    Dump { address : u32, length : usize, file : Option<String> },
+
+    /// Prints the given locations every time they change.
+    Monitor { items : Vec<Item>, interval : Duration },
+
+    /// Forwards requests sent by GDB, listening on the given address.
+    Gdb { address : String }
+}
+
+impl Operation {
+    /// This function parses a subcommand and its parameters, where
+    /// size is the size in bytes of each value read or written, and
+    /// interval is the time between two reads when monitoring.
+    /// Addresses can be given as symbols if available.
+    pub fn parse(name : &str,
+                 params : &[&str],
+                 size : usize,
+                 interval : Duration,
+                 symbols : Option<&SymbolTable>) -> Result<Operation, String> {
+        let number = |index : usize, what : &str| -> Result<u32, String> {
+            match params.get(index) {
+                Some(param) => raw::parse_number(param).ok_or(format!("Invalid {}: {}", what, param)),
+                None => Err(format!("Missing {} for {}", what, name))
+            }
+        };
+
+        if name == "monitor" {
+            if params.is_empty() {
+                return Err(String::from("Missing locations for monitor"))
+            }
+
+            let items = params.iter().map(|item| Item::parse(item, symbols)).collect::<Result<Vec<Item>, String>>()?;
+
+            return Ok(Operation::Monitor { items, interval })
+        }
+
+        if name == "gdb" {
+            let address = params.first().map_or(gdb::DEFAULT_ADDRESS, |address| *address);
+
+            return Ok(Operation::Gdb { address : String::from(address) })
+        }
+
+        let address = symbols::resolve(params.first().ok_or(format!("Missing address for {}", name))?, symbols)?;
+
+        if !(address as usize).is_multiple_of(size) {
+            return Err(format!("Address {:#X} is not aligned to {} bytes", address, size))
+        }
+
+        match name {
+            "peek" => {
+                let count = if params.len() > 1 { number(1, "count")? as usize } else { 1 };
+
+                check_range(address, count * size)?;
+
+                Ok(Operation::Peek { address, size, count })
+            },
+
+            "poke" => {
+                if params.len() < 2 {
+                    return Err(String::from("Missing values for poke"))
+                }
+
+                let mut values : Vec<u8> = Vec::new();
+
+                for index in 1..params.len() {
+                    let value = number(index, "value")?;
+
+                    if size < 4 && value >= 1 << (size * 8) {
+                        return Err(format!("Value {:#X} does not fit in {} bytes", value, size))
+                    }
+
+                    values.extend_from_slice(&value.to_le_bytes()[..size]);
+                }
+
+                check_range(address, values.len())?;
+
+                Ok(Operation::Poke { address, size, values })
+            },
+
+            "dump" => {
+                let length = number(1, "length")? as usize;
+
+                check_range(address, length)?;
+
+                Ok(Operation::Dump {
+                    address,
+                    length,
+                    file : params.get(2).map(|file| String::from(*file))
+                })
+            },
+
+            _ => Err(format!("Unknown subcommand {}", name))
+        }
+    }
+}
+
+/// This function checks that length bytes, starting at address,
+/// fit in the address space of the console, so addresses of all
+/// bytes in the range can be calculated without overflowing.
+pub fn check_range(address : u32, length : usize) -> Result<(), String> {
+    use std::convert::TryFrom;
+
+    let last = u32::try_from(length.saturating_sub(1)).ok().and_then(|offset| address.checked_add(offset));
+
+    match last {
+        Some(_) => Ok(()),
+        None => Err(format!("Range of {} bytes at {:#X} goes past the end of the address space", length, address))
+    }
+}
+
+/// This function starts a session with the console, so its RAM can
+/// be read and written, and returns the parameters agreed during the
+/// handshake. Only the given capabilities are offered to the console.
+/// An error is returned if the console does not answer after as many
+/// retries as allowed by policy.
+pub fn connect(port : &mut Link, capabilities : u16, policy : &RetryPolicy) -> io::Result<Protocol> {
+    use transfer::{self, TransferState};
+
+    let mut state = TransferState::FirstContact;
+    let mut protocol = Protocol::lock_step();
+    let mut image_hash : u32 = 0;
+    let mut retries = 0;
+
+    while state == TransferState::FirstContact || state == TransferState::Handshake {
+        state = match state {
+            TransferState::FirstContact => transfer::first_contact(port),
+            _ => transfer::wait_handshake(port, &mut protocol, capabilities, &mut image_hash, policy)
+        };
+
+        if state == TransferState::FirstContact {
+            retries += 1;
+
+            if !policy.allows(retries) {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "The console did not answer"))
+            }
+        }
+    }
+
+    Ok(protocol)
+}
+
+/// This function performs the given operation on the console,
+/// once the handshake has finished, and prints its result.
+pub fn run(port : &mut Link, protocol : &Protocol, policy : &RetryPolicy, operation : &Operation) -> io::Result<()> {
+    if !protocol.supports(protocol::MEMORY_ACCESS) {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, "The console does not support memory access"))
+    }
+
+    match *operation {
+        Operation::Peek { address, size, count } => {
+            let data = read(port, protocol, policy, address, size, count)?;
+
+            for (index, value) in data.chunks(size).enumerate() {
+                let mut bytes : [u8; 4] = [0; 4];
+
+                bytes[..size].copy_from_slice(value);
+
+                println!("{:#010X}: {:#0width$X}",
+                         address as usize + index * size,
+                         u32::from_le_bytes(bytes),
+                         width = size * 2 + 2);
+            }
+        },
+
+        Operation::Poke { address, size, ref values } => {
+            write(port, protocol, policy, address, size, values)?;
+
+            println!("Wrote {} bytes at {:#X}", values.len(), address);
+        },
+
+        Operation::Dump { address, length, ref file } => {
+            let data = read(port, protocol, policy, address, 1, length)?;
+
+            match *file {
+                Some(ref file) => {
+                    fs::write(file, &data)?;
+
+                    println!("Wrote {} bytes from {:#X} into {}", data.len(), address, file);
+                },
+                None => print!("{}", hex_dump(address, &data))
+            }
+        },
+
+        Operation::Monitor { ref items, interval } => monitor::run(port, protocol, policy, items, interval)?,
+
+        Operation::Gdb { ref address } => gdb::run(port, protocol, policy, address)?
+    }
+
+    Ok(())
+}
+
+/// This function reads count values of the given size in bytes,
+/// starting at address, from the console RAM. Commands are retried
+/// as defined by policy if no reply, or a corrupted one, is received.
+pub fn read(port : &mut Link,
+            protocol : &Protocol,
+            policy : &RetryPolicy,
+            address : u32,
+            size : usize,
+            count : usize) -> io::Result<Vec<u8>> {
+    let mut data : Vec<u8> = Vec::with_capacity(count * size);
+
+    for start in (0..count).step_by(MAX_CHUNK_SIZE / size) {
+        let values = std::cmp::min(MAX_CHUNK_SIZE / size, count - start);
+        let chunk_address = address + (start * size) as u32;
+
+        let chunk = retry(port, policy, |port, timeout| {
+            read_chunk(port, protocol, timeout, chunk_address, size, values)
+        })?;
+
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+/// This function writes values, encoded as little-endian bytes,
+/// into the console RAM using stores of the given size in bytes.
+/// Commands are retried as defined by policy if not acknowledged.
+pub fn write(port : &mut Link,
+             protocol : &Protocol,
+             policy : &RetryPolicy,
+             address : u32,
+             size : usize,
+             values : &[u8]) -> io::Result<()> {
+    for (index, chunk) in values.chunks(MAX_CHUNK_SIZE).enumerate() {
+        let chunk_address = address + (index * MAX_CHUNK_SIZE) as u32;
+
+        retry(port, policy, |port, timeout| {
+            write_chunk(port, protocol, timeout, chunk_address, size, chunk)
+        })?;
+    }
+
+    Ok(())
+}
+
+/// This function returns a hex dump of the given data,
+/// where each line starts with the address of its first byte.
+pub fn hex_dump(address : u32, data : &[u8]) -> String {
+    let mut dump = String::new();
+
+    for (index, line) in data.chunks(HEX_DUMP_WIDTH).enumerate() {
+        let hex : Vec<String> = line.iter().map(|b| format!("{:02X}", b)).collect();
+        let text : String = line.iter()
+                                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                                .collect();
+
+        dump += &format!("{:08X}  {:<width$}  |{}|\n",
+                         address as usize + index * HEX_DUMP_WIDTH,
+                         hex.join(" "),
+                         text,
+                         width = HEX_DUMP_WIDTH * 3 - 1);
+    }
+
+    dump
+}
+
+/// This function runs the given command, which sends a message to
+/// the console and waits for its reply within the given timeout.
+/// It is run again, as defined by policy, until it succeeds.
+pub fn retry<T, F>(port : &mut Link, policy : &RetryPolicy, mut command : F) -> io::Result<T>
+    where F : FnMut(&mut Link, std::time::Duration) -> io::Result<T> {
+    let mut retries = 0;
+
+    loop {
+        match command(port, policy.timeout(policy.ack, retries)) {
+            Ok(result) => return Ok(result),
+            Err(e) => {
+                retries += 1;
+
+                if !policy.allows(retries) {
+                    return Err(e)
+                }
+
+                println!("{}, retrying ({}/{})", e, retries, policy.max_retries);
+
+                // Late replies must not be taken
+                // as replies to the next command.
+                (*port).clear_input();
+            }
+        }
+    }
+}
+
+fn command(kind : u8, address : u32, size : usize, count : usize) -> Vec<u8> {
+    let mut command : Vec<u8> = vec![kind];
+
+    command.extend_from_slice(&address.to_le_bytes());
+    command.push(size as u8);
+    command.extend_from_slice(&(count as u32).to_le_bytes());
+
+    command
+}
+
+fn read_chunk(port : &mut Link,
+              protocol : &Protocol,
+              timeout : std::time::Duration,
+              address : u32,
+              size : usize,
+              count : usize) -> io::Result<Vec<u8>> {
+    (*port).send(link::COMMAND, &command(PEEK_COMMAND, address, size, count))?;
+    (*port).set_timeout(timeout)?;
+
+    let mut data : Vec<u8> = vec![0; size * count];
+
+    (*port).read_exact(&mut data)?;
+
+    if protocol.supports(protocol::CHECKSUMS) {
+        let mut checksum : [u8; 2] = [0; 2];
+
+        (*port).read_exact(&mut checksum)?;
+
+        if u16::from_le_bytes(checksum) != crc::crc16(&data) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Corrupted data read from {:#X}", address)))
+        }
+    }
+
+    Ok(data)
+}
+
+fn write_chunk(port : &mut Link,
+               protocol : &Protocol,
+               timeout : std::time::Duration,
+               address : u32,
+               size : usize,
+               values : &[u8]) -> io::Result<()> {
+    let mut message = command(POKE_COMMAND, address, size, values.len() / size);
+
+    message.extend_from_slice(values);
+
+    if protocol.supports(protocol::CHECKSUMS) {
+        message.extend_from_slice(&crc::crc16(values).to_le_bytes());
+    }
+
+    (*port).send(link::COMMAND, &message)?;
+    (*port).set_timeout(timeout)?;
+
+    let mut reply : [u8; 1] = [0];
+
+    (*port).read_exact(&mut reply)?;
+
+    match reply[0] {
+        ACK => Ok(()),
+        NAK => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Corrupted data written at {:#X}", address))),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected reply {:#X} to write", reply[0])))
+    }
+}
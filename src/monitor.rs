@@ -0,0 +1,153 @@
+use std::{
+    io,
+    thread,
+    time::{Duration, Instant}
+};
+
+use link::Link;
+use memory;
+use protocol::Protocol;
+use retry::RetryPolicy;
+use symbols::{self, SymbolTable};
+
+/// Time between two reads of all monitored locations, unless
+/// given on the command line.
+pub const DEFAULT_INTERVAL : Duration = Duration::from_millis(500);
+
+/// Number of fractional bits of fixed-point values,
+/// as used by the GTE and most PlayStation programs.
+const FIXED_POINT_BITS : u32 = 12;
+
+/// This enum defines how a monitored value is displayed.
+#[derive(Copy, Clone)]
+pub enum Format {
+    Hex(usize),
+    Unsigned(usize),
+    Signed(usize),
+
+    /// Signed fixed-point value with FIXED_POINT_BITS fractional bits.
+    Fixed(usize)
+}
+
+impl Format {
+    /// This function parses a format given on the command line,
+    /// made of a kind (x, u, s or fx) and a size in bits, e.g.: "s16".
+    pub fn parse(format : &str) -> Result<Format, String> {
+        let (kind, bits) = format.split_at(format.find(|c : char| c.is_ascii_digit()).unwrap_or(format.len()));
+
+        let size = match bits {
+            "8" => 1,
+            "16" => 2,
+            "32" => 4,
+            _ => return Err(format!("Invalid size in format {:?}, expected 8, 16 or 32", format))
+        };
+
+        match kind {
+            "x" => Ok(Format::Hex(size)),
+            "u" => Ok(Format::Unsigned(size)),
+            "s" => Ok(Format::Signed(size)),
+            "fx" if size > 1 => Ok(Format::Fixed(size)),
+            _ => Err(format!("Invalid format {:?}, expected x, u, s or fx followed by its size in bits", format))
+        }
+    }
+
+    /// Returns the size in bytes of values in this format.
+    pub fn size(&self) -> usize {
+        match *self {
+            Format::Hex(size) | Format::Unsigned(size) | Format::Signed(size) | Format::Fixed(size) => size
+        }
+    }
+
+    /// Returns the given little-endian value as text.
+    pub fn display(&self, value : &[u8]) -> String {
+        let mut bytes : [u8; 4] = [0; 4];
+
+        bytes[..value.len()].copy_from_slice(value);
+
+        let unsigned = u32::from_le_bytes(bytes);
+        let shift = 32 - 8 * self.size() as u32;
+        let signed = ((unsigned << shift) as i32) >> shift;
+
+        match *self {
+            Format::Hex(size) => format!("{:#0width$X}", unsigned, width = size * 2 + 2),
+            Format::Unsigned(_) => format!("{}", unsigned),
+            Format::Signed(_) => format!("{}", signed),
+            Format::Fixed(_) => format!("{:.3}", signed as f64 / (1 << FIXED_POINT_BITS) as f64)
+        }
+    }
+}
+
+/// This structure describes a location of the
+/// console RAM which is read periodically.
+pub struct Item {
+    /// Location as given on the command line.
+    pub label : String,
+
+    pub address : u32,
+    pub format : Format
+}
+
+impl Item {
+    /// This function parses a location given on the command line as
+    /// `LOCATION[:FORMAT]`, where LOCATION is an address or a symbol,
+    /// optionally followed by an offset. Format defaults to hex, using
+    /// the size of the symbol if known and valid, or 32 bits otherwise.
+    pub fn parse(item : &str, symbols : Option<&SymbolTable>) -> Result<Item, String> {
+        let (location, format) = match item.split_once(':') {
+            Some((location, format)) => (location, Format::parse(format)?),
+            None => {
+                let size = symbols.and_then(|s| s.find(item))
+                                  .map(|symbol| symbol.size as usize)
+                                  .filter(|&size| size == 1 || size == 2 || size == 4)
+                                  .unwrap_or(4);
+
+                (item, Format::Hex(size))
+            }
+        };
+
+        let address = symbols::resolve(location, symbols)?;
+
+        if !(address as usize).is_multiple_of(format.size()) {
+            return Err(format!("{} ({:#X}) is not aligned to {} bytes", location, address, format.size()))
+        }
+
+        Ok(Item {
+            label : String::from(location),
+            address,
+            format
+        })
+    }
+}
+
+/// This function reads all given locations every interval, and
+/// prints them every time any of them changes, until the console
+/// stops responding.
+pub fn run(port : &mut Link,
+           protocol : &Protocol,
+           policy : &RetryPolicy,
+           items : &[Item],
+           interval : Duration) -> io::Result<()> {
+    let started = Instant::now();
+    let mut previous : Vec<Vec<u8>> = Vec::new();
+
+    loop {
+        let mut values : Vec<Vec<u8>> = Vec::with_capacity(items.len());
+
+        for item in items {
+            values.push(memory::read(port, protocol, policy, item.address, item.format.size(), 1)?);
+        }
+
+        if values != previous {
+            let line : Vec<String> = items.iter()
+                                          .zip(values.iter())
+                                          .map(|(item, value)| format!("{}={}", item.label, item.format.display(value)))
+                                          .collect();
+
+            println!("[{:8.1}s] {}", started.elapsed().as_secs_f64(), line.join("  "));
+
+            previous = values;
+        }
+
+        thread::sleep(interval);
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    cmp,
+    thread,
+    time::{Duration, Instant}
+};
+
+/// Delay used by old loaders between EXE header packets,
+/// and largest delay ever applied by adaptive pacing.
+const LEGACY_DELAY : Duration = Duration::from_millis(100);
+
+/// Number of bits sent over the serial link for each
+/// byte, including start and stop bits.
+const BITS_PER_BYTE : u64 = 10;
+
+/// This enum defines how long the host waits
+/// before sending each packet to the console.
+#[derive(Copy, Clone)]
+pub enum Pacing {
+    /// Packets are sent as fast as possible.
+    None,
+
+    /// The same delay is applied before each packet.
+    Fixed(Duration),
+
+    /// Packets are kept apart by the time the console needs to
+    /// process each of them, based on how long it takes to
+    /// acknowledge data. See Pacer::received() for further details.
+    Adaptive
+}
+
+impl Pacing {
+    /// This function parses a pacing policy given on the command
+    /// line: "none", "adaptive" or "fixed:MS", where MS is the delay
+    /// in milliseconds.
+    pub fn parse(policy : &str) -> Result<Pacing, String> {
+        match policy {
+            "none" => Ok(Pacing::None),
+            "adaptive" => Ok(Pacing::Adaptive),
+            _ => {
+                match policy.strip_prefix("fixed:").map(|ms| ms.parse::<u64>()) {
+                    Some(Ok(ms)) => Ok(Pacing::Fixed(Duration::from_millis(ms))),
+                    _ => Err(format!("Invalid pacing policy {:?}, expected none, adaptive or fixed:MS", policy))
+                }
+            }
+        }
+    }
+}
+
+/// This structure applies a pacing policy to all packets sent
+/// to the console, e.g.: EXE header, EXE data and file data,
+/// and keeps track of the time the console takes to acknowledge them.
+pub struct Pacer {
+    pacing : Pacing,
+
+    /// Serial link speed, in bits per second.
+    baud_rate : u64,
+
+    /// Delay applied between packets.
+    delay : Duration,
+
+    /// Time when the last packet was sent.
+    last_sent : Option<Instant>,
+
+    /// Number of bytes and packets sent since the last reply.
+    pending_bytes : usize,
+    pending_packets : usize,
+
+    /// Shortest time the console has taken to reply during
+    /// the session, besides the time needed to send data.
+    /// It is taken as the time any reply takes to go through
+    /// the link, e.g.: the latency of USB-serial adapters.
+    min_latency : Option<Duration>
+}
+
+impl Pacer {
+    pub fn new(pacing : Pacing, baud_rate : u64) -> Pacer {
+        Pacer {
+            pacing,
+            baud_rate : cmp::max(baud_rate, 1),
+            delay : Duration::ZERO,
+            last_sent : None,
+            pending_bytes : 0,
+            pending_packets : 0,
+            min_latency : None
+        }
+    }
+
+    /// This function resets the delay when a new session starts.
+    /// Adaptive pacing starts with the delay expected by old loaders
+    /// if legacy is true, or with no delay otherwise.
+    pub fn start(&mut self, legacy : bool) {
+        self.delay = match self.pacing {
+            Pacing::None => Duration::ZERO,
+            Pacing::Fixed(delay) => delay,
+            Pacing::Adaptive if legacy => LEGACY_DELAY,
+            Pacing::Adaptive => Duration::ZERO
+        };
+
+        self.last_sent = None;
+        self.pending_bytes = 0;
+        self.pending_packets = 0;
+        self.min_latency = None;
+    }
+
+    /// Returns how long to wait before the next packet is sent.
+    /// Adaptive pacing only waits for whatever is left of the delay
+    /// since the last packet was sent, so packets sent after waiting
+    /// for an acknowledgement are not delayed any further.
+    pub fn next_delay(&self) -> Duration {
+        match (self.pacing, self.last_sent) {
+            (Pacing::Adaptive, Some(last_sent)) => self.delay.saturating_sub(last_sent.elapsed()),
+            (Pacing::Adaptive, None) => Duration::ZERO,
+            _ => self.delay
+        }
+    }
+
+    /// This function waits as required by the
+    /// policy before a packet is sent.
+    pub fn pause(&self) {
+        let delay = self.next_delay();
+
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Keeps track of a packet of the given size being sent.
+    pub fn sent(&mut self, bytes : usize) {
+        self.last_sent = Some(Instant::now());
+        self.pending_bytes += bytes;
+        self.pending_packets += 1;
+    }
+
+    /// This function must be called when data is received from
+    /// the console. See reply_received() for further details.
+    pub fn received(&mut self) {
+        if let Some(last_sent) = self.last_sent {
+            self.reply_received(last_sent.elapsed());
+        }
+    }
+
+    /// This function updates the delay applied by adaptive pacing,
+    /// given the time elapsed since the last packet was sent until
+    /// the console replied. The time needed to send all data sent
+    /// since the last reply is taken out of it, and so is the shortest
+    /// latency observed during the session, since it is spent on the
+    /// link rather than on the console. The rest is taken as time spent
+    /// by the console processing the data, so it is spread among the
+    /// packets sent since the last reply and averaged with previous
+    /// delays.
+    fn reply_received(&mut self, elapsed : Duration) {
+        if self.pending_packets == 0 {
+            return
+        }
+
+        if let Pacing::Adaptive = self.pacing {
+            let line_time = Duration::from_micros((self.pending_bytes as u64 + 1) * BITS_PER_BYTE * 1_000_000
+                                                  / self.baud_rate);
+            let latency = elapsed.saturating_sub(line_time);
+            let min_latency = cmp::min(self.min_latency.unwrap_or(latency), latency);
+            let processing = (latency - min_latency) / self.pending_packets as u32;
+
+            self.min_latency = Some(min_latency);
+            self.delay = cmp::min((self.delay + processing) / 2, LEGACY_DELAY);
+        }
+
+        self.pending_bytes = 0;
+        self.pending_packets = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BAUD_RATE : u64 = 115200;
+
+    fn ms(ms : u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    /// Returns a pacer which has sent the given number
+    /// of packets, each one of the given size.
+    fn sent(pacer : &mut Pacer, packets : usize, bytes : usize) {
+        for _ in 0..packets {
+            pacer.sent(bytes);
+        }
+    }
+
+    #[test]
+    fn parse_policies() {
+        assert!(matches!(Pacing::parse("none"), Ok(Pacing::None)));
+        assert!(matches!(Pacing::parse("adaptive"), Ok(Pacing::Adaptive)));
+        assert!(matches!(Pacing::parse("fixed:15"), Ok(Pacing::Fixed(d)) if d == ms(15)));
+        assert!(Pacing::parse("fixed:").is_err());
+        assert!(Pacing::parse("fixed:-1").is_err());
+        assert!(Pacing::parse("slow").is_err());
+    }
+
+    #[test]
+    fn fixed_and_no_pacing() {
+        let mut pacer = Pacer::new(Pacing::Fixed(ms(20)), BAUD_RATE);
+
+        pacer.start(false);
+        sent(&mut pacer, 4, 8);
+        pacer.reply_received(ms(500));
+        assert_eq!(pacer.next_delay(), ms(20));
+
+        let mut pacer = Pacer::new(Pacing::None, BAUD_RATE);
+
+        pacer.start(true);
+        sent(&mut pacer, 4, 8);
+        pacer.reply_received(ms(500));
+        assert_eq!(pacer.next_delay(), Duration::ZERO);
+    }
+
+    #[test]
+    fn adaptive_starts_with_legacy_delay() {
+        let mut pacer = Pacer::new(Pacing::Adaptive, BAUD_RATE);
+
+        pacer.start(true);
+        assert_eq!(pacer.next_delay(), Duration::ZERO);
+
+        pacer.sent(8);
+        assert!(pacer.next_delay() > ms(90));
+
+        pacer.start(false);
+        pacer.sent(8);
+        assert_eq!(pacer.next_delay(), Duration::ZERO);
+    }
+
+    #[test]
+    fn adaptive_ignores_link_latency() {
+        // Replies always take as long, e.g.: due to USB latency,
+        // so the console is not slowed down by the link.
+        let mut pacer = Pacer::new(Pacing::Adaptive, BAUD_RATE);
+
+        pacer.start(false);
+
+        for _ in 0..10 {
+            sent(&mut pacer, 8, 256);
+            pacer.reply_received(ms(16) + ms(8 * 256 * 10 * 1000 / BAUD_RATE));
+            assert_eq!(pacer.delay, Duration::ZERO);
+        }
+    }
+
+    #[test]
+    fn adaptive_follows_processing_time() {
+        let mut pacer = Pacer::new(Pacing::Adaptive, BAUD_RATE);
+
+        pacer.start(false);
+
+        // First reply sets the link latency.
+        sent(&mut pacer, 1, 8);
+        pacer.reply_received(ms(16));
+
+        // The console then needs 4 ms more for each of 8 packets.
+        for _ in 0..20 {
+            sent(&mut pacer, 8, 8);
+            pacer.reply_received(ms(16 + 32));
+        }
+
+        assert!(pacer.delay > ms(3) && pacer.delay <= ms(4), "{:?}", pacer.delay);
+
+        // Once the console catches up, the delay shrinks back.
+        for _ in 0..20 {
+            sent(&mut pacer, 8, 8);
+            pacer.reply_received(ms(16));
+        }
+
+        assert!(pacer.delay < ms(1), "{:?}", pacer.delay);
+    }
+
+    #[test]
+    fn adaptive_delay_limited() {
+        let mut pacer = Pacer::new(Pacing::Adaptive, BAUD_RATE);
+
+        pacer.start(false);
+        sent(&mut pacer, 1, 8);
+        pacer.reply_received(Duration::ZERO);
+
+        for _ in 0..20 {
+            sent(&mut pacer, 1, 8);
+            pacer.reply_received(ms(10_000));
+        }
+
+        assert_eq!(pacer.delay, LEGACY_DELAY);
+    }
+
+    #[test]
+    fn replies_without_packets_ignored() {
+        let mut pacer = Pacer::new(Pacing::Adaptive, BAUD_RATE);
+
+        pacer.start(true);
+        pacer.reply_received(ms(1000));
+        assert_eq!(pacer.delay, LEGACY_DELAY);
+    }
+}
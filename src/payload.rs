@@ -0,0 +1,373 @@
+use std::{
+    cmp,
+    collections::HashMap,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::{self, Read, Seek, SeekFrom},
+    rc::Rc,
+    time::Instant
+};
+
+use crc;
+use lz;
+use protocol::{self, Protocol};
+
+/// Maximum number of compressed bytes kept in
+/// the cache before it is emptied. This keeps
+/// memory usage bounded when serving large games.
+const MAX_CACHE_SIZE : usize = 64 * 1024 * 1024;
+
+/// Largest amount of data compressed before being sent. Since the
+/// whole data and its compressed version are held in memory until
+/// sent, larger data, which would not even fit in the 2 MiB of RAM
+/// of the console at once, is streamed uncompressed instead.
+const MAX_COMPRESSED_SIZE : usize = 2 * 1024 * 1024;
+
+/// Number of bytes read from disk at once
+/// when data is streamed from a file.
+const READ_AHEAD_SIZE : usize = 64 * 1024;
+
+/// This structure reads a range of a file on demand,
+/// keeping a read-ahead buffer so that memory usage
+/// stays bounded no matter how large the file is.
+struct FileSource {
+    file : File,
+
+    /// Offset of the range inside the file.
+    start : u64,
+
+    /// Length of the range.
+    length : usize,
+
+    /// Data read in advance.
+    buffer : Vec<u8>,
+
+    /// Offset of the read-ahead buffer inside the range.
+    buffer_offset : usize
+}
+
+impl FileSource {
+    fn read(&mut self, offset : usize, length : usize) -> io::Result<&[u8]> {
+        if offset < self.buffer_offset || offset + length > self.buffer_offset + self.buffer.len() {
+            let to_read = cmp::min(cmp::max(READ_AHEAD_SIZE, length), self.length - offset);
+
+            self.buffer.resize(to_read, 0);
+            self.file.seek(SeekFrom::Start(self.start + offset as u64))?;
+            self.file.read_exact(&mut self.buffer)?;
+            self.buffer_offset = offset;
+        }
+
+        let begin = offset - self.buffer_offset;
+
+        Ok(&self.buffer[begin..begin + length])
+    }
+}
+
+/// Where payload data is taken from.
+enum Source {
+    /// Data already held in memory, e.g.: compressed data.
+    Memory(Rc<Vec<u8>>),
+
+    /// Data read from disk as it is sent.
+    File(FileSource)
+}
+
+/// This structure holds the data sent to the console
+/// for an executable or file, which might be compressed.
+pub struct Payload {
+    source : Source,
+
+    /// Number of bytes sent over the serial link.
+    pub size : usize,
+
+    /// Size of the data before being compressed.
+    pub original_size : usize,
+
+    /// CRC-32 of the data before being compressed,
+    /// so the console can verify it after decompressing.
+    /// Data streamed from disk is added as it is read, so
+    /// it is only complete once all data has been read.
+    pub checksum : u32,
+
+    /// Number of bytes of the original data
+    /// already added to the checksum.
+    checksummed : usize,
+
+    /// Time when the payload was built,
+    /// used to estimate transfer time savings.
+    started : Instant
+}
+
+impl Payload {
+    pub fn new() -> Payload {
+        Payload {
+            source : Source::Memory(Rc::new(Vec::new())),
+            size : 0,
+            original_size : 0,
+            checksum : 0,
+            checksummed : 0,
+            started : Instant::now()
+        }
+    }
+
+    /// Returns up to length bytes of the data
+    /// sent over the serial link, starting at offset.
+    /// Data streamed from disk is added to the checksum
+    /// the first time it is read.
+    pub fn read(&mut self, offset : usize, length : usize) -> io::Result<&[u8]> {
+        let length = cmp::min(length, self.size.saturating_sub(offset));
+
+        match self.source {
+            Source::Memory(ref data) => Ok(&data[offset..offset + length]),
+            Source::File(ref mut file) => {
+                let data = file.read(offset, length)?;
+
+                // Data is always read again from an earlier offset,
+                // so no byte can be skipped nor added twice.
+                if offset <= self.checksummed && offset + length > self.checksummed {
+                    self.checksum = crc::crc32_update(self.checksum, &data[self.checksummed - offset..]);
+                    self.checksummed = offset + length;
+                }
+
+                Ok(data)
+            }
+        }
+    }
+
+    /// Returns whether the data sent over the serial link is compressed.
+    pub fn is_compressed(&self) -> bool {
+        self.size < self.original_size
+    }
+
+    /// Prints the compression ratio and an estimation of the time
+    /// saved, based on the throughput observed during the transfer.
+    pub fn report(&self) {
+        if self.is_compressed() && self.size != 0 {
+            let elapsed = self.started.elapsed().as_secs_f64();
+            let ratio = self.size as f64 / self.original_size as f64;
+            let saved_bytes = self.original_size - self.size;
+            let saved_time = elapsed * saved_bytes as f64 / self.size as f64;
+
+            println!("Compressed {} bytes into {} ({:.1}%), about {:.1} seconds saved",
+                     self.original_size,
+                     self.size,
+                     ratio * 100.0,
+                     saved_time);
+        }
+    }
+}
+
+/// This structure keeps compressed versions of the data
+/// sent to the console, keyed by a hash of their contents,
+/// so files requested several times are only compressed once.
+pub struct Cache {
+    entries : HashMap<u64, Rc<Vec<u8>>>,
+    size : usize
+}
+
+impl Cache {
+    pub fn new() -> Cache {
+        Cache {
+            entries : HashMap::new(),
+            size : 0
+        }
+    }
+
+    /// This function builds the payload for length bytes of the
+    /// file located at path, starting at offset. A length of 0
+    /// means reading until the end of file. An offset past the
+    /// end of file is an error, while a length going past it is
+    /// shortened so the range ends at the end of file.
+    ///
+    /// Uncompressed data is streamed from disk as it is sent,
+    /// and its checksum is calculated on the way, so nothing
+    /// is read before sending its size. However, compressed data
+    /// must be held in memory, since its size has to be sent first,
+    /// so only data up to MAX_COMPRESSED_SIZE is compressed.
+    /// Compressed data is only used if it is smaller than the
+    /// original data.
+    pub fn payload(&mut self,
+                   path : &str,
+                   offset : u64,
+                   length : u64,
+                   protocol : &Protocol) -> io::Result<Payload> {
+        let mut file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+
+        if offset > file_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("Offset {} is past the end of file ({} bytes)", offset, file_size)))
+        }
+
+        let start = offset;
+        let available = file_size - start;
+        let size = if length == 0 {
+            available
+        }
+        else
+        {
+            cmp::min(length, available)
+        } as usize;
+
+        if protocol.supports(protocol::COMPRESSION) && size <= MAX_COMPRESSED_SIZE {
+            let mut data : Vec<u8> = vec![0; size];
+
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(&mut data)?;
+
+            Ok(self.memory_payload(data, protocol))
+        }
+        else
+        {
+            let mut payload = Payload::new();
+
+            payload.size = size;
+            payload.original_size = size;
+            payload.source = Source::File(FileSource {
+                file,
+                start,
+                length : size,
+                buffer : Vec::new(),
+                buffer_offset : 0
+            });
+
+            Ok(payload)
+        }
+    }
+
+    /// This function builds the payload for data already
+    /// held in memory, compressing it if agreed with the console.
+    /// Compressed data is only used if it is smaller than the
+    /// original data.
+    pub fn memory_payload(&mut self, data : Vec<u8>, protocol : &Protocol) -> Payload {
+        let mut payload = Payload::new();
+
+        payload.size = data.len();
+        payload.original_size = data.len();
+        payload.checksum = crc::crc32(&data);
+        payload.checksummed = data.len();
+
+        if protocol.supports(protocol::COMPRESSION) {
+            let compressed = self.compressed(&data);
+
+            if compressed.len() < data.len() {
+                payload.size = compressed.len();
+                payload.source = Source::Memory(compressed);
+
+                return payload
+            }
+        }
+
+        payload.source = Source::Memory(Rc::new(data));
+
+        payload
+    }
+
+    fn compressed(&mut self, data : &[u8]) -> Rc<Vec<u8>> {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+
+        data.hash(&mut hasher);
+
+        let key = hasher.finish();
+
+        if let Some(compressed) = self.entries.get(&key) {
+            return compressed.clone()
+        }
+
+        let compressed = Rc::new(lz::compress(data));
+
+        if self.size + compressed.len() > MAX_CACHE_SIZE {
+            self.entries.clear();
+            self.size = 0;
+        }
+
+        self.size += compressed.len();
+        self.entries.insert(key, compressed.clone());
+
+        compressed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes data into a file inside the temporary folder,
+    /// returning its path.
+    fn temp_file(name : &str, data : &[u8]) -> String {
+        let path = std::env::temp_dir().join(format!("rspsxserial-payload-{}-{}", std::process::id(), name));
+
+        std::fs::write(&path, data).expect("Could not write temporary file");
+        path.to_string_lossy().into_owned()
+    }
+
+    fn read_all(payload : &mut Payload) -> Vec<u8> {
+        let size = payload.size;
+
+        payload.read(0, size).expect("Could not read payload").to_vec()
+    }
+
+    #[test]
+    fn ranges_inside_file() {
+        let path = temp_file("inside", b"0123456789");
+        let mut cache = Cache::new();
+        let protocol = Protocol::lock_step();
+
+        assert_eq!(read_all(&mut cache.payload(&path, 0, 0, &protocol).unwrap()), b"0123456789");
+        assert_eq!(read_all(&mut cache.payload(&path, 2, 3, &protocol).unwrap()), b"234");
+        assert_eq!(read_all(&mut cache.payload(&path, 8, 100, &protocol).unwrap()), b"89");
+        assert_eq!(read_all(&mut cache.payload(&path, 10, 0, &protocol).unwrap()), b"");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checksum_calculated_while_streaming() {
+        let data : Vec<u8> = (0..3 * READ_AHEAD_SIZE + 100).map(|i| (i * 7) as u8).collect();
+        let path = temp_file("stream", &data);
+        let protocol = Protocol::negotiate(1, protocol::CHECKSUMS, 2048, 8, protocol::HOST_CAPABILITIES);
+        let mut payload = Cache::new().payload(&path, 0, 0, &protocol).unwrap();
+
+        assert_eq!(payload.checksum, 0);
+
+        // Windows sent again after a NAK must not change the checksum.
+        for &(offset, length) in &[(0, 5000), (3000, 5000), (8000, READ_AHEAD_SIZE), (0, 100), (8000 + READ_AHEAD_SIZE, data.len())] {
+            assert_eq!(payload.read(offset, length).unwrap(), &data[offset..std::cmp::min(offset + length, data.len())]);
+        }
+
+        assert_eq!(payload.checksum, crc::crc32(&data));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn checksum_of_memory_payloads() {
+        let data = b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec();
+        let protocol = Protocol::negotiate(1, protocol::CHECKSUMS | protocol::COMPRESSION, 2048, 8, protocol::HOST_CAPABILITIES);
+        let payload = Cache::new().memory_payload(data.clone(), &protocol);
+
+        assert!(payload.is_compressed());
+        assert_eq!(payload.checksum, crc::crc32(&data));
+    }
+
+    #[test]
+    fn offset_past_end_of_file() {
+        let path = temp_file("past", b"0123456789");
+        let mut cache = Cache::new();
+
+        for protocol in &[Protocol::lock_step(), Protocol::negotiate(1, protocol::HOST_CAPABILITIES, 2048, 8, protocol::HOST_CAPABILITIES)] {
+            let error = cache.payload(&path, 11, 0, protocol).err().expect("Offset past end of file accepted");
+
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn missing_file() {
+        let path = std::env::temp_dir().join("rspsxserial-payload-missing");
+
+        assert!(Cache::new().payload(&path.to_string_lossy(), 0, 0, &Protocol::lock_step()).is_err());
+    }
+}
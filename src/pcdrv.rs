@@ -0,0 +1,269 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom},
+    path::PathBuf
+};
+
+use regex::Regex;
+
+/// Maximum number of files the console can keep open at once.
+pub const MAX_OPEN_HANDLES : usize = 16;
+
+/// This enum defines the requests that allow the console to use
+/// files on the host as with Psy-Q's PCopen(), PCcreat(), PCread(),
+/// PCwrite(), PClseek() and PCclose(). Paths use the same syntax as
+/// file reads and writes, and all numbers are given in decimal:
+///
+/// - "pcopen:\PATH:MODE", where MODE is 0 (read), 1 (write) or 2 (both).
+/// - "pccreat:\PATH", which creates or truncates a file for writing.
+/// - "pcread:HANDLE:LENGTH"
+/// - "pcwrite:HANDLE:LENGTH"
+/// - "pclseek:HANDLE:OFFSET:WHENCE", where WHENCE is 0 (from start),
+///   1 (from current position) or 2 (from end of file).
+/// - "pcclose:HANDLE"
+pub enum Request {
+    Open { path : String, mode : u8 },
+    Create { path : String },
+    Read { handle : usize, length : usize },
+    Write { handle : usize, length : usize },
+    Seek { handle : usize, offset : i64, whence : u8 },
+    Close { handle : usize }
+}
+
+impl Request {
+    /// This function parses a request sent by the console.
+    /// Returns None if the request is not a valid PCdrv request.
+    pub fn parse(request : &str) -> Option<Request> {
+        lazy_static! {
+            static ref OPEN_RX: Regex = Regex::new(r"^pcopen:\\(.+):([0-2])$").expect("Could not compile regex");
+            static ref CREATE_RX: Regex = Regex::new(r"^pccreat:\\(.+)$").expect("Could not compile regex");
+            static ref READ_RX: Regex = Regex::new(r"^pcread:(\d+):(\d+)$").expect("Could not compile regex");
+            static ref WRITE_RX: Regex = Regex::new(r"^pcwrite:(\d+):(\d+)$").expect("Could not compile regex");
+            static ref SEEK_RX: Regex = Regex::new(r"^pclseek:(\d+):(-?\d+):([0-2])$").expect("Could not compile regex");
+            static ref CLOSE_RX: Regex = Regex::new(r"^pcclose:(\d+)$").expect("Could not compile regex");
+        }
+
+        if let Some(c) = OPEN_RX.captures(request) {
+            Some(Request::Open { path : String::from(&c[1]), mode : c[2].parse().ok()? })
+        }
+        else if let Some(c) = CREATE_RX.captures(request) {
+            Some(Request::Create { path : String::from(&c[1]) })
+        }
+        else if let Some(c) = READ_RX.captures(request) {
+            Some(Request::Read { handle : c[1].parse().ok()?, length : c[2].parse().ok()? })
+        }
+        else if let Some(c) = WRITE_RX.captures(request) {
+            Some(Request::Write { handle : c[1].parse().ok()?, length : c[2].parse().ok()? })
+        }
+        else if let Some(c) = SEEK_RX.captures(request) {
+            Some(Request::Seek {
+                handle : c[1].parse().ok()?,
+                offset : c[2].parse().ok()?,
+                whence : c[3].parse().ok()?
+            })
+        }
+        else if let Some(c) = CLOSE_RX.captures(request) {
+            Some(Request::Close { handle : c[1].parse().ok()? })
+        }
+        else
+        {
+            None
+        }
+    }
+}
+
+/// This structure holds a file opened by the console.
+pub struct HostFile {
+    pub file : File,
+
+    /// Path to the file on the host.
+    pub path : PathBuf
+}
+
+/// This structure relates handles given to the console with
+/// the files they refer to. Handles are only valid during the
+/// session they were opened in, and the number of files that
+/// can be open at once is limited.
+pub struct HandleTable {
+    handles : Vec<Option<HostFile>>
+}
+
+impl HandleTable {
+    pub fn new() -> HandleTable {
+        HandleTable {
+            handles : (0..MAX_OPEN_HANDLES).map(|_| None).collect()
+        }
+    }
+
+    /// This function opens the file at path, where mode follows
+    /// PCopen() conventions, and 3 means creating or truncating
+    /// the file as PCcreat() does. Returns the new handle.
+    pub fn open(&mut self, path : PathBuf, mode : u8) -> io::Result<usize> {
+        let handle = match self.handles.iter().position(|h| h.is_none()) {
+            Some(handle) => handle,
+            None => return Err(io::Error::other("Too many open files"))
+        };
+
+        let mut options = OpenOptions::new();
+
+        match mode {
+            0 => options.read(true),
+            1 => options.write(true),
+            2 => options.read(true).write(true),
+            _ => options.write(true).create(true).truncate(true)
+        };
+
+        if mode == 3 {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let file = options.open(&path)?;
+
+        self.handles[handle] = Some(HostFile { file, path });
+
+        Ok(handle)
+    }
+
+    /// Returns the file referred to by the given handle, if open.
+    pub fn get(&mut self, handle : usize) -> Option<&mut HostFile> {
+        self.handles.get_mut(handle).and_then(|h| h.as_mut())
+    }
+
+    /// This function moves the position of the given handle, where
+    /// whence follows PClseek() conventions. Returns the new position.
+    pub fn seek(&mut self, handle : usize, offset : i64, whence : u8) -> io::Result<u64> {
+        let position = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            0 => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+            1 => SeekFrom::Current(offset),
+            _ => SeekFrom::End(offset)
+        };
+
+        match self.get(handle) {
+            Some(host_file) => host_file.file.seek(position),
+            None => Err(io::Error::from(io::ErrorKind::NotFound))
+        }
+    }
+
+    /// Closes the file referred to by the given handle.
+    /// Returns whether the handle was open.
+    pub fn close(&mut self, handle : usize) -> bool {
+        match self.handles.get_mut(handle) {
+            Some(h) => h.take().is_some(),
+            None => false
+        }
+    }
+
+    /// Closes all files, e.g.: when a new session starts.
+    pub fn close_all(&mut self) {
+        for h in self.handles.iter_mut() {
+            *h = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    /// Returns the path of a file inside the temporary folder.
+    fn temp_path(name : &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rspsxserial-pcdrv-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn parse_requests() {
+        assert!(matches!(Request::parse(r"pcopen:\DATA\SAVE.DAT:2"),
+                         Some(Request::Open { ref path, mode : 2 }) if path == r"DATA\SAVE.DAT"));
+        assert!(matches!(Request::parse(r"pccreat:\LOG.TXT"),
+                         Some(Request::Create { ref path }) if path == "LOG.TXT"));
+        assert!(matches!(Request::parse("pcread:3:2048"), Some(Request::Read { handle : 3, length : 2048 })));
+        assert!(matches!(Request::parse("pcwrite:15:1"), Some(Request::Write { handle : 15, length : 1 })));
+        assert!(matches!(Request::parse("pclseek:0:-16:2"), Some(Request::Seek { handle : 0, offset : -16, whence : 2 })));
+        assert!(matches!(Request::parse("pcclose:7"), Some(Request::Close { handle : 7 })));
+    }
+
+    #[test]
+    fn parse_invalid_requests() {
+        assert!(Request::parse(r"pcopen:\DATA\SAVE.DAT:3").is_none());
+        assert!(Request::parse("pcopen:DATA.DAT:0").is_none());
+        assert!(Request::parse(r"pccreat:\").is_none());
+        assert!(Request::parse("pcread:-1:16").is_none());
+        assert!(Request::parse("pcread:1").is_none());
+        assert!(Request::parse("pcwrite:1:16:0").is_none());
+        assert!(Request::parse("pclseek:1:0:3").is_none());
+        assert!(Request::parse("pcclose:").is_none());
+        assert!(Request::parse("pcread:99999999999999999999999:1").is_none());
+        assert!(Request::parse(r"cdrom:\DATA.BIN;1").is_none());
+    }
+
+    #[test]
+    fn open_write_seek_and_read() {
+        let path = temp_path("rw");
+        let mut handles = HandleTable::new();
+
+        let handle = handles.open(path.clone(), 3).expect("Could not create file");
+
+        handles.get(handle).expect("Handle not open").file.write_all(b"0123456789").expect("Could not write file");
+        handles.close(handle);
+
+        let handle = handles.open(path.clone(), 0).expect("Could not open file");
+
+        assert_eq!(handles.seek(handle, -4, 2).expect("Could not seek"), 6);
+        assert_eq!(handles.seek(handle, -2, 1).expect("Could not seek"), 4);
+        assert!(handles.seek(handle, -1, 0).is_err());
+
+        let mut data = String::new();
+
+        handles.get(handle).expect("Handle not open").file.read_to_string(&mut data).expect("Could not read file");
+        assert_eq!(data, "456789");
+
+        handles.close_all();
+        assert!(handles.get(handle).is_none());
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn handles_run_out() {
+        let path = temp_path("many");
+        let mut handles = HandleTable::new();
+
+        fs::write(&path, b"data").expect("Could not write temporary file");
+
+        let opened : Vec<usize> = (0..MAX_OPEN_HANDLES).map(|_| handles.open(path.clone(), 0).expect("Could not open file"))
+                                                        .collect();
+
+        assert_eq!(opened, (0..MAX_OPEN_HANDLES).collect::<Vec<usize>>());
+        assert!(handles.open(path.clone(), 0).is_err());
+
+        // Closed handles are given again.
+        assert!(handles.close(5));
+        assert_eq!(handles.open(path.clone(), 0).expect("Could not open file"), 5);
+
+        fs::remove_file(path).ok();
+    }
+
+    #[test]
+    fn invalid_handles() {
+        let mut handles = HandleTable::new();
+
+        assert!(!handles.close(0));
+        assert!(!handles.close(MAX_OPEN_HANDLES));
+        assert!(!handles.close(usize::MAX));
+        assert!(handles.get(MAX_OPEN_HANDLES).is_none());
+        assert!(handles.seek(3, 0, 0).is_err());
+        assert!(handles.open(temp_path("missing"), 0).is_err());
+
+        // Handles can only be closed once.
+        let path = temp_path("once");
+
+        let handle = handles.open(path.clone(), 3).expect("Could not create file");
+
+        assert!(handles.close(handle));
+        assert!(!handles.close(handle));
+        fs::remove_file(path).ok();
+    }
+}
@@ -0,0 +1,253 @@
+/// Size of each data packet sent to old loaders,
+/// which expect an acknowledgement after every packet.
+pub const LOCK_STEP_PACKET_SIZE : usize = 8;
+
+/// Largest packet size the host agrees to use
+/// when the console requests windowed transfers.
+const MAX_PACKET_SIZE : usize = 2048;
+
+/// Largest number of packets the host agrees to send
+/// before an acknowledgement is required.
+const MAX_WINDOW_SIZE : usize = 64;
+
+/// Latest protocol version known by the host.
+pub const PROTOCOL_VERSION : u8 = 1;
+
+/// Several packets are sent before an
+/// acknowledgement is required.
+pub const WINDOWED : u16 = 1 << 0;
+
+/// Each packet is followed by its CRC-16
+/// and whole images are verified with a CRC-32.
+pub const CHECKSUMS : u16 = 1 << 1;
+
+/// Data is compressed before being sent.
+pub const COMPRESSION : u16 = 1 << 2;
+
+/// Requests other than plain file reads are accepted.
+pub const EXTENDED_REQUESTS : u16 = 1 << 3;
+
+/// All messages travel inside frames with their own type,
+/// length and checksum. See link::Link for further details.
+pub const FRAMING : u16 = 1 << 4;
+
+/// The console reports a hash of the executable it still holds
+/// in RAM, so it is not sent again if it has not changed.
+pub const IMAGE_HASH : u16 = 1 << 5;
+
+/// Only blocks changed since the last upload are sent if the
+/// console still holds the last executable sent by the host.
+/// It relies on IMAGE_HASH, so it is ignored without it.
+pub const DELTA_UPLOAD : u16 = 1 << 6;
+
+/// Raw binaries can be written to any address of the console
+/// RAM, verified, and jumped to, instead of a PSX-EXE.
+pub const RAW_UPLOAD : u16 = 1 << 7;
+
+/// The console RAM can be read and written by the host.
+/// See memory::PEEK_COMMAND for further details.
+pub const MEMORY_ACCESS : u16 = 1 << 8;
+
+/// A debug stub on the console can stop, inspect and resume
+/// the running program. It relies on MEMORY_ACCESS.
+/// See gdb::READ_REGISTERS_COMMAND for further details.
+pub const DEBUG_STUB : u16 = 1 << 9;
+
+/// Human-readable names for each capability flag.
+const CAPABILITY_NAMES : [(u16, &str); 10] =
+[
+    (WINDOWED, "windowed transfers"),
+    (CHECKSUMS, "checksums"),
+    (COMPRESSION, "compression"),
+    (EXTENDED_REQUESTS, "extended requests"),
+    (FRAMING, "framed messages"),
+    (IMAGE_HASH, "image hash"),
+    (DELTA_UPLOAD, "delta uploads"),
+    (RAW_UPLOAD, "raw uploads"),
+    (MEMORY_ACCESS, "memory access"),
+    (DEBUG_STUB, "debug stub")
+];
+
+/// Capabilities implemented by the host.
+pub const HOST_CAPABILITIES : u16 = WINDOWED | CHECKSUMS | COMPRESSION | EXTENDED_REQUESTS | FRAMING | IMAGE_HASH | DELTA_UPLOAD | RAW_UPLOAD | MEMORY_ACCESS | DEBUG_STUB;
+
+/// This structure holds the transfer parameters
+/// agreed with the console during the handshake.
+#[derive(Copy, Clone)]
+pub struct Protocol {
+    /// Protocol version. Old loaders, which do
+    /// not report any version, are considered 0.
+    pub version : u8,
+
+    /// Capability flags supported by both sides.
+    pub capabilities : u16,
+
+    /// Number of bytes sent on each data packet.
+    pub packet_size : usize,
+
+    /// Number of packets sent before
+    /// an acknowledgement is expected.
+    pub window_size : usize
+}
+
+impl Protocol {
+    /// Returns the parameters used by old loaders,
+    /// which acknowledge each 8-byte packet.
+    pub fn lock_step() -> Protocol {
+        Protocol {
+            version : 0,
+            capabilities : 0,
+            packet_size : LOCK_STEP_PACKET_SIZE,
+            window_size : 1
+        }
+    }
+
+    /// Returns the best parameters supported by both sides, given
+    /// the version, capabilities, packet and window sizes reported
+    /// by the console, and the capabilities enabled on the host.
+    /// Packet and window sizes are limited to what the host supports,
+    /// and packet size is rounded down to a multiple of 8 bytes. They
+    /// are ignored unless windowed transfers are supported by both sides.
+    pub fn negotiate(version : u8,
+                     capabilities : u16,
+                     packet_size : usize,
+                     window_size : usize,
+                     host_capabilities : u16) -> Protocol {
+        let mut protocol = Protocol::lock_step();
+
+        protocol.version = std::cmp::min(version, PROTOCOL_VERSION);
+        protocol.capabilities = capabilities & host_capabilities & HOST_CAPABILITIES;
+
+        if !protocol.supports(IMAGE_HASH) {
+            protocol.capabilities &= !DELTA_UPLOAD;
+        }
+
+        if protocol.supports(WINDOWED) {
+            let packet_size = packet_size.clamp(LOCK_STEP_PACKET_SIZE, MAX_PACKET_SIZE);
+
+            protocol.packet_size = packet_size - (packet_size % LOCK_STEP_PACKET_SIZE);
+            protocol.window_size = window_size.clamp(1, MAX_WINDOW_SIZE);
+        }
+
+        protocol
+    }
+
+    /// Returns whether the given capability
+    /// is supported by both sides.
+    pub fn supports(&self, capability : u16) -> bool {
+        self.capabilities & capability != 0
+    }
+
+    /// Returns a comma-separated list with the
+    /// names of all agreed capabilities.
+    pub fn describe_capabilities(&self) -> String {
+        let names : Vec<&str> = CAPABILITY_NAMES.iter()
+                                                .filter(|&&(flag, _)| self.supports(flag))
+                                                .map(|&(_, name)| name)
+                                                .collect();
+
+        if names.is_empty() {
+            String::from("none")
+        }
+        else
+        {
+            names.join(", ")
+        }
+    }
+
+    /// Returns whether the console acknowledges
+    /// each packet individually.
+    pub fn is_lock_step(&self) -> bool {
+        self.packet_size == LOCK_STEP_PACKET_SIZE && self.window_size == 1
+    }
+
+    /// Returns the maximum number of bytes that
+    /// can be sent between two acknowledgements.
+    pub fn burst_size(&self) -> usize {
+        self.packet_size * self.window_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_step_for_old_loaders() {
+        // Old loaders reply with a single ACK instead of a version.
+        let protocol = Protocol::lock_step();
+
+        assert_eq!(protocol.version, 0);
+        assert_eq!(protocol.capabilities, 0);
+        assert!(protocol.is_lock_step());
+        assert_eq!(protocol.burst_size(), LOCK_STEP_PACKET_SIZE);
+        assert_eq!(protocol.describe_capabilities(), "none");
+    }
+
+    #[test]
+    fn negotiate_windowed_transfers() {
+        let protocol = Protocol::negotiate(1, WINDOWED | CHECKSUMS | COMPRESSION, 256, 4, HOST_CAPABILITIES);
+
+        assert_eq!(protocol.version, 1);
+        assert_eq!(protocol.capabilities, WINDOWED | CHECKSUMS | COMPRESSION);
+        assert_eq!((protocol.packet_size, protocol.window_size), (256, 4));
+        assert!(!protocol.is_lock_step());
+        assert_eq!(protocol.burst_size(), 1024);
+        assert_eq!(protocol.describe_capabilities(), "windowed transfers, checksums, compression");
+    }
+
+    #[test]
+    fn negotiate_without_windowed_transfers() {
+        // Packet and window sizes are ignored.
+        let protocol = Protocol::negotiate(1, CHECKSUMS | FRAMING, 256, 4, HOST_CAPABILITIES);
+
+        assert_eq!(protocol.capabilities, CHECKSUMS | FRAMING);
+        assert!(protocol.is_lock_step());
+
+        // Windowed transfers disabled on the host.
+        let protocol = Protocol::negotiate(1, WINDOWED | CHECKSUMS, 256, 4, HOST_CAPABILITIES & !WINDOWED);
+
+        assert_eq!(protocol.capabilities, CHECKSUMS);
+        assert!(protocol.is_lock_step());
+    }
+
+    #[test]
+    fn negotiate_limits_packet_and_window_sizes() {
+        let sizes = |packet_size, window_size| {
+            let protocol = Protocol::negotiate(1, WINDOWED, packet_size, window_size, HOST_CAPABILITIES);
+
+            (protocol.packet_size, protocol.window_size)
+        };
+
+        assert_eq!(sizes(1001, 8), (1000, 8));
+        assert_eq!(sizes(4, 0), (LOCK_STEP_PACKET_SIZE, 1));
+        assert_eq!(sizes(65535, 255), (MAX_PACKET_SIZE, MAX_WINDOW_SIZE));
+    }
+
+    #[test]
+    fn negotiate_ignores_unknown_capabilities_and_versions() {
+        let protocol = Protocol::negotiate(200, 0xFFFF, 256, 4, 0xFFFF);
+
+        assert_eq!(protocol.version, PROTOCOL_VERSION);
+        assert_eq!(protocol.capabilities, HOST_CAPABILITIES);
+    }
+
+    #[test]
+    fn negotiate_only_host_capabilities() {
+        let protocol = Protocol::negotiate(1, HOST_CAPABILITIES, 256, 4, HOST_CAPABILITIES & !FRAMING);
+
+        assert!(!protocol.supports(FRAMING));
+        assert!(protocol.supports(WINDOWED));
+    }
+
+    #[test]
+    fn negotiate_delta_uploads_need_image_hash() {
+        let protocol = Protocol::negotiate(1, DELTA_UPLOAD, 256, 4, HOST_CAPABILITIES);
+
+        assert!(!protocol.supports(DELTA_UPLOAD));
+
+        let protocol = Protocol::negotiate(1, DELTA_UPLOAD | IMAGE_HASH, 256, 4, HOST_CAPABILITIES);
+
+        assert!(protocol.supports(DELTA_UPLOAD));
+    }
+}
@@ -0,0 +1,110 @@
+/// Registers which can be set before jumping, in the order
+/// their values are sent to the console.
+pub const REGISTERS : [&str; 6] = ["a0", "a1", "a2", "a3", "gp", "sp"];
+
+/// This structure defines where the console jumps
+/// to once a raw binary has been uploaded.
+pub struct Jump {
+    /// Address to jump to.
+    pub address : u32,
+
+    /// Registers to be set before jumping, where bit n refers to
+    /// REGISTERS[n]. Other registers are left untouched.
+    pub mask : u8,
+
+    /// Values for each register in REGISTERS,
+    /// only used if the matching bit in mask is set.
+    pub values : [u32; 6]
+}
+
+impl Jump {
+    /// This function parses a jump given on the command line:
+    /// an address, optionally followed by a comma-separated list
+    /// of registers and their values, e.g.: "0x80100000,sp=0x801FFF00".
+    pub fn parse(jump : &str) -> Result<Jump, String> {
+        let mut items = jump.split(',');
+
+        let address = items.next().and_then(parse_number);
+
+        let mut jump = match address {
+            Some(address) if address % 4 == 0 => Jump::to(address),
+            _ => return Err(format!("Invalid jump address in {:?}", jump))
+        };
+
+        for item in items {
+            let (register, value) = match item.split_once('=') {
+                Some((register, value)) => (register.trim(), value.trim()),
+                None => return Err(format!("Invalid register {:?}, expected REGISTER=VALUE", item))
+            };
+
+            let index = match REGISTERS.iter().position(|&r| r == register) {
+                Some(index) => index,
+                None => return Err(format!("Unknown register {:?}, expected one of {}", register, REGISTERS.join(", ")))
+            };
+
+            jump.values[index] = parse_number(value).ok_or(format!("Invalid value for {}: {}", register, value))?;
+            jump.mask |= 1 << index;
+        }
+
+        Ok(jump)
+    }
+
+    /// Returns a jump to the given address
+    /// where no register is set.
+    pub fn to(address : u32) -> Jump {
+        Jump {
+            address,
+            mask : 0,
+            values : [0; 6]
+        }
+    }
+}
+
+/// This structure describes a raw binary to be
+/// uploaded to a given address of the console RAM,
+/// instead of a PSX-EXE.
+pub struct RawUpload {
+    /// Path to the binary file.
+    pub path : String,
+
+    /// Address where the binary is written.
+    pub address : u32,
+
+    /// Whether the console is asked to send back
+    /// the checksum of the written data.
+    pub verify : bool,
+
+    /// Where the console jumps to once the binary has been
+    /// written. Defaults to the address of the binary.
+    pub jump : Jump
+}
+
+impl RawUpload {
+    /// This function parses a raw binary given on the command line
+    /// as its path and load address, e.g.: "overlay.bin@0x80100000".
+    pub fn parse(upload : &str) -> Result<RawUpload, String> {
+        let (path, address) = match upload.rsplit_once('@') {
+            Some((path, address)) => (path, parse_number(address)),
+            None => return Err(format!("Invalid raw binary {:?}, expected FILE@ADDRESS", upload))
+        };
+
+        match address {
+            Some(address) => Ok(RawUpload {
+                path : String::from(path),
+                address,
+                verify : false,
+                jump : Jump::to(address)
+            }),
+            None => Err(format!("Invalid load address in {:?}", upload))
+        }
+    }
+}
+
+/// This function parses a number given either in
+/// hexadecimal, prefixed by "0x", or in decimal.
+pub fn parse_number(number : &str) -> Option<u32> {
+    match number.strip_prefix("0x").or_else(|| number.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => number.parse().ok()
+    }
+}
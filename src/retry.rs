@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+/// Longest time to wait for a reply, no matter how
+/// many times the timeout has been increased.
+const MAX_TIMEOUT : Duration = Duration::from_secs(30);
+
+/// This structure defines how long the host waits for the console
+/// on each phase of the transfer, and how many times a message is
+/// sent again, or waited for, before giving up.
+pub struct RetryPolicy {
+    /// Time to wait for the console to answer the initial transmission.
+    pub handshake : Duration,
+
+    /// Time to wait for the console to acknowledge
+    /// the EXE header, sizes or a window of data.
+    pub ack : Duration,
+
+    /// Time to wait for each packet sent by the console.
+    pub data : Duration,
+
+    /// Time to wait for a request before checking again.
+    pub request : Duration,
+
+    /// Time without receiving anything from the console, while
+    /// waiting for requests, after which a new session is started
+    /// in daemon mode. Only applied to old loaders, since newer
+    /// ones announce when they are reset.
+    pub silence : Duration,
+
+    /// Number of times a message is sent again, or
+    /// waited for, before the transfer is aborted.
+    pub max_retries : usize,
+
+    /// Factor applied to timeouts on each retry.
+    pub backoff : f64,
+
+    /// Maximum duration of the whole session, if any.
+    pub deadline : Option<Duration>
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy::new()
+    }
+}
+
+impl RetryPolicy {
+    pub fn new() -> RetryPolicy {
+        RetryPolicy {
+            handshake : Duration::from_secs(2),
+            ack : Duration::from_secs(2),
+            data : Duration::from_secs(2),
+            request : Duration::from_secs(5),
+            silence : Duration::from_secs(60),
+            max_retries : 8,
+            backoff : 2.0,
+            deadline : None
+        }
+    }
+
+    /// This function sets timeouts from a comma-separated list of
+    /// phases and timeouts in milliseconds, e.g.: "ack=500,data=1000".
+    /// Valid phases are "handshake", "ack", "data", "request" and "silence".
+    pub fn set_timeouts(&mut self, list : &str) -> Result<(), String> {
+        for item in list.split(',') {
+            let (phase, value) = match item.split_once('=') {
+                Some((phase, value)) => (phase.trim(), value.trim()),
+                None => return Err(format!("Invalid timeout {:?}, expected PHASE=MILLISECONDS", item))
+            };
+
+            let timeout = match value.parse::<u64>() {
+                Ok(ms) if ms > 0 => Duration::from_millis(ms),
+                _ => return Err(format!("Invalid timeout for {}: {}", phase, value))
+            };
+
+            match phase {
+                "handshake" => self.handshake = timeout,
+                "ack" => self.ack = timeout,
+                "data" => self.data = timeout,
+                "request" => self.request = timeout,
+                "silence" => self.silence = timeout,
+                _ => return Err(format!("Unknown timeout phase {:?}", phase))
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the time to wait after the given number of
+    /// retries, given the initial timeout for the phase.
+    pub fn timeout(&self, initial : Duration, retries : usize) -> Duration {
+        let factor = self.backoff.powi(std::cmp::min(retries, 16) as i32);
+
+        std::cmp::min(initial.mul_f64(factor), std::cmp::max(initial, MAX_TIMEOUT))
+    }
+
+    /// Returns whether the given number of retries
+    /// is still allowed by the policy.
+    pub fn allows(&self, retries : usize) -> bool {
+        retries <= self.max_retries
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    fs,
+    io,
+    time::{Duration, Instant}
+};
+
+use regex::Regex;
+
+use crash;
+
+/// Time given to a test run, unless given on the command line.
+pub const DEFAULT_TIMEOUT : Duration = Duration::from_secs(120);
+
+/// Line printed by the console, as debug text, once all tests have
+/// run, followed by an exit code and " ***", e.g.: "*** EXIT 0 ***".
+/// The run passes if the exit code is 0.
+const EXIT_MESSAGE : &str = "*** EXIT ";
+
+/// Suffix of EXIT_MESSAGE.
+const EXIT_MESSAGE_END : &str = " ***";
+
+/// Start of lines printed by the console, as debug text, to report
+/// a single test case, followed by its result (PASS, FAIL or SKIP),
+/// its name and, optionally, a message after a colon, e.g.:
+/// "TEST FAIL gte_rotate: expected 4096, got 0".
+const TEST_CASE_PREFIX : &str = "TEST ";
+
+/// This structure holds all settings given on
+/// the command line which apply to a test run.
+pub struct TestOptions {
+    /// Name of the test suite, as written on the report.
+    pub name : String,
+
+    /// The run passes once a line matches it.
+    pub pass_pattern : Option<Regex>,
+
+    /// The run fails once a line matches it.
+    pub fail_pattern : Option<Regex>,
+
+    /// The run fails if it has not finished after this time.
+    pub timeout : Duration,
+
+    /// Path to the JUnit XML report, if any.
+    pub report : Option<String>
+}
+
+/// This enum defines the result of a test case.
+enum CaseResult {
+    Passed,
+    Failed(String),
+    Skipped
+}
+
+/// This structure describes a test case reported by the console.
+struct TestCase {
+    name : String,
+    result : CaseResult,
+
+    /// Time since the previous test case was reported,
+    /// or since the run started for the first one.
+    time : Duration
+}
+
+/// This structure follows a test run, using debug text sent by the
+/// console to find reported test cases and the result of the run.
+pub struct TestRun {
+    options : TestOptions,
+
+    /// All debug text received so far.
+    output : Vec<u8>,
+
+    /// Text received since the last line break.
+    line : Vec<u8>,
+
+    cases : Vec<TestCase>,
+
+    /// Result of the run, once known: Ok if passed,
+    /// or the reason why it failed otherwise.
+    outcome : Option<Result<(), String>>,
+
+    started : Instant,
+    last_case : Instant
+}
+
+impl TestRun {
+    pub fn new(options : TestOptions) -> TestRun {
+        TestRun {
+            options,
+            output : Vec::new(),
+            line : Vec::new(),
+            cases : Vec::new(),
+            outcome : None,
+            started : Instant::now(),
+            last_case : Instant::now()
+        }
+    }
+
+    /// This function processes debug text sent by the console.
+    /// Text received once the outcome is known is ignored.
+    pub fn feed(&mut self, text : &[u8]) {
+        for &byte in text {
+            if self.outcome.is_some() {
+                return
+            }
+
+            self.output.push(byte);
+
+            if byte != b'\n' {
+                self.line.push(byte);
+                continue
+            }
+
+            let line = String::from_utf8_lossy(&self.line).trim().to_string();
+
+            self.line.clear();
+            self.process_line(&line);
+        }
+    }
+
+    fn process_line(&mut self, line : &str) {
+        if let Some(code) = line.strip_prefix(EXIT_MESSAGE).and_then(|l| l.strip_suffix(EXIT_MESSAGE_END)) {
+            self.outcome = Some(match code.trim().parse::<i32>() {
+                Ok(0) => Ok(()),
+                Ok(code) => Err(format!("The console exited with code {}", code)),
+                Err(_) => Err(format!("Invalid exit message: {}", line))
+            });
+        }
+        else if let Some(case) = line.strip_prefix(TEST_CASE_PREFIX) {
+            let (case, message) = match case.split_once(':') {
+                Some((case, message)) => (case, message.trim()),
+                None => (case, "")
+            };
+
+            let (result, name) = match case.split_once(' ') {
+                Some(("PASS", name)) => (CaseResult::Passed, name),
+                Some(("FAIL", name)) => (CaseResult::Failed(String::from(message)), name),
+                Some(("SKIP", name)) => (CaseResult::Skipped, name),
+                _ => return
+            };
+
+            self.cases.push(TestCase {
+                name : String::from(name.trim()),
+                result,
+                time : self.last_case.elapsed()
+            });
+
+            self.last_case = Instant::now();
+        }
+        // A crash report is only printed once decoded.
+        else if line == crash::REPORT_END {
+            self.outcome = Some(Err(String::from("The console crashed")));
+        }
+        else if self.options.fail_pattern.as_ref().is_some_and(|p| p.is_match(line)) {
+            self.outcome = Some(Err(format!("Found failure: {}", line)));
+        }
+        else if self.options.pass_pattern.as_ref().is_some_and(|p| p.is_match(line)) {
+            self.outcome = Some(Ok(()));
+        }
+    }
+
+    /// Returns whether the run has finished, either
+    /// because its outcome is known or it timed out.
+    pub fn finished(&mut self) -> bool {
+        if self.outcome.is_none() && self.started.elapsed() > self.options.timeout {
+            self.outcome = Some(Err(format!("Timed out after {} seconds", self.options.timeout.as_secs())));
+        }
+
+        self.outcome.is_some()
+    }
+
+    /// This function ends the run, given the result of the session,
+    /// and writes the report if requested. Returns an error if the
+    /// run failed, so the exit status can be used by CI scripts.
+    /// Any test case reported as failed makes the run fail.
+    pub fn finish(mut self, session : io::Result<()>) -> io::Result<()> {
+        let failed = self.cases.iter().filter(|c| matches!(c.result, CaseResult::Failed(_))).count();
+
+        let outcome = match self.outcome.take() {
+            Some(Ok(())) if failed > 0 => Err(format!("{} of {} test cases failed", failed, self.cases.len())),
+            Some(outcome) => outcome,
+            None => Err(match session {
+                Err(e) => e.to_string(),
+                Ok(_) => String::from("The session ended before the tests finished")
+            })
+        };
+
+        if let Some(ref path) = self.options.report {
+            fs::write(path, self.junit_report(&outcome))?;
+
+            println!("\nWrote test report into {}", path);
+        }
+
+        match outcome {
+            Ok(()) => {
+                println!("\nTests passed ({} test cases)", self.cases.len());
+                Ok(())
+            },
+            Err(reason) => Err(io::Error::other(format!("Tests failed: {}", reason)))
+        }
+    }
+
+    /// Returns a JUnit XML report with all test cases reported by the
+    /// console. A test case named after the suite is added to report
+    /// any failure not related to test cases, e.g.: a crash, or the
+    /// whole run if no test cases were reported.
+    fn junit_report(&self, outcome : &Result<(), String>) -> String {
+        let suite = self.options.name.as_str();
+        let mut cases = String::new();
+        let mut failures = 0;
+        let mut skipped = 0;
+
+        for case in self.cases.iter() {
+            cases += &format!("    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                              escape(&case.name),
+                              escape(suite),
+                              case.time.as_secs_f64());
+
+            cases += &match case.result {
+                CaseResult::Passed => String::from("/>\n"),
+                CaseResult::Failed(ref message) => {
+                    failures += 1;
+                    format!(">\n      <failure message=\"{}\"/>\n    </testcase>\n", escape(message))
+                },
+                CaseResult::Skipped => {
+                    skipped += 1;
+                    String::from(">\n      <skipped/>\n    </testcase>\n")
+                }
+            };
+        }
+
+        let mut tests = self.cases.len();
+
+        match *outcome {
+            Err(ref reason) if failures == 0 => {
+                tests += 1;
+                failures += 1;
+                cases += &format!("    <testcase name=\"{0}\" classname=\"{0}\" time=\"{1:.3}\">\n      <failure message=\"{2}\"/>\n    </testcase>\n",
+                                  escape(suite),
+                                  self.started.elapsed().as_secs_f64(),
+                                  escape(reason));
+            },
+            Ok(()) if tests == 0 => {
+                tests += 1;
+                cases += &format!("    <testcase name=\"{0}\" classname=\"{0}\" time=\"{1:.3}\"/>\n",
+                                  escape(suite),
+                                  self.started.elapsed().as_secs_f64());
+            },
+            _ => {}
+        }
+
+        format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                 <testsuites>\n  \
+                 <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n\
+                 {}    \
+                 <system-out>{}</system-out>\n  \
+                 </testsuite>\n\
+                 </testsuites>\n",
+                escape(suite),
+                tests,
+                failures,
+                skipped,
+                self.started.elapsed().as_secs_f64(),
+                cases,
+                escape(&String::from_utf8_lossy(&self.output)))
+    }
+}
+
+/// Returns the given text, escaped so it can be written
+/// on XML attributes and text. Control characters not
+/// allowed by XML, other than line breaks and tabs, are dropped.
+fn escape(text : &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&apos;",
+            '\n' | '\t' | '\r' => escaped.push(c),
+            c if c.is_control() => {},
+            c => escaped.push(c)
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options() -> TestOptions {
+        TestOptions {
+            name : String::from("gte"),
+            pass_pattern : None,
+            fail_pattern : None,
+            timeout : DEFAULT_TIMEOUT,
+            report : None
+        }
+    }
+
+    fn names(run : &TestRun) -> Vec<&str> {
+        run.cases.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_cases_parsed() {
+        let mut run = TestRun::new(options());
+
+        // Lines might be split across debug frames.
+        run.feed(b"booting\r\nTEST PASS gte_rtps\nTEST FA");
+        run.feed(b"IL gte_rotate: expected 4096, got 0\r\n");
+        run.feed(b"TEST SKIP gte_ncds \nTEST MAYBE nothing\nTEST PASS\n");
+
+        assert_eq!(names(&run), ["gte_rtps", "gte_rotate", "gte_ncds"]);
+        assert!(matches!(run.cases[0].result, CaseResult::Passed));
+        assert!(matches!(run.cases[1].result, CaseResult::Failed(ref m) if m == "expected 4096, got 0"));
+        assert!(matches!(run.cases[2].result, CaseResult::Skipped));
+        assert!(!run.finished());
+    }
+
+    #[test]
+    fn exit_codes() {
+        let mut run = TestRun::new(options());
+
+        run.feed(b"*** EXIT 0 ***\nTEST PASS late\n");
+        assert!(run.finished());
+        assert_eq!(run.outcome, Some(Ok(())));
+        // Text received once finished is ignored.
+        assert!(run.cases.is_empty());
+
+        let mut run = TestRun::new(options());
+
+        run.feed(b"*** EXIT -3 ***\n");
+        assert_eq!(run.outcome, Some(Err(String::from("The console exited with code -3"))));
+
+        let mut run = TestRun::new(options());
+
+        run.feed(b"*** EXIT zero ***\n");
+        assert!(matches!(run.outcome, Some(Err(ref reason)) if reason.starts_with("Invalid exit message")));
+    }
+
+    #[test]
+    fn crashes_and_patterns() {
+        let mut run = TestRun::new(options());
+
+        run.feed(format!("{}\n", crash::REPORT_END).as_bytes());
+        assert_eq!(run.outcome, Some(Err(String::from("The console crashed"))));
+
+        let mut run = TestRun::new(TestOptions {
+            pass_pattern : Some(Regex::new("^ALL DONE$").expect("Could not compile regex")),
+            fail_pattern : Some(Regex::new("ASSERT").expect("Could not compile regex")),
+            ..options()
+        });
+
+        run.feed(b"ALL DONE?\n");
+        assert!(!run.finished());
+        run.feed(b"ASSERT failed at main.c:12\n");
+        assert_eq!(run.outcome, Some(Err(String::from("Found failure: ASSERT failed at main.c:12"))));
+
+        let mut run = TestRun::new(TestOptions {
+            pass_pattern : Some(Regex::new("^ALL DONE$").expect("Could not compile regex")),
+            ..options()
+        });
+
+        run.feed(b"ALL DONE\n");
+        assert_eq!(run.outcome, Some(Ok(())));
+    }
+
+    #[test]
+    fn timeouts() {
+        let mut run = TestRun::new(TestOptions { timeout : Duration::ZERO, ..options() });
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(run.finished());
+        assert!(matches!(run.outcome, Some(Err(ref reason)) if reason.starts_with("Timed out")));
+    }
+
+    #[test]
+    fn failed_cases_fail_the_run() {
+        let mut run = TestRun::new(options());
+
+        run.feed(b"TEST FAIL a\nTEST PASS b\n*** EXIT 0 ***\n");
+        assert!(run.finish(Ok(())).is_err());
+
+        let mut run = TestRun::new(options());
+
+        run.feed(b"TEST PASS a\n*** EXIT 0 ***\n");
+        assert!(run.finish(Ok(())).is_ok());
+
+        // Sessions ending before the outcome is known fail.
+        assert!(TestRun::new(options()).finish(Ok(())).is_err());
+    }
+
+    #[test]
+    fn escape_text() {
+        assert_eq!(escape(r#"<a href="x">Tom & 'Jerry'</a>"#),
+                   "&lt;a href=&quot;x&quot;&gt;Tom &amp; &apos;Jerry&apos;&lt;/a&gt;");
+        assert_eq!(escape("line\r\n\ttab\u{0}\u{1B}[0m é"), "line\r\n\ttab[0m é");
+    }
+
+    #[test]
+    fn junit_reports() {
+        let mut run = TestRun::new(TestOptions { name : String::from("a<b>"), ..options() });
+
+        run.feed(b"TEST PASS x&y\nTEST FAIL \"q\": 1 < 2\nTEST SKIP z\n*** EXIT 0 ***\n");
+
+        let report = run.junit_report(&Ok(()));
+
+        assert!(report.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n"));
+        assert!(report.contains("<testsuite name=\"a&lt;b&gt;\" tests=\"3\" failures=\"1\" skipped=\"1\""));
+        assert!(report.contains("<testcase name=\"x&amp;y\" classname=\"a&lt;b&gt;\""));
+        assert!(report.contains("<testcase name=\"&quot;q&quot;\" classname=\"a&lt;b&gt;\""));
+        assert!(report.contains("<failure message=\"1 &lt; 2\"/>"));
+        assert!(report.contains("<skipped/>"));
+        assert!(report.contains("<system-out>TEST PASS x&amp;y\n"));
+        assert!(report.ends_with("</system-out>\n  </testsuite>\n</testsuites>\n"));
+    }
+
+    #[test]
+    fn junit_reports_without_test_cases() {
+        let run = TestRun::new(options());
+
+        // Failures not related to test cases are reported
+        // as a test case named after the suite.
+        let report = run.junit_report(&Err(String::from("The console crashed")));
+
+        assert!(report.contains("tests=\"1\" failures=\"1\" skipped=\"0\""));
+        assert!(report.contains("<testcase name=\"gte\" classname=\"gte\""));
+        assert!(report.contains("<failure message=\"The console crashed\"/>"));
+
+        let report = run.junit_report(&Ok(()));
+
+        assert!(report.contains("tests=\"1\" failures=\"0\" skipped=\"0\""));
+        assert!(!report.contains("<failure"));
+    }
+}
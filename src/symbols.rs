@@ -0,0 +1,488 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io,
+    path::Path
+};
+
+use regex::Regex;
+
+use dwarf::DebugInfo;
+use raw;
+
+/// Bytes found at the start of any ELF file.
+const ELF_MAGIC : [u8; 4] = [0x7F, b'E', b'L', b'F'];
+
+/// Section type holding the symbol table of an ELF file.
+const SHT_SYMTAB : u32 = 2;
+
+/// Section type taking no space in the file, e.g.: .bss.
+const SHT_NOBITS : u32 = 8;
+
+/// Size of each entry inside an ELF32 symbol table.
+const ELF32_SYMBOL_SIZE : usize = 16;
+
+/// Type of ELF symbols referring to data.
+const STT_OBJECT : u8 = 1;
+
+/// Section index of ELF symbols defined by another file,
+/// whose address is not known.
+const SHN_UNDEF : u16 = 0;
+
+/// Headers found on map files written by Psy-Q's PSYLINK,
+/// before the list of symbols sorted by name and by address.
+const PSYQ_MAP_HEADERS : [&str; 2] = ["Names alphabetically", "Names in address order"];
+
+/// Extensions of files looked for next to the
+/// executable when no symbols are given, by preference.
+const SYMBOL_FILE_EXTENSIONS : [&str; 4] = ["elf", "ELF", "map", "MAP"];
+
+/// This structure describes a symbol of the uploaded program.
+pub struct Symbol {
+    pub name : String,
+    pub address : u32,
+
+    /// Size in bytes, or 0 if unknown.
+    pub size : u32,
+
+    /// Whether the symbol is known to refer to data rather
+    /// than code. Map files do not tell, so it is always
+    /// false for their symbols.
+    pub is_data : bool
+}
+
+/// This structure holds the symbols of the uploaded program,
+/// so they can be used instead of addresses.
+pub struct SymbolTable {
+    /// Sorted by address.
+    symbols : Vec<Symbol>,
+
+    /// Types of global variables, if read from an ELF file
+    /// holding debugging information.
+    debug_info : Option<DebugInfo>
+}
+
+impl SymbolTable {
+    /// This function loads symbols from the file found at path,
+    /// either an ELF file or a map file written by GNU ld or Psy-Q.
+    pub fn load(path : &str) -> io::Result<SymbolTable> {
+        let data = fs::read(path)?;
+        let mut debug_info : Option<DebugInfo> = None;
+
+        let symbols = if data.starts_with(&ELF_MAGIC) {
+            read_elf_sections(&data).and_then(|sections| {
+                debug_info = read_debug_info(&sections);
+                read_elf(&sections)
+            })
+        }
+        else
+        {
+            let map = String::from_utf8_lossy(&data);
+
+            if PSYQ_MAP_HEADERS.iter().any(|header| map.contains(header)) {
+                Ok(read_psyq_map(&map))
+            }
+            else
+            {
+                Ok(read_gnu_map(&map))
+            }
+        };
+
+        match symbols {
+            Ok(symbols) if symbols.is_empty() => {
+                Err(io::Error::new(io::ErrorKind::InvalidData, format!("No symbols found on {}", path)))
+            },
+            Ok(mut symbols) => {
+                symbols.sort_by(|a, b| a.address.cmp(&b.address).then_with(|| a.name.cmp(&b.name)));
+
+                Ok(SymbolTable { symbols, debug_info })
+            },
+            Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))
+        }
+    }
+
+    /// This function looks for a symbol file next to the executable
+    /// found at exe_path, named as it, and returns its path if found.
+    pub fn find_for(exe_path : &str) -> Option<String> {
+        SYMBOL_FILE_EXTENSIONS.iter()
+                              .map(|extension| Path::new(exe_path).with_extension(extension))
+                              .find(|path| path.is_file())
+                              .and_then(|path| path.to_str().map(String::from))
+    }
+
+    /// Returns the symbol with the given name, if any.
+    pub fn find(&self, name : &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    /// Returns the symbol the given address belongs to, if any,
+    /// and the offset of the address from it. Symbols without
+    /// a known size extend up to the next one.
+    pub fn lookup(&self, address : u32) -> Option<(&Symbol, u32)> {
+        let index = self.symbols.partition_point(|s| s.address <= address).checked_sub(1)?;
+        let symbol = &self.symbols[index];
+        let offset = address - symbol.address;
+
+        if symbol.size != 0 && offset >= symbol.size {
+            None
+        }
+        else
+        {
+            Some((symbol, offset))
+        }
+    }
+
+    /// Returns the given address as text, followed by
+    /// the symbol it belongs to if any, e.g.: "0x80010024 main+0x24".
+    pub fn describe(&self, address : u32) -> String {
+        match self.lookup(address) {
+            Some((symbol, 0)) => format!("{:#010X} {}", address, symbol.name),
+            Some((symbol, offset)) => format!("{:#010X} {}+{:#X}", address, symbol.name, offset),
+            None => format!("{:#010X}", address)
+        }
+    }
+}
+
+/// This function returns the address of a location given either
+/// as a number or, if symbols are available, as a symbol name
+/// optionally followed by an offset, e.g.: "player+0x10". If the
+/// symbols were read from an ELF file holding debugging information,
+/// struct members of global variables can be given by name as well,
+/// e.g.: "player.health" or "player.pos.x+2".
+pub fn resolve(location : &str, symbols : Option<&SymbolTable>) -> Result<u32, String> {
+    if let Some(address) = raw::parse_number(location) {
+        return Ok(address)
+    }
+
+    let symbols = symbols.ok_or(format!("Invalid address {:?}, and no symbols were loaded", location))?;
+
+    // Symbols might contain dots, e.g.: static variables named
+    // "counter.0" by GCC, so the whole name is looked for first
+    // and only then taken as a variable followed by its members.
+    let (name, offset) = match location.find(['+', '-']) {
+        Some(pos) => {
+            let offset = raw::parse_number(&location[pos + 1..]).ok_or(format!("Invalid offset in {:?}", location))?;
+
+            (&location[..pos], if location.as_bytes()[pos] == b'+' { offset } else { offset.wrapping_neg() })
+        },
+        None => (location, 0)
+    };
+
+    if let Some(symbol) = symbols.find(name) {
+        return Ok(symbol.address.wrapping_add(offset))
+    }
+
+    let (variable, members) = match name.find('.') {
+        Some(pos) => (&name[..pos], &name[pos + 1..]),
+        None => return Err(format!("Unknown symbol {:?}", name))
+    };
+
+    let symbol = symbols.find(variable).ok_or(format!("Unknown symbol {:?}", variable))?;
+
+    let member_offset = match symbols.debug_info {
+        Some(ref debug_info) => debug_info.member_offset(variable, members)
+                                          .ok_or(format!("Unknown member {:?} of {:?}", members, variable))?,
+        None => return Err(format!("Unknown symbol {:?}, and struct members are only known from ELF files with debugging information", name))
+    };
+
+    Ok(symbol.address.wrapping_add(member_offset).wrapping_add(offset))
+}
+
+/// This function prints the address of each given symbol, optionally
+/// followed by an offset, or the symbol each given address belongs to.
+pub fn print_lookups(symbols : Option<&SymbolTable>, queries : &[&str]) -> io::Result<()> {
+    let symbols = symbols.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No symbols were loaded"))?;
+
+    if queries.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Missing symbols or addresses for symbol"))
+    }
+
+    for query in queries {
+        if let Some(address) = raw::parse_number(query) {
+            println!("{}", symbols.describe(address));
+            continue
+        }
+
+        match resolve(query, Some(symbols)) {
+            Ok(address) => println!("{} = {:#010X}", query, address),
+            Err(e) => println!("{}", e)
+        }
+    }
+
+    Ok(())
+}
+
+/// This structure describes a section of an ELF file.
+struct ElfSection<'a> {
+    name : &'a [u8],
+    kind : u32,
+    data : &'a [u8],
+
+    /// Index of the related section, e.g.: the
+    /// string table used by a symbol table.
+    link : usize
+}
+
+/// This function reads the section headers of a little-endian ELF32 file.
+fn read_elf_sections(data : &[u8]) -> Result<Vec<ElfSection<'_>>, &'static str> {
+    let u16_at = |pos : usize| data.get(pos..pos + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+    let u32_at = |pos : usize| data.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+
+    // Only 32-bit little-endian files are expected.
+    if data.get(4..6) != Some(&[1, 1]) {
+        return Err("Only 32-bit little-endian ELF files are supported")
+    }
+
+    let invalid = "Invalid ELF file";
+
+    let section_offset = u32_at(0x20).ok_or(invalid)? as usize;
+    let section_size = u16_at(0x2E).ok_or(invalid)? as usize;
+    let section_count = u16_at(0x30).ok_or(invalid)? as usize;
+    let names_index = u16_at(0x32).ok_or(invalid)? as usize;
+
+    let mut sections : Vec<(u32, u32, &[u8], usize)> = Vec::new();
+
+    for index in 0..section_count {
+        let header = section_offset + index * section_size;
+        let kind = u32_at(header + 4).ok_or(invalid)?;
+        let offset = u32_at(header + 0x10).ok_or(invalid)? as usize;
+        let size = u32_at(header + 0x14).ok_or(invalid)? as usize;
+
+        // Sections without contents in the file, e.g.: .bss.
+        let contents = if kind == SHT_NOBITS { &[][..] } else { data.get(offset..offset + size).ok_or(invalid)? };
+
+        sections.push((u32_at(header).ok_or(invalid)?, kind, contents, u32_at(header + 0x18).ok_or(invalid)? as usize));
+    }
+
+    let names = sections.get(names_index).map(|section| section.2).unwrap_or_default();
+
+    Ok(sections.into_iter()
+               .map(|(name, kind, data, link)| ElfSection {
+                   name : names.get(name as usize..)
+                               .and_then(|s| s.split(|&b| b == 0).next())
+                               .unwrap_or_default(),
+                   kind,
+                   data,
+                   link
+               })
+               .collect())
+}
+
+/// This function reads all named functions and objects
+/// from the symbol table of an ELF file.
+fn read_elf(sections : &[ElfSection]) -> Result<Vec<Symbol>, &'static str> {
+    let invalid = "Invalid ELF file";
+
+    let mut symbols : Vec<Symbol> = Vec::new();
+
+    for section in sections.iter().filter(|section| section.kind == SHT_SYMTAB) {
+        let data = section.data;
+        let strings = sections.get(section.link).ok_or(invalid)?.data;
+
+        let u16_at = |pos : usize| data.get(pos..pos + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let u32_at = |pos : usize| data.get(pos..pos + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+
+        for entry in (0..data.len()).step_by(ELF32_SYMBOL_SIZE) {
+            let name_offset = u32_at(entry).ok_or(invalid)? as usize;
+            let kind = data.get(entry + 12).ok_or(invalid)? & 0xF;
+            let section_index = u16_at(entry + 14).ok_or(invalid)?;
+
+            // Only untyped symbols, objects and functions
+            // defined by the file itself are kept.
+            if name_offset == 0 || kind > 2 || section_index == SHN_UNDEF {
+                continue
+            }
+
+            let name = strings.get(name_offset..)
+                              .and_then(|s| s.split(|&b| b == 0).next())
+                              .ok_or(invalid)?;
+
+            symbols.push(Symbol {
+                name : String::from_utf8_lossy(name).into_owned(),
+                address : u32_at(entry + 4).ok_or(invalid)?,
+                size : u32_at(entry + 8).ok_or(invalid)?,
+                is_data : kind == STT_OBJECT
+            });
+        }
+    }
+
+    Ok(symbols)
+}
+
+/// This function reads the DWARF debugging information of an
+/// ELF file, if any, so struct members can be found by name.
+fn read_debug_info(sections : &[ElfSection]) -> Option<DebugInfo> {
+    let section = |name : &str| sections.iter().find(|section| section.name == name.as_bytes()).map(|section| section.data);
+
+    Some(DebugInfo::read(section(".debug_info")?,
+                         section(".debug_abbrev")?,
+                         section(".debug_str").unwrap_or_default(),
+                         section(".debug_line_str").unwrap_or_default()))
+}
+
+/// This function reads all symbols from a map file written by
+/// GNU ld, found on lines made of an address followed by a name.
+fn read_gnu_map(map : &str) -> Vec<Symbol> {
+    lazy_static! {
+        static ref RX: Regex = Regex::new(r"^\s+0x([0-9a-fA-F]+)\s+([A-Za-z_][A-Za-z0-9_.$]*)\s*$").expect("Could not compile regex");
+    }
+
+    map.lines()
+       .filter_map(|line| RX.captures(line))
+       .filter_map(|c| {
+           u64::from_str_radix(&c[1], 16).ok().map(|address| Symbol {
+               name : String::from(&c[2]),
+               address : address as u32,
+               size : 0,
+               is_data : false
+           })
+       })
+       .collect()
+}
+
+/// This function reads all symbols from a map file written by
+/// Psy-Q's PSYLINK, found on lines made of an address, without
+/// prefix, followed by a name. Since symbols are listed twice,
+/// sorted by name and by address, duplicates are removed.
+fn read_psyq_map(map : &str) -> Vec<Symbol> {
+    lazy_static! {
+        static ref RX: Regex = Regex::new(r"^\s*([0-9a-fA-F]{8})\s+([A-Za-z_][A-Za-z0-9_.$]*)\s*$").expect("Could not compile regex");
+    }
+
+    let mut found : HashSet<(u32, String)> = HashSet::new();
+
+    for c in map.lines().filter_map(|line| RX.captures(line)) {
+        if let Ok(address) = u32::from_str_radix(&c[1], 16) {
+            found.insert((address, String::from(&c[2])));
+        }
+    }
+
+    found.into_iter()
+         .map(|(address, name)| Symbol {
+             name,
+             address,
+             size : 0,
+             is_data : false
+         })
+         .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the path of a file found on tests/fixtures.
+    /// See tests/fixtures/symbols.c for how they were built.
+    fn fixture(name : &str) -> String {
+        format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    fn load(name : &str) -> SymbolTable {
+        SymbolTable::load(&fixture(name)).expect("Could not load fixture")
+    }
+
+    #[test]
+    fn members_resolved_from_dwarf() {
+        // symbols.elf holds DWARF 4 and symbols.o DWARF 5.
+        for (name, player, origin) in [("symbols.elf", 0x0804B004, 0x0804A000), ("symbols.o", 0, 0)] {
+            let symbols = load(name);
+            let symbols = Some(&symbols);
+
+            assert_eq!(resolve("player", symbols), Ok(player), "{}", name);
+            assert_eq!(resolve("player.score", symbols), Ok(player), "{}", name);
+            assert_eq!(resolve("player.pos.y", symbols), Ok(player + 6), "{}", name);
+            assert_eq!(resolve("player.pos.y+2", symbols), Ok(player + 8), "{}", name);
+            // Members of an anonymous union.
+            assert_eq!(resolve("player.health", symbols), Ok(player + 8), "{}", name);
+            assert_eq!(resolve("player.shield", symbols), Ok(player + 8), "{}", name);
+            // Variables whose type is qualified.
+            assert_eq!(resolve("origin.y", symbols), Ok(origin + 2), "{}", name);
+        }
+    }
+
+    #[test]
+    fn symbols_with_dots_preferred_over_members() {
+        let symbols = load("symbols.elf");
+        let frames = symbols.find("frames.0").expect("Could not find frames.0").address;
+
+        assert_eq!(resolve("frames.0", Some(&symbols)), Ok(frames));
+        assert_eq!(resolve("frames.0-4", Some(&symbols)), Ok(frames - 4));
+    }
+
+    #[test]
+    fn unknown_members_rejected() {
+        let symbols = load("symbols.elf");
+
+        assert!(resolve("player.nope", Some(&symbols)).is_err());
+        assert!(resolve("player.pos.z", Some(&symbols)).is_err());
+        assert!(resolve("counter.x", Some(&symbols)).is_err());
+        assert!(resolve("nobody.x", Some(&symbols)).is_err());
+
+        // Map files do not describe types.
+        assert!(resolve("player.health", Some(&load("symbols.map"))).is_err());
+    }
+
+    #[test]
+    fn elf_symbols() {
+        let symbols = load("symbols.elf");
+        let player = symbols.find("player").expect("Could not find player");
+
+        assert_eq!((player.address, player.size, player.is_data), (0x0804B004, 12, true));
+
+        let start = symbols.find("_start").expect("Could not find _start");
+
+        assert_eq!((start.address, start.is_data), (0x08049000, false));
+        assert!(symbols.find("frames.0").is_some());
+    }
+
+    #[test]
+    fn undefined_elf_symbols_skipped() {
+        // symbols.o refers to a weak symbol defined elsewhere,
+        // which must not be taken as found at address 0.
+        let symbols = load("symbols.o");
+
+        assert!(symbols.find("missing").is_none());
+        assert!(symbols.find("player").is_some());
+        assert_eq!(resolve("missing", Some(&symbols)), Err(String::from("Unknown symbol \"missing\"")));
+    }
+
+    #[test]
+    fn gnu_map_symbols() {
+        let symbols = load("symbols.map");
+
+        for (name, address) in [("_start", 0x08049000), ("origin", 0x0804A000), ("player", 0x0804B004), ("counter", 0x0804B010)] {
+            assert_eq!(symbols.find(name).map(|s| s.address), Some(address), "{}", name);
+        }
+
+        // Assignments found on the map are not symbols.
+        assert!(symbols.find("__bss_start").is_none());
+    }
+
+    #[test]
+    fn psyq_map_symbols() {
+        let symbols = load("psyq.map");
+        let names : Vec<(&str, u32)> = symbols.symbols.iter().map(|s| (s.name.as_str(), s.address)).collect();
+
+        // Symbols listed twice are kept once, sorted by address.
+        assert_eq!(names, [("_start", 0x80010000), ("origin", 0x80010040), ("player", 0x80010044), ("counter", 0x80010050)]);
+        assert_eq!(resolve("player+4", Some(&symbols)), Ok(0x80010048));
+    }
+
+    #[test]
+    fn lookup_and_describe() {
+        let symbols = load("symbols.elf");
+
+        assert_eq!(symbols.lookup(0x0804B008).map(|(s, offset)| (s.name.as_str(), offset)), Some(("player", 4)));
+        assert_eq!(symbols.describe(0x0804B004), "0x0804B004 player");
+        assert_eq!(symbols.describe(0x0804B00B), "0x0804B00B player+0x7");
+        // Past the end of a symbol with a known size.
+        assert!(symbols.lookup(0x0804A004).is_none());
+        assert_eq!(symbols.describe(0x08000000), "0x08000000");
+
+        // Map symbols extend up to the next one.
+        let symbols = load("psyq.map");
+
+        assert_eq!(symbols.describe(0x8001004C), "0x8001004C player+0x8");
+        assert_eq!(symbols.describe(0x80010100), "0x80010100 counter+0xB0");
+    }
+}
@@ -1,6 +1,20 @@
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum TransferState {
     FirstContact,
+    Handshake,
+
+    /// The console holds an executable in RAM, which
+    /// is compared against the one to be sent.
+    CheckImage,
+
+    /// Only blocks changed since the last upload are sent.
+    SendDelta,
+
+    /// A raw binary is sent instead of a PSX-EXE, optionally
+    /// verified, and then the console jumps into it.
+    SendRaw,
+    VerifyRaw,
+    Jump,
     WaitAck,
     SendHeader,
     SendExeSize,
@@ -8,346 +22,1736 @@ pub enum TransferState {
     SendExeData,
     WaitFileRequest,
     SendFile,
-    Finished
+    ReceiveFile,
+    HostFileRequest,
+    DirectoryRequest,
+    Finished,
+
+    /// The transfer was aborted, e.g.: since the console stopped
+    /// responding or written data could not be verified.
+    Failed
+}
+
+use link::{self, Link};
+use framer::{RequestFramer, FrameError};
+use crc;
+use protocol::{self, Protocol};
+use payload::{Payload, Cache};
+use pcdrv::HandleTable;
+use retry::RetryPolicy;
+use raw::{RawUpload, Jump};
+
+/// Byte sent by the console to acknowledge a packet.
+pub const ACK : u8 = b'b';
+
+/// Byte sent by the console when one of the packets inside
+/// the last window was corrupted. It is followed by the index
+/// of the first packet that must be sent again.
+pub const NAK : u8 = b'n';
+
+/// Byte sent to the console when a request cannot be served.
+/// For file reads, it is sent in place of the file size, e.g.:
+/// when the file is missing or the requested range is invalid.
+const ERROR_REPLY : u8 = b'e';
+
+/// Suffix added to the name of files written by the console
+/// while they are being received. See Upload for further details.
+const UPLOAD_TEMP_SUFFIX : &str = ".part";
+
+/// Packet index sent after NAK when the checksum
+/// of the whole image or file did not match.
+const NAK_WHOLE_IMAGE : u8 = 0xFF;
+
+/// Byte sent to the console so the running executable returns
+/// to the loader, e.g.: when a new build is available. It is sent
+/// inside a COMMAND frame when framed. Only executables which
+/// listen for it can react, so the console can also be reset
+/// through a serial control line instead.
+const RELOAD_COMMAND : u8 = b'r';
+
+/// Byte sent to the console instead of ACK or NAK after checking
+/// the hash of the executable it holds, if only blocks changed
+/// since the last upload are sent. It is followed by the size of
+/// the delta, as for any other data, and then by the delta itself,
+/// made of as many blocks as needed:
+/// - Offset inside the image (32-bit, little-endian).
+/// - Length (32-bit, little-endian).
+/// - Data.
+///
+/// See Image for further details about offsets.
+const DELTA_REPLY : u8 = b'd';
+
+/// Number of bytes compared at once when looking for changed blocks.
+const DELTA_BLOCK_SIZE : usize = 64;
+
+/// Command sent to the console, instead of the EXE header or the
+/// reply to its image hash, so a raw binary is written to RAM.
+/// It is followed by the load address (32-bit, little-endian),
+/// and then by the size of the binary, as for any other data.
+/// The binary is sent once the console acknowledges the size.
+const LOAD_COMMAND : u8 = b'l';
+
+/// Command sent to the console so it replies with the CRC-32
+/// (32-bit, little-endian) of a range of its RAM. It is followed by:
+/// - Address (32-bit, little-endian).
+/// - Length (32-bit, little-endian).
+const VERIFY_COMMAND : u8 = b'c';
+
+/// Command sent to the console so it jumps to a given address.
+/// It is followed by:
+/// - Address (32-bit, little-endian).
+/// - Mask of registers to be set (8-bit). See raw::REGISTERS.
+/// - Values of the registers in the mask (32-bit, little-endian).
+const JUMP_COMMAND : u8 = b'j';
+
+/// Byte sent by the console instead of ACK on the first contact
+/// when it reports its protocol version. It is followed by:
+/// - Protocol version (8-bit).
+/// - Capability flags (16-bit, little-endian).
+/// - Maximum packet size (16-bit, little-endian).
+/// - Maximum window size (8-bit).
+/// - CRC-32 of the executable held in RAM (32-bit, little-endian),
+///   or 0 if none. Only sent if the console reports IMAGE_HASH.
+///
+/// The host answers with the same byte and fields,
+/// filled with the parameters that will be used.
+const VERSION_REPLY : u8 = b'v';
+
+/// This structure keeps track of the last window
+/// of packets sent to the console, so it can be
+/// sent again if the console requests so.
+pub struct Window {
+    /// Offset of the first packet in the window.
+    offset : usize,
+
+    /// Number of times the window has been sent again.
+    retries : usize,
+
+    /// Number of times the whole image or file
+    /// has been sent again.
+    image_retries : usize
+}
+
+impl Window {
+    pub fn new() -> Window {
+        Window {
+            offset : 0,
+            retries : 0,
+            image_retries : 0
+        }
+    }
+}
+
+/// This structure holds the file
+/// currently requested by the console.
+pub struct FileTransfer {
+    /// File name, as requested by the console.
+    pub name : String,
+
+    /// Data sent to the console, only
+    /// available once the file has been read.
+    payload : Option<Payload>,
+
+    /// File written on behalf of the console,
+    /// only available for write requests.
+    upload : Option<Upload>
 }
 
-use serial;
+impl FileTransfer {
+    pub fn new() -> FileTransfer {
+        FileTransfer {
+            name : String::new(),
+            payload : None,
+            upload : None
+        }
+    }
+}
+
+/// This structure holds a file
+/// being sent by the console.
+struct Upload {
+    writer : std::io::BufWriter<std::fs::File>,
+
+    /// Path to the file on the host.
+    path : std::path::PathBuf,
+
+    /// Number of bytes announced by the console.
+    size : usize,
+
+    /// Number of bytes received so far.
+    received : usize,
+
+    /// File data is written into, if not path itself. It is renamed
+    /// to path once completely received, or removed if the upload
+    /// fails, so any file found at path is kept until then.
+    temp_path : Option<std::path::PathBuf>
+}
+
+pub fn first_contact(port : &mut Link) -> TransferState {
+    const INITIAL_TRANSMISSION: u8 = link::HELLO;
+    use std::io::Write;
+
+    // Framing is only used once agreed during the handshake.
+    (*port).set_framed(false);
+
+    // Replies to a previous session, or any announcement
+    // sent by the console, must not be taken as a reply.
+    (*port).clear_input();
+
+    match (*port).write(&[INITIAL_TRANSMISSION]) {
+        Err(_) => TransferState::FirstContact,
+        Ok(b) => {
+            if b == 1 {
+                TransferState::Handshake
+            }
+            else
+            {
+                TransferState::FirstContact
+            }
+        }
+    }
+}
+
+/// This function makes the console return to the loader, either
+/// by resetting it through the given control line, or by sending
+/// RELOAD_COMMAND otherwise. A new session is started afterwards.
+pub fn reload_console(port : &mut Link, reset_line : Option<link::ResetLine>) -> TransferState {
+    match reset_line {
+        Some(line) => {
+            println!("Resetting the console");
+
+            (*port).pulse(line).expect("Could not reset the device");
+        },
+        None => {
+            println!("Requesting the console to return to the loader");
+
+            (*port).send(link::COMMAND, &[RELOAD_COMMAND]).expect("Could not write command into the device");
+        }
+    }
+
+    TransferState::FirstContact
+}
+
+/// This function is called when the console announces it has been
+/// reset, so a new session is started. See link::HELLO.
+fn console_reset() -> TransferState {
+    println!("\nThe console has been reset, starting a new session");
+
+    TransferState::FirstContact
+}
+
+/// This function starts a new session if nothing has been received
+/// from the console for the silence timeout given by the policy.
+pub fn check_contact(port : &mut Link, policy : &RetryPolicy) -> TransferState {
+    if (*port).silence() < policy.silence {
+        return TransferState::WaitFileRequest
+    }
+
+    println!("\nNothing received from the console for {} seconds, starting a new session",
+             policy.silence.as_secs());
+
+    TransferState::FirstContact
+}
+
+/// This function waits for a single-byte reply from the console.
+/// Debug text received in the meantime is printed by the link.
+pub fn wait_ack(port : &mut Link, buffer : &mut [u8; 1], timeout : std::time::Duration) -> Result<usize, std::io::Error> {
+    // For some reason, this trait has to be imported,
+    // but shouldn't serial::SerialPort be already doing this?
+    use std::io::Read;
+
+    (*port).set_timeout(timeout).expect("Could not adjust timeout");
+
+    match (*port).read(buffer) {
+        // When framed, the link reports the console
+        // has been reset as an error. See link::HELLO.
+        Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionReset => {
+            buffer[0] = link::HELLO;
+            Ok(1)
+        },
+        result => result
+    }
+}
+
+/// This function reads data sent by the console as read_exact()
+/// does. If the data stops arriving, the console might have been
+/// reset while sending it, so an error of kind ConnectionReset is
+/// returned if the bytes received last are link::HELLO.
+fn read_data(port : &mut Link, buffer : &mut [u8]) -> std::io::Result<()> {
+    use std::io::{self, Read};
+
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        let error = match (*port).read(&mut buffer[filled..]) {
+            Ok(0) => io::Error::from(io::ErrorKind::UnexpectedEof),
+            Ok(n) => {
+                filled += n;
+                continue
+            },
+            Err(e) => e
+        };
+
+        // When framed, the link already looks for it.
+        if buffer[..filled].ends_with(&[link::HELLO, 0]) && !(*port).is_framed() {
+            return Err(io::Error::from(io::ErrorKind::ConnectionReset))
+        }
+
+        return Err(error)
+    }
+
+    Ok(())
+}
+
+/// This function waits for the console to answer the initial
+/// transmission. Old loaders reply with a single ACK, so lock-step
+/// transfers are used. Newer loaders report their protocol version
+/// and capabilities instead, and the best set of features supported
+/// by both sides is sent back to the console so they agree on it.
+/// Only capabilities enabled on the host are taken into account.
+/// Messages are framed right after the reply if agreed so.
+pub fn wait_handshake(port : &mut Link,
+                      protocol : &mut Protocol,
+                      capabilities : u16,
+                      image_hash : &mut u32,
+                      policy : &RetryPolicy) -> TransferState {
+    let mut buffer : [u8; 1] = [0];
+
+    *image_hash = 0;
+
+    match wait_ack(port, &mut buffer, policy.handshake) {
+        Ok(1) => {
+            match buffer[0] {
+                ACK => {
+                    *protocol = Protocol::lock_step();
+                },
+
+                VERSION_REPLY => {
+                    use std::io::{Read, Write};
+
+                    let mut params : [u8; 6] = [0; 6];
+
+                    if (*port).read_exact(&mut params).is_err() {
+                        println!("Could not read protocol version from the device");
+                        return TransferState::FirstContact
+                    }
+
+                    let console_capabilities = u16::from_le_bytes([params[1], params[2]]);
+
+                    if console_capabilities & protocol::IMAGE_HASH != 0 {
+                        let mut hash : [u8; 4] = [0; 4];
+
+                        if (*port).read_exact(&mut hash).is_err() {
+                            println!("Could not read image hash from the device");
+                            return TransferState::FirstContact
+                        }
+
+                        *image_hash = u32::from_le_bytes(hash);
+                    }
+
+                    *protocol = Protocol::negotiate(params[0],
+                                                    console_capabilities,
+                                                    u16::from_le_bytes([params[3], params[4]]) as usize,
+                                                    params[5] as usize,
+                                                    capabilities);
+
+                    let capabilities = protocol.capabilities.to_le_bytes();
+                    let packet_size = (protocol.packet_size as u16).to_le_bytes();
+
+                    let reply : [u8; 7] = [VERSION_REPLY,
+                                           protocol.version,
+                                           capabilities[0],
+                                           capabilities[1],
+                                           packet_size[0],
+                                           packet_size[1],
+                                           protocol.window_size as u8];
+
+                    (*port).write_all(&reply).expect("Could not write protocol version into the device");
+
+                    (*port).set_framed(protocol.supports(protocol::FRAMING));
+                },
+
+                // Announcements sent before the initial
+                // transmission was received are ignored.
+                link::HELLO => return TransferState::Handshake,
+
+                _ => return TransferState::FirstContact
+            }
+
+            // Old loaders might need some time between packets.
+            (*port).start_pacing(protocol.version == 0);
+
+            println!("Got response from the device");
+            println!("Using protocol version {}", protocol.version);
+
+            if protocol.is_lock_step() {
+                println!("Using lock-step transfers");
+            }
+            else
+            {
+                println!("Using windowed transfers: {} packets of {} bytes",
+                         protocol.window_size,
+                         protocol.packet_size);
+            }
+
+            println!("Capabilities: {}", protocol.describe_capabilities());
+
+            if protocol.supports(protocol::IMAGE_HASH) {
+                TransferState::CheckImage
+            }
+            else
+            {
+                TransferState::SendHeader
+            }
+        },
+        _ => TransferState::FirstContact
+    }
+}
+
+/// This function waits for the console to acknowledge the EXE header
+/// or size, or to finish cleaning RAM. If no reply is received, the
+/// header is sent again, while other replies are waited for again,
+/// since sending them again would only confuse the console. Any byte
+/// other than ACK is ignored, e.g.: noise on the line, so the reply
+/// is waited for again without sending anything.
+/// The transfer is aborted after too many retries.
+pub fn wait_ack_default(port : &mut Link,
+                        prev_state : TransferState,
+                        window : &mut Window,
+                        policy : &RetryPolicy) -> TransferState {
+    let mut buffer : [u8; 1] = [0];
+
+    match wait_ack(port, &mut buffer, policy.timeout(policy.ack, window.retries)) {
+        Ok(1) if buffer[0] == link::HELLO => console_reset(),
+        Ok(1) if buffer[0] == ACK => {
+            window.retries = 0;
+
+            match prev_state {
+                TransferState::SendHeader => TransferState::SendExeSize,
+                TransferState::SendDelta => TransferState::SendExeData,
+                TransferState::SendRaw => TransferState::SendExeData,
+                TransferState::SendExeSize => TransferState::CleaningRAM,
+                TransferState::CleaningRAM => TransferState::SendExeData,
+                TransferState::SendExeData => TransferState::SendExeData,
+                TransferState::SendFile => TransferState::SendFile,
+                _ => TransferState::Finished
+            }
+        },
+        Ok(1) => TransferState::WaitAck,
+        _ => {
+            window.retries += 1;
+
+            if !policy.allows(window.retries) {
+                println!("Error: the console stopped responding after {} retries", policy.max_retries);
+                return TransferState::Failed
+            }
+
+            println!("No reply from the console, retrying ({}/{})", window.retries, policy.max_retries);
+
+            match prev_state {
+                TransferState::SendHeader => prev_state,
+                _ => TransferState::WaitAck
+            }
+        }
+    }
+}
+
+/// This function compares the hash of the executable held in RAM
+/// by the console against the one to be sent. If they match, the
+/// console is told to run it with ACK, and only file requests are
+/// served. If it matches the last image uploaded instead, given as
+/// base, only changed blocks are sent. Otherwise, NAK is sent,
+/// followed by the executable.
+pub fn check_image(port : &mut Link, exe : &Executable, image_hash : u32, base : Option<&Image>) -> TransferState {
+    let holds_exe = image_hash != 0 && match exe.hash() {
+        Ok(hash) => hash == image_hash,
+        Err(e) => {
+            println!("{}. File path: {}", e, exe.path);
+            false
+        }
+    };
+
+    if holds_exe {
+        println!("The console already holds {}, skipping upload", exe.path);
+
+        (*port).send(link::REPLY, &[ACK]).expect("Could not write reply into the device");
+
+        TransferState::WaitFileRequest
+    }
+    else if image_hash != 0 && base.map(|b| b.hash) == Some(image_hash) {
+        TransferState::SendDelta
+    }
+    else
+    {
+        (*port).send(link::REPLY, &[NAK]).expect("Could not write reply into the device");
+
+        TransferState::SendHeader
+    }
+}
+
+/// This function sends the blocks of the executable which changed
+/// since base was uploaded, preceded by DELTA_REPLY and the size
+/// of the delta. The whole executable is sent instead, preceded by
+/// NAK, if it cannot be read or the delta is not any smaller.
+pub fn send_delta(port : &mut Link,
+                  exe : &Executable,
+                  base : Option<&Image>,
+                  exe_payload : &mut Payload,
+                  cache : &mut Cache,
+                  protocol : &Protocol) -> TransferState {
+    let delta = match (base, Image::load(exe)) {
+        (Some(base), Ok(image)) => Some((image.delta(base), image.data.len())),
+        (_, Err(e)) => {
+            println!("{}. File path: {}", e, exe.path);
+            None
+        },
+        _ => None
+    };
+
+    match delta {
+        Some((delta, size)) if !delta.is_empty() && delta.len() < size => {
+            println!("Sending {} bytes changed since the last upload", delta.len());
+
+            *exe_payload = cache.memory_payload(delta, protocol);
+
+            (*port).send(link::REPLY, &[DELTA_REPLY]).expect("Could not write reply into the device");
+
+            send_data_size(port, exe_payload, protocol).expect("Could not write delta size into the device");
+
+            TransferState::WaitAck
+        },
+        _ => {
+            (*port).send(link::REPLY, &[NAK]).expect("Could not write reply into the device");
+
+            TransferState::SendHeader
+        }
+    }
+}
+
+/// This function sends LOAD_COMMAND, followed by the size of the given
+/// raw binary, so the console writes it to the given address once
+/// the size is acknowledged.
+pub fn send_raw(port : &mut Link,
+                raw : &RawUpload,
+                raw_payload : &mut Payload,
+                cache : &mut Cache,
+                protocol : &Protocol) -> TransferState {
+    *raw_payload = match cache.payload(&raw.path, 0, 0, protocol) {
+        Ok(payload) if payload.original_size != 0 => payload,
+        Ok(_) => {
+            println!("{} is empty", raw.path);
+            return TransferState::Failed
+        },
+        Err(e) => {
+            println!("{}. File path: {}", e, raw.path);
+            return TransferState::Failed
+        }
+    };
+
+    println!("Writing {} to {:#X}", raw.path, raw.address);
+
+    let mut command : Vec<u8> = vec![LOAD_COMMAND];
+
+    command.extend_from_slice(&raw.address.to_le_bytes());
+
+    (*port).send(link::COMMAND, &command).expect("Could not write command into the device");
+
+    send_data_size(port, raw_payload, protocol).expect("Could not write binary size into the device");
+
+    TransferState::WaitAck
+}
+
+/// This function calculates the CRC-32 of the file found at path,
+/// read in chunks so it is never fully held in memory. Returns the
+/// checksum and the size of the file.
+fn file_crc32(path : &str) -> std::io::Result<(u32, usize)> {
+    use std::io::Read;
+
+    const CHUNK_SIZE : usize = 64 * 1024;
+
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer : Vec<u8> = vec![0; CHUNK_SIZE];
+    let mut checksum = 0;
+    let mut size = 0;
+
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok((checksum, size)),
+            n => {
+                checksum = crc::crc32_update(checksum, &buffer[..n]);
+                size += n;
+            }
+        }
+    }
+}
+
+/// This function asks the console for the CRC-32 of the RAM range
+/// where the given raw binary was written, and compares it against
+/// the CRC-32 of the binary. The transfer is aborted if they differ.
+pub fn verify_raw(port : &mut Link, raw : &RawUpload, policy : &RetryPolicy) -> TransferState {
+    let (expected, size) = match file_crc32(&raw.path) {
+        Ok(result) => result,
+        Err(e) => {
+            println!("{}. File path: {}", e, raw.path);
+            return TransferState::Failed
+        }
+    };
+
+    let mut command : Vec<u8> = vec![VERIFY_COMMAND];
+
+    command.extend_from_slice(&raw.address.to_le_bytes());
+    command.extend_from_slice(&(size as u32).to_le_bytes());
+
+    (*port).send(link::COMMAND, &command).expect("Could not write command into the device");
+
+    (*port).set_timeout(policy.ack).expect("Could not adjust timeout");
+
+    let mut checksum : [u8; 4] = [0; 4];
+
+    match read_data(port, &mut checksum) {
+        Ok(_) if u32::from_le_bytes(checksum) == expected => {
+            println!("Verified {} bytes at {:#X}", size, raw.address);
+            TransferState::Jump
+        },
+        Ok(_) => {
+            println!("Error: data written at {:#X} does not match {}", raw.address, raw.path);
+            TransferState::Failed
+        },
+        Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionReset => console_reset(),
+        Err(e) => {
+            println!("Could not read checksum from the device: {}", e);
+            TransferState::Failed
+        }
+    }
+}
+
+/// This function sends JUMP_COMMAND, so the console jumps to the
+/// given address once registers have been set. File requests sent
+/// by the code being run are served afterwards.
+pub fn jump(port : &mut Link, jump : &Jump) -> TransferState {
+    println!("Jumping to {:#X}", jump.address);
+
+    let mut command : Vec<u8> = vec![JUMP_COMMAND];
+
+    command.extend_from_slice(&jump.address.to_le_bytes());
+    command.push(jump.mask);
+
+    for (index, value) in jump.values.iter().enumerate() {
+        if jump.mask & (1 << index) != 0 {
+            command.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    (*port).send(link::COMMAND, &command).expect("Could not write command into the device");
+
+    TransferState::WaitFileRequest
+}
+
+pub fn send_header(port : &mut Link, exe: &Executable) -> TransferState {
+    use std::io::Write;
+
+    const HEADER_SIZE : usize = 32;
+    for packet in (0..HEADER_SIZE).step_by(protocol::LOCK_STEP_PACKET_SIZE) {
+        match exe.header.get(packet..(packet + protocol::LOCK_STEP_PACKET_SIZE)) {
+            None => return TransferState::Finished,
+            Some(chunk) => {
+                (*port).write_all(chunk).expect("Could not write EXE header into the device");
+            }
+        }
+    }
+
+    TransferState::WaitAck
+}
+
+/// This function waits for the console to acknowledge the last
+/// window of data packets. When checksums are enabled, the console
+/// can reply with NAK so the window is sent again starting from the
+/// first corrupted packet, as well as if no reply is received.
+/// Otherwise, the acknowledgement is waited for again.
+/// The transfer is aborted after too many retries.
+pub fn wait_data_ack(port : &mut Link,
+                     prev_state : TransferState,
+                     sent_bytes : &mut usize,
+                     window : &mut Window,
+                     protocol : &Protocol,
+                     policy : &RetryPolicy) -> TransferState {
+    let mut buffer : [u8; 1] = [0];
+
+    let rewind_offset = match wait_ack(port, &mut buffer, policy.timeout(policy.ack, window.retries)) {
+        Ok(1) if buffer[0] == link::HELLO => return console_reset(),
+
+        Ok(1) if buffer[0] == ACK => {
+            window.retries = 0;
+            return prev_state
+        },
+
+        Ok(1) if buffer[0] == NAK && protocol.supports(protocol::CHECKSUMS) => {
+            use std::io::Read;
+
+            let mut index : [u8; 1] = [0];
+
+            match (*port).read_exact(&mut index) {
+                Ok(_) if index[0] == NAK_WHOLE_IMAGE => {
+                    // Acknowledgements received while sending the
+                    // image again must not reset this counter.
+                    window.image_retries += 1;
+                    0
+                },
+                Ok(_) => {
+                    let offset = window.offset + (index[0] as usize * protocol.packet_size);
+
+                    // Invalid indexes make the whole window to be sent again.
+                    if offset < *sent_bytes {
+                        offset
+                    }
+                    else
+                    {
+                        window.offset
+                    }
+                },
+                Err(_) => window.offset
+            }
+        },
+
+        _ if protocol.supports(protocol::CHECKSUMS) => window.offset,
+        _ => {
+            window.retries += 1;
+
+            if !policy.allows(window.retries) {
+                println!("\nError: the console stopped responding after {} retries", policy.max_retries);
+                return TransferState::Failed
+            }
+
+            println!("\nNo reply from the console, retrying ({}/{})", window.retries, policy.max_retries);
+
+            return TransferState::WaitAck
+        }
+    };
+
+    window.retries += 1;
+
+    if !policy.allows(window.retries) || !policy.allows(window.image_retries) {
+        println!("\nError: {} at offset {:#X} could not be sent after {} retries",
+                 match prev_state {
+                     TransferState::SendExeData => "EXE data",
+                     _ => "File data"
+                 },
+                 rewind_offset,
+                 policy.max_retries);
+
+        return TransferState::Failed
+    }
+
+    println!("\nResending data from offset {:#X}", rewind_offset);
+
+    *sent_bytes = rewind_offset;
+
+    prev_state
+}
+
+const EXE_DATA_OFFSET : usize = 2048;
+
+/// This function writes the original size of the given payload.
+/// If compression is enabled, the size of the compressed data is
+/// also written, which equals the original size when the data could
+/// not be compressed.
+///
+/// If checksums are enabled, the CRC-32 of the original data follows
+/// the last data packet, so the size is sent before reading any data.
+/// Since empty payloads have no packets, it follows the size instead.
+fn send_data_size(port : &mut Link, payload : &Payload, protocol : &Protocol) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut header : Vec<u8> = (payload.original_size as u32).to_le_bytes().to_vec();
+
+    if protocol.supports(protocol::COMPRESSION) {
+        header.extend_from_slice(&(payload.size as u32).to_le_bytes());
+    }
+
+    if protocol.supports(protocol::CHECKSUMS) && payload.size == 0 {
+        header.extend_from_slice(&payload.checksum.to_le_bytes());
+    }
+
+    (*port).write_all(&header)
+}
+
+pub fn send_exe_size(port: &mut Link,
+                     exe: &Executable,
+                     exe_payload: &mut Payload,
+                     cache: &mut Cache,
+                     protocol: &Protocol) -> TransferState {
+    *exe_payload = match cache.payload(&exe.path, EXE_DATA_OFFSET as u64, 0, protocol) {
+        Ok(payload) => payload,
+        Err(e) => {
+            println!("{}. File path: {}", e, exe.path);
+            return TransferState::Finished
+        }
+    };
+
+    if exe_payload.original_size != 0 {
+        send_data_size(port, exe_payload, protocol).expect("Could not write EXE size into the device");
+
+        TransferState::WaitAck
+    }
+    else
+    {
+        println!("PSX-EXE is too small");
+        TransferState::Finished
+    }
+}
+
+/// This function writes as many packets as allowed by the
+/// negotiated window, taken from data starting at offset.
+/// The last packet is shorter if not enough data is left, and
+/// each packet is followed by its CRC-16 if checksums are enabled.
+/// In that case, the last packet of the payload is also followed by
+/// the CRC-32 of the original data, every time it is sent.
+/// Returns the number of bytes that have been written, or an error
+/// if data could not be read, e.g.: the file was truncated.
+fn send_window(port : &mut Link,
+               payload : &mut Payload,
+               offset : usize,
+               window : &mut Window,
+               protocol : &Protocol) -> std::io::Result<usize> {
+    use std::io::Write;
+
+    let data = payload.read(offset, protocol.burst_size())?;
+
+    window.offset = offset;
+
+    for chunk in data.chunks(protocol.packet_size) {
+        // Each packet is written at once, so it
+        // travels inside a single frame if framed.
+        let mut packet : Vec<u8> = chunk.to_vec();
+
+        if protocol.supports(protocol::CHECKSUMS) {
+            packet.extend_from_slice(&crc::crc16(chunk).to_le_bytes());
+        }
+
+        (*port).write_all(&packet).expect("Could not write data packet into the device");
+    }
+
+    let written = data.len();
+
+    if protocol.supports(protocol::CHECKSUMS) && offset + written == payload.size {
+        (*port).write_all(&payload.checksum.to_le_bytes()).expect("Could not write checksum into the device");
+    }
+
+    Ok(written)
+}
+
+/// Prints transfer progress every time a multiple
+/// of 32 bytes is reached or crossed.
+fn show_progress(action : &str, transferred : usize, last : usize, total : usize) {
+    const PROGRESS_STEP : usize = 32;
+
+    if transferred / PROGRESS_STEP != (transferred - last) / PROGRESS_STEP {
+        print!("\r{} {:?}/{:?} bytes...", action, transferred, total);
+    }
+}
+
+pub fn send_exe_data(port: &mut Link,
+                     sent_bytes: &mut usize,
+                     exe_payload: &mut Payload,
+                     window: &mut Window,
+                     protocol: &Protocol) -> TransferState {
+    if *sent_bytes < exe_payload.size {
+        let written = match send_window(port, exe_payload, *sent_bytes, window, protocol) {
+            Ok(written) => written,
+            Err(e) => {
+                println!("\nError: EXE data at offset {:#X} could not be read: {}", *sent_bytes, e);
+                return TransferState::Failed
+            }
+        };
+
+        *sent_bytes += written;
+
+        show_progress("Sent", *sent_bytes, written, exe_payload.size);
+
+        TransferState::WaitAck
+    }
+    else
+    {
+        println!("Finished");
+
+        exe_payload.report();
+
+        // Reset number of sent bytes.
+        *sent_bytes = 0;
+        *window = Window::new();
+        TransferState::WaitFileRequest
+    }
+}
+
+/// This function waits for a request from the device and stores it
+/// on requested_file. Requests might be received across several
+/// reads, or several of them in a single read, so the framer keeps
+/// any partial or pending request between calls. If no valid request
+/// is received, this state is re-entered cyclically.
+pub fn wait_file_request(port : &mut Link,
+                         framer : &mut RequestFramer,
+                         requested_file : &mut String,
+                         policy : &RetryPolicy) -> TransferState {
+    if let Some(request) = framer.next_request() {
+        return get_file_name(request, requested_file)
+    }
+
+    if (*port).is_framed() {
+        (*port).set_timeout(policy.request).expect("Could not adjust timeout");
+
+        // Requests travel inside their own frames, so the
+        // framer is not needed. Debug text is returned as
+        // well, so the caller can follow the output.
+        return match (*port).receive_any() {
+            Ok((link::REPLY, ref data)) if data[..] == [link::HELLO] => console_reset(),
+            Ok((link::REQUEST, request)) => {
+                get_file_name(String::from_utf8(request).map_err(|e| FrameError::InvalidText(e.into_bytes())),
+                              requested_file)
+            },
+            _ => TransferState::WaitFileRequest
+        }
+    }
+
+    let mut buffer : [u8; 128] = [0; 128];
+
+    (*port).set_timeout(policy.request).expect("Could not adjust timeout");
+
+    // Debug text might be received at any time, but it is
+    // taken out by the link, so it is never mistaken for a
+    // request. The caller can follow the output as well.
+    match (*port).read_any(&mut buffer) {
+        Err(_) | Ok(0) => TransferState::WaitFileRequest,
+        Ok(n) => {
+            framer.push(&buffer[..n]);
+
+            if framer.take_hello() {
+                return console_reset()
+            }
+
+            match framer.next_request() {
+                Some(request) => get_file_name(request, requested_file),
+                None => TransferState::WaitFileRequest
+            }
+        }
+    }
+}
+
+fn get_file_name(request : Result<String, FrameError>, requested_file : &mut String) -> TransferState {
+    match request {
+        Err(e) => {
+            println!("Malformed request: {}", e);
+            TransferState::WaitFileRequest
+        },
+        Ok(request) => {
+            println!("Requested file: {}", request);
+
+            *requested_file = request;
+
+            if WRITE_REQUEST_RX.is_match(requested_file) {
+                TransferState::ReceiveFile
+            }
+            else if requested_file.starts_with("pc") {
+                TransferState::HostFileRequest
+            }
+            else if requested_file.starts_with("list:") || requested_file.starts_with("stat:") {
+                TransferState::DirectoryRequest
+            }
+            else
+            {
+                TransferState::SendFile
+            }
+        }
+    }
+}
+
+lazy_static! {
+    /// File requests are made of a file path, optionally followed
+    /// by an offset and a length in bytes, all in decimal, e.g.:
+    /// "cdrom:\DATA\LEVEL1.ARC;1:2048:4096". A length of 0
+    /// means reading from the offset until the end of file.
+    /// Invalid ranges, including offsets past the end of file,
+    /// are answered with ERROR_REPLY.
+    static ref FILE_REQUEST_RX: regex::Regex =
+        regex::Regex::new(r"^cdrom:\\(.+);1(?::(\d+):(\d+))?$").expect("Could not compile regex");
+
+    /// Write requests are made of the path where the file will
+    /// be written, relative to the output folder, followed by
+    /// its size in bytes, in decimal, e.g.: "write:\LOGS\RUN.TXT:512".
+    static ref WRITE_REQUEST_RX: regex::Regex =
+        regex::Regex::new(r"^write:\\(.+):(\d+)$").expect("Could not compile regex");
+}
+
+/// This function builds the path to a file inside folder, given
+/// the path requested by the console, where both '\\' and '/'
+/// are accepted as separators. Paths that would escape folder,
+/// e.g. by using "..", as well as drive letters, are rejected.
+/// An empty path refers to folder itself.
+fn host_path(folder : &str, requested : &str) -> Option<std::path::PathBuf> {
+    let mut path = std::path::PathBuf::from(folder);
+
+    for component in requested.split(['\\', '/']) {
+        match component {
+            "" | "." => {},
+            ".." => return None,
+            c if c.contains(':') => return None,
+            c => path.push(c)
+        }
+    }
+
+    Some(path)
+}
+
+/// This function parses the offset and length of a ranged file
+/// read. Returns None if either of them does not fit in 64 bits,
+/// or if the range would end past the largest possible offset.
+fn parse_range(offset : &str, length : &str) -> Option<(u64, u64)> {
+    let offset : u64 = offset.parse().ok()?;
+    let length : u64 = length.parse().ok()?;
+
+    offset.checked_add(length).map(|_| (offset, length))
+}
+
+pub fn send_file(port : &mut Link,
+                 folder: &str,
+                 sent_bytes: &mut usize,
+                 file : &mut FileTransfer,
+                 cache : &mut Cache,
+                 window : &mut Window,
+                 protocol : &Protocol) -> TransferState {
+    match file.payload {
+        None => {
+            match FILE_REQUEST_RX.captures(&file.name) {
+                None => {
+                    println!("{} is not a valid file path", file.name);
+                    *file = FileTransfer::new();
+                    TransferState::WaitFileRequest
+                },
+                Some(s) => {
+                    match s.get(1) {
+                        None => {
+                            println!("Internal error");
+                            TransferState::Finished
+                        },
+                        Some(s_) => {
+                            let absolute_path = match host_path(folder, s_.as_str()) {
+                                Some(path) => path.to_string_lossy().into_owned(),
+                                None => {
+                                    println!("{} is not a valid file path", file.name);
+                                    *file = FileTransfer::new();
+                                    return TransferState::WaitFileRequest
+                                }
+                            };
+
+                            println!("Absolute file path: {}", absolute_path);
+
+                            // Whole file is read unless a range is given.
+                            let (offset, length) = match (s.get(2), s.get(3)) {
+                                (Some(o), Some(l)) => match parse_range(o.as_str(), l.as_str()) {
+                                    Some(range) => range,
+                                    None => {
+                                        println!("Invalid range {}:{}. File path: {}", o.as_str(), l.as_str(), absolute_path);
+                                        (*port).send(link::REPLY, &[ERROR_REPLY]).expect("Could not write error into the device");
+                                        *file = FileTransfer::new();
+                                        return TransferState::WaitFileRequest
+                                    }
+                                },
+                                _ => (0, 0)
+                            };
+
+                            if offset != 0 || length != 0 {
+                                println!("Requested range: {} bytes from offset {}", length, offset);
+                            }
+
+                            let payload = match cache.payload(&absolute_path, offset, length, protocol) {
+                                Ok(payload) => payload,
+                                Err(e) => {
+                                    // Missing or unreadable files, as well as offsets
+                                    // past the end of file, are answered with an error
+                                    // so the console does not wait for the file size.
+                                    println!("{}. File path: {}", e, absolute_path);
+                                    (*port).send(link::REPLY, &[ERROR_REPLY]).expect("Could not write error into the device");
+                                    *file = FileTransfer::new();
+                                    return TransferState::WaitFileRequest
+                                }
+                            };
+
+                            println!("File size: {:?} bytes", payload.original_size);
+
+                            send_data_size(port, &payload, protocol).expect("Could not write file size into the device");
+
+                            file.payload = Some(payload);
+
+                            TransferState::WaitAck
+                        }
+                    }
+                }
+            }
+        },
+        Some(ref mut payload) => {
+            let size = payload.size;
+
+            if *sent_bytes < size {
+                let written = match send_window(port, payload, *sent_bytes, window, protocol) {
+                    Ok(written) => written,
+                    Err(e) => {
+                        println!("\nError: file data at offset {:#X} could not be read: {}", *sent_bytes, e);
+                        return TransferState::Failed
+                    }
+                };
+
+                *sent_bytes += written;
+
+                show_progress("Sent", *sent_bytes, written, size);
+
+                TransferState::WaitAck
+            }
+            else
+            {
+                println!("\r{} has been completely sent.", file.name);
+
+                payload.report();
+
+                // Reset information.
+                *sent_bytes = 0;
+                *file = FileTransfer::new();
+                *window = Window::new();
+
+                TransferState::WaitFileRequest
+            }
+        }
+    }
+}
+
+/// This function reads a window of packets sent by the console
+/// and writes them into the uploaded file. If checksums are enabled,
+/// packets following a corrupted or missing one are read but
+/// discarded. Returns the index of the first packet that must
+/// be sent again, if any, or an error of kind ConnectionReset
+/// if the console has been reset in the meantime.
+fn receive_window(port : &mut Link,
+                  upload : &mut Upload,
+                  protocol : &Protocol,
+                  timeout : std::time::Duration) -> std::io::Result<Option<usize>> {
+    use std::io::{ErrorKind, Write};
+
+    let end = std::cmp::min(upload.received + protocol.burst_size(), upload.size);
+    let mut offset = upload.received;
+    let mut first_bad : Option<usize> = None;
+    let mut index = 0;
+
+    (*port).set_timeout(timeout).expect("Could not adjust timeout");
+
+    while offset < end {
+        let mut packet : Vec<u8> = vec![0; std::cmp::min(protocol.packet_size, end - offset)];
+
+        match read_data(port, &mut packet) {
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => return Err(e),
+            // Nothing else is expected to arrive.
+            Err(_) => return Ok(Some(first_bad.unwrap_or(index))),
+            Ok(_) => {}
+        }
+
+        if protocol.supports(protocol::CHECKSUMS) {
+            let mut checksum : [u8; 2] = [0; 2];
+
+            match read_data(port, &mut checksum) {
+                Err(e) if e.kind() == ErrorKind::ConnectionReset => return Err(e),
+                Err(_) => return Ok(Some(first_bad.unwrap_or(index))),
+                Ok(_) => {}
+            }
+
+            if first_bad.is_none() && u16::from_le_bytes(checksum) != crc::crc16(&packet) {
+                first_bad = Some(index);
+            }
+        }
+
+        if first_bad.is_none() {
+            upload.writer.write_all(&packet).expect("Could not write received data into file");
+            upload.received += packet.len();
+        }
+
+        offset += packet.len();
+        index += 1;
+    }
+
+    Ok(first_bad)
+}
+
+/// This function opens the file requested to be written by
+/// the console under the output folder, creating any missing
+/// directories. Data is written into a temporary file next to
+/// it, so an existing file is only replaced once the upload
+/// succeeds. Returns None if the request cannot be served.
+fn open_upload(output_folder : Option<&str>, request : &str) -> Option<Upload> {
+    use std::{fs, io};
+
+    let folder = match output_folder {
+        Some(folder) => folder,
+        None => {
+            println!("Write requests are disabled since no output folder was given");
+            return None
+        }
+    };
+
+    let (path, size) = match WRITE_REQUEST_RX.captures(request) {
+        Some(c) => {
+            match (host_path(folder, &c[1]), c[2].parse::<usize>()) {
+                (Some(path), Ok(size)) => (path, size),
+                _ => {
+                    println!("{} is not a valid write request", request);
+                    return None
+                }
+            }
+        },
+        None => {
+            println!("{} is not a valid write request", request);
+            return None
+        }
+    };
+
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+
+    temp_name.push(UPLOAD_TEMP_SUFFIX);
+
+    let temp_path = path.with_file_name(temp_name);
+
+    let file = match path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(())
+    }.and_then(|_| fs::File::create(&temp_path));
+
+    match file {
+        Ok(file) => {
+            Some(Upload {
+                writer : io::BufWriter::new(file),
+                path,
+                size,
+                received : 0,
+                temp_path : Some(temp_path)
+            })
+        },
+        Err(e) => {
+            println!("{}. File path: {}", e, path.display());
+            None
+        }
+    }
+}
+
+/// This function serves write requests, where the console sends
+/// a file to be written under the output folder. If the request
+/// is accepted, the console sends the file contents in windows of
+/// packets, just like the host does for file reads, and each window
+/// is acknowledged by the host. When checksums are enabled, the host
+/// replies with NAK so the window is sent again starting from the
+/// first corrupted packet.
+pub fn receive_file(port : &mut Link,
+                    output_folder : Option<&str>,
+                    file : &mut FileTransfer,
+                    window : &mut Window,
+                    protocol : &Protocol,
+                    policy : &RetryPolicy) -> TransferState {
+    use std::{fs, io::Write};
+
+    let finished = match file.upload {
+        None => {
+            match open_upload(output_folder, &file.name) {
+                Some(upload) => {
+                    println!("Absolute file path: {}", upload.path.display());
+
+                    file.upload = Some(upload);
 
-pub fn first_contact(port : &mut serial::SystemPort) -> TransferState {
-    const INITIAL_TRANSMISSION: u8 = 99u8;
-    use std::io::Write;
+                    (*port).send(link::REPLY, &[ACK]).expect("Could not write acknowledgement into the device");
 
-    match (*port).write(&vec![INITIAL_TRANSMISSION]) {
-        Err(_) => TransferState::FirstContact,
-        Ok(b) => {
-            if b == 1 {
-                TransferState::WaitAck
-            }
-            else
-            {
-                TransferState::FirstContact
+                    return TransferState::ReceiveFile
+                },
+                None => {
+                    (*port).send(link::REPLY, &[ERROR_REPLY]).expect("Could not write error into the device");
+
+                    false
+                }
             }
-        }
-    }
-}
+        },
 
-fn wait_ack(port : &mut serial::SystemPort, buffer : &mut [u8]) -> Result<usize, std::io::Error> {
-    // For some reason, this trait has to be imported,
-    // but shouldn't serial::SerialPort be already doing this?
-    use std::io::Read;
+        Some(ref mut upload) => {
+            if upload.received < upload.size {
+                let received = upload.received;
 
-    use serial::SerialPort;
+                match receive_window(port, upload, protocol, policy.timeout(policy.data, window.retries)) {
+                    Err(_) => {
+                        if let Some(ref temp_path) = upload.temp_path {
+                            fs::remove_file(temp_path).ok();
+                        }
 
-    const TIMEOUT_SECONDS : u64 = 2;
+                        return console_reset()
+                    },
 
-    (*port).set_timeout(std::time::Duration::from_secs(TIMEOUT_SECONDS)).expect("Could not adjust timeout");
+                    Ok(None) => {
+                        window.retries = 0;
 
-    (*port).read(buffer)
-}
+                        (*port).send(link::REPLY, &[ACK]).expect("Could not write acknowledgement into the device");
 
-pub fn wait_ack_default(port : &mut serial::SystemPort, prev_state: TransferState) -> TransferState {
-    let mut buffer : [u8; 1] = [0];
+                        show_progress("Received", upload.received, upload.received - received, upload.size);
 
-    match wait_ack(port, &mut buffer) {
-        Ok(1) => {
-            if *(buffer.get(0).unwrap()) == 'b' as u8 {
-                match prev_state {
-                    TransferState::FirstContact => {
-                        println!("Got response from the device");
-                        TransferState::SendHeader
+                        return TransferState::ReceiveFile
                     },
-                    TransferState::SendHeader => TransferState::SendExeSize,
-                    TransferState::SendExeSize => TransferState::CleaningRAM,
-                    TransferState::CleaningRAM => TransferState::SendExeData,
-                    TransferState::SendExeData => TransferState::SendExeData,
-                    TransferState::SendFile => TransferState::SendFile,
-                    _ => TransferState::Finished
+
+                    Ok(Some(index)) => {
+                        window.retries += 1;
+
+                        if protocol.supports(protocol::CHECKSUMS) && policy.allows(window.retries) {
+                            println!("\nRequesting data again from offset {:#X}", upload.received);
+
+                            (*port).send(link::REPLY, &[NAK, index as u8]).expect("Could not write NAK into the device");
+
+                            return TransferState::ReceiveFile
+                        }
+
+                        println!("\nError: file data at offset {:#X} could not be received", upload.received);
+
+                        (*port).send(link::REPLY, &[ERROR_REPLY]).expect("Could not write error into the device");
+
+                        if let Some(ref temp_path) = upload.temp_path {
+                            fs::remove_file(temp_path).ok();
+                        }
+
+                        false
+                    }
                 }
             }
             else
             {
-                prev_state
-            }
-        },
-        _ => {
-            match prev_state {
-                TransferState::SendExeSize => TransferState::CleaningRAM,
-                _ => prev_state
-            }
-        }
-    }
-}
-
-const PACKET_SIZE : usize = 8 as usize;
-
-pub fn send_header(port : &mut serial::SystemPort, exe_data: &Vec<u8>) -> TransferState {
+                upload.writer.flush().expect("Could not write received data into file");
 
-    const HEADER_SIZE : usize = 32 as usize;
-    for packet in (0..HEADER_SIZE).step_by(PACKET_SIZE) {
-        match exe_data.get(packet..(packet + PACKET_SIZE)) {
-            None => return TransferState::Finished,
-            Some(chunk) => {
-                use std::{thread, time};
+                let renamed = match upload.temp_path {
+                    Some(ref temp_path) => fs::rename(temp_path, &upload.path).map_err(|e| {
+                        println!("\n{}. File path: {}", e, upload.path.display());
+                        fs::remove_file(temp_path).ok();
+                    }),
+                    None => Ok(())
+                };
 
-                thread::sleep(time::Duration::from_millis(100));
+                if renamed.is_ok() {
+                    println!("\r{} has been completely received.", upload.path.display());
+                }
 
-                use std::io::Write;
-                (*port).write(&chunk).expect("Could not write EXE header into the device");
+                renamed.is_ok()
             }
         }
-    }
+    };
 
-    TransferState::WaitAck
-}
+    if !finished {
+        println!("Write request {} could not be served", file.name);
+    }
 
-const EXE_DATA_OFFSET : usize = 2048 as usize;
+    // Reset information.
+    *file = FileTransfer::new();
+    *window = Window::new();
 
-pub fn send_exe_size(port: &mut serial::SystemPort, exe_data: &Vec<u8>) -> TransferState {
-    if exe_data.len() > EXE_DATA_OFFSET {
-        let exe_size = exe_data.len() - EXE_DATA_OFFSET;
+    TransferState::WaitFileRequest
+}
 
-        use std::io::Write;
+/// This function opens a file for a PCdrv request, given the
+/// folder it must be found in, if any. Returns the new handle.
+fn open_host_file(handles : &mut HandleTable,
+                  folder : Option<&str>,
+                  path : &str,
+                  mode : u8) -> std::io::Result<u32> {
+    use std::io;
 
-        let exe_size_vec : [u8; 4] = [(exe_size & 0xFF) as u8,
-                                      ((exe_size & 0xFF00) >> 8) as u8,
-                                      ((exe_size & 0xFF0000) >> 16) as u8,
-                                      ((exe_size & 0xFF000000) >> 24) as u8];
-        (*port).write(&exe_size_vec).expect("Could not write EXE size into the device");
+    match folder.and_then(|folder| host_path(folder, path)) {
+        Some(path) => {
+            println!("Absolute file path: {}", path.display());
 
-        TransferState::WaitAck
-    }
-    else
-    {
-        println!("PSX-EXE is too small");
-        TransferState::Finished
+            handles.open(path, mode).map(|handle| handle as u32)
+        },
+        None => Err(io::Error::from(io::ErrorKind::PermissionDenied))
     }
 }
 
-pub fn send_exe_data(port: &mut serial::SystemPort, sent_bytes: &mut usize, exe_data: &Vec<u8>) -> TransferState {
-    let exe_size = exe_data.len();
+/// This function serves PCdrv requests, which allow the console
+/// to use files on the host through handles. Files are opened under
+/// the CD-ROM folder for reading, and under the output folder for
+/// writing. All requests are answered with ACK, or ERROR_REPLY if
+/// they cannot be served. After ACK:
+/// - Open and create requests send the new handle, and seek requests
+///   send the new position, both as 32-bit little-endian words.
+/// - Data read from a handle is sent just like file reads.
+/// - Data written to a handle is received as for write requests.
+pub fn serve_host_file(port : &mut Link,
+                       folder : &str,
+                       output_folder : Option<&str>,
+                       file : &mut FileTransfer,
+                       handles : &mut HandleTable,
+                       cache : &mut Cache,
+                       protocol : &Protocol) -> TransferState {
+    use std::io::{self, Read};
+    use pcdrv::Request;
 
-    let total_sent_bytes = *sent_bytes + EXE_DATA_OFFSET;
+    let not_open = || io::Error::other("Handle is not open");
 
-    if total_sent_bytes < exe_size {
-        match exe_data.get(total_sent_bytes..(total_sent_bytes + PACKET_SIZE)) {
-            None => return TransferState::Finished,
-            Some(chunk) => {
-                use std::io::Write;
-                (*port).write(&chunk).expect("Could not write EXE header into the device");
+    let reply : io::Result<Option<u32>> = match Request::parse(&file.name) {
+        None => Err(io::Error::from(io::ErrorKind::InvalidInput)),
 
-                *sent_bytes += PACKET_SIZE;
+        Some(Request::Open { path, mode : 0 }) => open_host_file(handles, Some(folder), &path, 0).map(Some),
 
-                if *sent_bytes % 32 == 0 {
-                    print!("\rSent {:?}/{:?} bytes...", *sent_bytes, exe_size - EXE_DATA_OFFSET);
-                }
+        Some(Request::Open { path, mode }) => open_host_file(handles, output_folder, &path, mode).map(Some),
+
+        Some(Request::Create { path }) => open_host_file(handles, output_folder, &path, 3).map(Some),
+
+        Some(Request::Seek { handle, offset, whence }) => {
+            handles.seek(handle, offset, whence).map(|position| Some(position as u32))
+        },
+
+        Some(Request::Close { handle }) => {
+            if handles.close(handle) {
+                Ok(None)
             }
-        }
+            else
+            {
+                Err(not_open())
+            }
+        },
 
-        TransferState::WaitAck
-    }
-    else
-    {
-        println!("Finished");
+        Some(Request::Read { handle, length }) => {
+            let mut data : Vec<u8> = Vec::new();
 
-        // Reset number of sent bytes.
-        *sent_bytes = 0;
-        TransferState::WaitFileRequest
-    }
-}
+            match handles.get(handle) {
+                Some(host_file) => {
+                    match (&host_file.file).take(length as u64).read_to_end(&mut data) {
+                        Ok(_) => {
+                            let payload = cache.memory_payload(data, protocol);
 
-/// This function waits for a file read request from the device and
-/// constructs a valid file name for it. If no valid data is provided,
-/// this state is re-entered cyclically.
-pub fn wait_file_request(port : &mut serial::SystemPort, requested_file : &mut String) -> TransferState {
-    // For some reason, this trait has to be imported,
-    // but shouldn't serial::SerialPort be already doing this?
-    use std::io::Read;
+                            (*port).send(link::REPLY, &[ACK]).expect("Could not write acknowledgement into the device");
 
-    use serial::SerialPort;
+                            send_data_size(port, &payload, protocol).expect("Could not write file size into the device");
 
-    const TIMEOUT_SECONDS : u64 = 5;
+                            file.payload = Some(payload);
 
-    let mut buffer : [u8; 128] = [0; 128];
+                            return TransferState::WaitAck
+                        },
+                        Err(e) => Err(e)
+                    }
+                },
+                None => Err(not_open())
+            }
+        },
 
-    (*port).set_timeout(std::time::Duration::from_secs(TIMEOUT_SECONDS)).expect("Could not adjust timeout");
+        Some(Request::Write { handle, length }) => {
+            match handles.get(handle) {
+                Some(host_file) => {
+                    match host_file.file.try_clone() {
+                        Ok(clone) => {
+                            file.upload = Some(Upload {
+                                writer : io::BufWriter::new(clone),
+                                path : host_file.path.clone(),
+                                size : length,
+                                received : 0,
+                                temp_path : None
+                            });
 
-    match (*port).read(&mut buffer) {
-        Err(_) | Ok(0) => TransferState::WaitFileRequest,
-        Ok(_) => get_file_name(&buffer.to_vec(), requested_file)
-    }
-}
+                            (*port).send(link::REPLY, &[ACK]).expect("Could not write acknowledgement into the device");
 
-fn get_file_name(buffer : &Vec<u8>, requested_file: &mut String) -> TransferState {
-    if requested_file.is_empty() {
-        // No valid header byte has been found yet.
-        match buffer.iter().position(|&c| c == '#' as u8) {
-            Some(pos) => {
-                let final_pos : usize =
-                    match buffer.iter().position(|&c| c == '@' as u8) {
-                        None => {
-                            println!("Terminator character could not be found");
-                            buffer.len() - 1
+                            return TransferState::ReceiveFile
                         },
-                        Some(l) => l
-                    };
-                requested_file.clone_from(&String::from_utf8(buffer[pos + 1..final_pos].to_vec()).unwrap());
+                        Err(e) => Err(e)
+                    }
+                },
+                None => Err(not_open())
             }
-            None => {},
         }
-    }
-    else
-    {
-        // No valid header byte has been found yet.
-        match buffer.iter().position(|&c| c == '@' as u8) {
-            Some(pos) => {
-                requested_file.push_str(&String::from_utf8(buffer[0..pos].to_vec()).unwrap());
+    };
+
+    match reply {
+        Ok(value) => {
+            let mut reply : Vec<u8> = vec![ACK];
+
+            if let Some(value) = value {
+                reply.extend_from_slice(&value.to_le_bytes());
             }
-            None => {},
+
+            (*port).send(link::REPLY, &reply).expect("Could not write reply into the device");
+        },
+        Err(e) => {
+            println!("{} could not be served: {}", file.name, e);
+
+            (*port).send(link::REPLY, &[ERROR_REPLY]).expect("Could not write error into the device");
         }
     }
 
-    if requested_file.ends_with(";1") {
-        println!("Requested file: {}", requested_file);
-        TransferState::SendFile
+    *file = FileTransfer::new();
+
+    TransferState::WaitFileRequest
+}
+
+/// Flag set on directory entries which are directories themselves.
+const ENTRY_IS_DIRECTORY : u8 = 1 << 0;
+
+/// This function encodes a directory entry as sent to the console:
+/// - Size in bytes (32-bit, little-endian), 0 for directories.
+/// - Flags (8-bit), see ENTRY_IS_DIRECTORY.
+/// - Name length (8-bit), followed by the name itself, if given.
+fn encode_entry(metadata : &std::fs::Metadata, name : Option<&str>, entry : &mut Vec<u8>) {
+    let (size, flags) = if metadata.is_dir() {
+        (0, ENTRY_IS_DIRECTORY)
     }
     else
     {
-        TransferState::WaitFileRequest
+        (metadata.len() as u32, 0)
+    };
+
+    entry.extend_from_slice(&size.to_le_bytes());
+    entry.push(flags);
+
+    if let Some(name) = name {
+        let name = &name.as_bytes()[..std::cmp::min(name.len(), u8::MAX as usize)];
+
+        entry.push(name.len() as u8);
+        entry.extend_from_slice(name);
     }
 }
 
-pub fn send_file(port : &mut serial::SystemPort,
-                 folder: &String,
-                 sent_bytes: &mut usize,
-                 requested_file: &mut String,
-                 file_data : &mut Vec<u8>,
-                 file_size : &mut Option<usize>) -> TransferState {
-    use std::fs;
-    use regex::Regex;
+/// This function returns all entries inside a directory, sorted
+/// by name and encoded as described on encode_entry().
+fn list_directory(path : &std::path::Path) -> std::io::Result<Vec<u8>> {
+    let mut entries : Vec<(String, std::fs::Metadata)> = Vec::new();
+
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+
+        entries.push((entry.file_name().to_string_lossy().into_owned(), entry.metadata()?));
+    }
+
+    entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut data : Vec<u8> = Vec::new();
+
+    for (name, metadata) in entries.iter() {
+        encode_entry(metadata, Some(name), &mut data);
+    }
+
+    Ok(data)
+}
+
+/// This function serves requests that allow the console to discover
+/// files available under the CD-ROM folder. Paths use the same syntax
+/// as file reads, without the ";1" suffix:
+/// - "list:\PATH" sends all entries inside a directory, just like
+///   file reads, after an ACK. Each entry includes its name.
+/// - "stat:\PATH" sends ACK followed by a single entry, without name.
+///
+/// See encode_entry() for the format used by each entry.
+/// ERROR_REPLY is sent if the path cannot be found.
+pub fn serve_directory_request(port : &mut Link,
+                               folder : &str,
+                               file : &mut FileTransfer,
+                               cache : &mut Cache,
+                               protocol : &Protocol) -> TransferState {
+    use std::{fs, io};
 
     lazy_static! {
-        static ref RX: Regex = Regex::new(r"^cdrom:\\(.+);1$").expect("Could not compile regex");
+        static ref RX: regex::Regex = regex::Regex::new(r"^(list|stat):\\(.*)$").expect("Could not compile regex");
     }
 
-    match *file_size {
-        None => {
-            match RX.captures(&requested_file) {
-                None => {
-                    println!("{} is not a valid file path", requested_file);
-                    TransferState::WaitFileRequest
-                },
-                Some(s) => {
-                    match s.get(1) {
-                        None => {
-                            println!("Internal error");
-                            TransferState::Finished
-                        },
-                        Some(s_) => {
-                            let mut path = String::from(s_.as_str()).replace('\\', "/");
+    let request = RX.captures(&file.name).and_then(|c| {
+        host_path(folder, &c[2]).map(|path| (&c[1] == "list", path))
+    });
 
-                            let absolute_path = format!("{}/{}", folder, path);
+    let result : io::Result<TransferState> = match request {
+        Some((true, path)) => {
+            list_directory(&path).map(|data| {
+                let payload = cache.memory_payload(data, protocol);
 
-                            println!("Absolute file path: {}", absolute_path);
+                (*port).send(link::REPLY, &[ACK]).expect("Could not write acknowledgement into the device");
 
-                            *file_data = fs::read(&absolute_path).unwrap();
+                send_data_size(port, &payload, protocol).expect("Could not write list size into the device");
 
-                            *file_size = Some(file_data.len());
+                file.payload = Some(payload);
+
+                TransferState::WaitAck
+            })
+        },
 
-                            let size = (*file_size).unwrap();
+        Some((false, path)) => {
+            fs::metadata(&path).map(|metadata| {
+                let mut entry : Vec<u8> = vec![ACK];
 
-                            println!("File size: {:?} bytes", size);
+                encode_entry(&metadata, None, &mut entry);
 
-                            let file_size_vec : [u8; 4] = [(size & 0xFF) as u8,
-                                                           ((size & 0xFF00) >> 8) as u8,
-                                                           ((size & 0xFF0000) >> 16) as u8,
-                                                           ((size & 0xFF000000) >> 24) as u8];
-                            use std::io::Write;
-                            (*port).write(&file_size_vec).expect("Could not write EXE size into the device");
+                (*port).send(link::REPLY, &entry).expect("Could not write file status into the device");
 
-                            return TransferState::WaitAck
-                        }
-                    }
-                }
-            }
+                TransferState::WaitFileRequest
+            })
         },
-        Some(size) => {
-            if *sent_bytes < size {
-                match file_data.get(*sent_bytes..(*sent_bytes + PACKET_SIZE)) {
-                    None => {
-                        match file_data.get(*sent_bytes..size) {
-                            None => TransferState::Finished,
-                            Some(chunk) => {
-                                use std::io::Write;
-                                (*port).write(&chunk).expect("Could not file data chunk into the device");
-
-                                *sent_bytes = size;
-
-                                if *sent_bytes % 32 == 0 {
-                                    print!("\rSent {:?}/{:?} bytes...", *sent_bytes, size);
-                                }
 
-                                TransferState::WaitAck
-                            }
-                        }
-                    }
-                    Some(chunk) => {
-                        use std::io::Write;
-                        (*port).write(&chunk).expect("Could not file data chunk into the device");
+        None => Err(io::Error::from(io::ErrorKind::InvalidInput))
+    };
 
-                        *sent_bytes += PACKET_SIZE;
+    match result {
+        Ok(TransferState::WaitAck) => TransferState::WaitAck,
+        Ok(state) => {
+            *file = FileTransfer::new();
+            state
+        },
+        Err(e) => {
+            println!("{} could not be served: {}", file.name, e);
 
-                        if *sent_bytes % 32 == 0 {
-                            print!("\rSent {:?}/{:?} bytes...", *sent_bytes, size);
-                        }
+            (*port).send(link::REPLY, &[ERROR_REPLY]).expect("Could not write error into the device");
 
-                        TransferState::WaitAck
-                    }
-                }
+            *file = FileTransfer::new();
+
+            TransferState::WaitFileRequest
+        }
+    }
+}
+
+/// This structure describes the executable
+/// to be sent to the console. Only its header
+/// is held in memory, while its body is read
+/// from disk as it is being sent.
+#[derive(Default)]
+pub struct Executable {
+    /// Path to the PSX-EXE file.
+    pub path : String,
+
+    /// PSX-EXE header.
+    pub header : Vec<u8>
+}
+
+impl Executable {
+    /// This function calculates the hash reported by the console
+    /// for this executable: the CRC-32 of the first 32 bytes of
+    /// the header, which are sent to the console, followed by the
+    /// executable data. The whole file has to be read, so it is
+    /// only calculated when the console reports a hash.
+    pub fn hash(&self) -> std::io::Result<u32> {
+        use std::{fs, io::{Read, Seek, SeekFrom}};
+
+        const HEADER_SIZE : usize = 32;
+
+        let mut file = fs::File::open(&self.path)?;
+        let mut buffer : Vec<u8> = vec![0; 65536];
+        let mut hash = crc::crc32(&self.header[..std::cmp::min(self.header.len(), HEADER_SIZE)]);
+
+        file.seek(SeekFrom::Start(EXE_DATA_OFFSET as u64))?;
+
+        loop {
+            match file.read(&mut buffer)? {
+                0 => return Ok(hash),
+                n => hash = crc::crc32_update(hash, &buffer[..n])
             }
-            else
-            {
-                println!("\r{} has been completely sent.", requested_file);
+        }
+    }
+}
 
-                // Reset information.
-                *sent_bytes = 0;
-                *file_size = None;
-                file_data.clear();
-                requested_file.clear();
+/// This structure holds a whole executable as held in RAM by
+/// the console: the first 32 bytes of its header, followed by
+/// its data. Offsets below 32 refer to the header, so the
+/// console must apply them there instead of into RAM.
+pub struct Image {
+    /// Hash of the image, as reported by the console.
+    pub hash : u32,
 
-                TransferState::WaitFileRequest
+    data : Vec<u8>
+}
+
+impl Image {
+    /// This function reads the image for the given executable.
+    pub fn load(exe : &Executable) -> std::io::Result<Image> {
+        use std::{fs, io::{Read, Seek, SeekFrom}};
+
+        const HEADER_SIZE : usize = 32;
+
+        let mut data : Vec<u8> = exe.header[..std::cmp::min(exe.header.len(), HEADER_SIZE)].to_vec();
+        let mut file = fs::File::open(&exe.path)?;
+
+        file.seek(SeekFrom::Start(EXE_DATA_OFFSET as u64))?;
+        file.read_to_end(&mut data)?;
+
+        Ok(Image {
+            hash : crc::crc32(&data),
+            data
+        })
+    }
+
+    /// This function builds a delta with the blocks of this
+    /// image which differ from base. Consecutive changed blocks
+    /// are merged. See DELTA_REPLY for further details.
+    fn delta(&self, base : &Image) -> Vec<u8> {
+        let mut delta : Vec<u8> = Vec::new();
+        let mut changed_from : Option<usize> = None;
+
+        for start in (0..self.data.len()).step_by(DELTA_BLOCK_SIZE) {
+            let end = std::cmp::min(start + DELTA_BLOCK_SIZE, self.data.len());
+            let changed = base.data.get(start..end) != Some(&self.data[start..end]);
+
+            match changed_from {
+                None if changed => changed_from = Some(start),
+                Some(from) if !changed => {
+                    Image::push_block(&mut delta, from, &self.data[from..start]);
+                    changed_from = None;
+                },
+                _ => {}
             }
         }
+
+        if let Some(from) = changed_from {
+            Image::push_block(&mut delta, from, &self.data[from..]);
+        }
+
+        delta
+    }
+
+    fn push_block(delta : &mut Vec<u8>, offset : usize, data : &[u8]) {
+        delta.extend_from_slice(&(offset as u32).to_le_bytes());
+        delta.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        delta.extend_from_slice(data);
     }
 }
 
-pub fn get_exe_data(folder: &String) -> Option<Vec<u8>> {
+pub fn get_exe_data(folder: &str) -> Option<Executable> {
     match get_exe_name(folder) {
         None => None,
         Some(exe_name) => {
             let exe_path = format!("{}/{}", folder, exe_name);
 
-            use std::fs;
+            use std::{fs, io::Read};
 
-            match fs::read(&exe_path) {
+            let mut header : Vec<u8> = Vec::new();
+
+            let result = fs::File::open(&exe_path)
+                                 .and_then(|f| f.take(EXE_DATA_OFFSET as u64).read_to_end(&mut header));
+
+            match result {
                 Err(e) => {
                     println!("{}. File path: {}", e, exe_path);
                     None
                 },
-                Ok(data) => {
-                    Some(data)
+                Ok(_) => {
+                    Some(Executable {
+                        path : exe_path,
+                        header
+                    })
                 }
             }
         }
     }
 }
 
-fn get_exe_name(folder : &String) -> Option<String> {
+/// This function returns the name of the
+/// executable defined by SYSTEM.CNF, if any.
+pub fn get_exe_name(folder : &str) -> Option<String> {
     use std::fs;
     use regex::Regex;
 
     let path = format!("{}/{}", folder, "SYSTEM.CNF");
 
-    let data_buffer = fs::read_to_string(&path).unwrap();
+    let data_buffer = match fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("{}. File path: {}", e, path);
+            return None
+        }
+    };
 
     lazy_static! {
         static ref RX: Regex = Regex::new(r"BOOT\s*=\s*cdrom:\\([aA-zZ0-9]{1,8}\.[aA-zZ0-9]{1,3}).+").expect("Could not compile regex");
@@ -369,3 +1773,174 @@ fn get_exe_name(folder : &String) -> Option<String> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the path host_path() is expected to build inside folder.
+    fn inside(folder : &str, components : &[&str]) -> Option<std::path::PathBuf> {
+        let mut path = std::path::PathBuf::from(folder);
+
+        path.extend(components);
+        Some(path)
+    }
+
+    #[test]
+    fn host_path_accepts_both_separators() {
+        assert_eq!(host_path("cd", r"DATA\LEVEL1.ARC"), inside("cd", &["DATA", "LEVEL1.ARC"]));
+        assert_eq!(host_path("cd", "DATA/LEVEL1.ARC"), inside("cd", &["DATA", "LEVEL1.ARC"]));
+        assert_eq!(host_path("cd", r"DATA/SUB\.\A.BIN"), inside("cd", &["DATA", "SUB", "A.BIN"]));
+        assert_eq!(host_path("cd", ""), inside("cd", &[]));
+    }
+
+    #[test]
+    fn host_path_keeps_absolute_paths_inside_folder() {
+        assert_eq!(host_path("cd", "/etc/passwd"), inside("cd", &["etc", "passwd"]));
+        assert_eq!(host_path("cd", r"\\server\share\A.BIN"), inside("cd", &["server", "share", "A.BIN"]));
+    }
+
+    #[test]
+    fn host_path_rejects_escaping_paths() {
+        assert_eq!(host_path("cd", ".."), None);
+        assert_eq!(host_path("cd", r"..\SECRET.TXT"), None);
+        assert_eq!(host_path("cd", "DATA/../../SECRET.TXT"), None);
+        assert_eq!(host_path("cd", r"DATA\..\A.BIN"), None);
+    }
+
+    #[test]
+    fn host_path_rejects_drive_letters() {
+        assert_eq!(host_path("cd", r"C:\WINDOWS\WIN.INI"), None);
+        assert_eq!(host_path("cd", "C:/WINDOWS/WIN.INI"), None);
+        assert_eq!(host_path("cd", "C:"), None);
+        assert_eq!(host_path("cd", r"DATA\D:A.BIN"), None);
+    }
+
+    fn image(data : Vec<u8>) -> Image {
+        Image {
+            hash : crc::crc32(&data),
+            data
+        }
+    }
+
+    /// Returns data following a pattern, so blocks differ from each other.
+    fn pattern(len : usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    /// Applies a delta as the console does, returning the blocks
+    /// found on it, and the image rebuilt for the given size.
+    fn apply(base : &[u8], delta : &[u8], size : usize) -> (Vec<(usize, usize)>, Vec<u8>) {
+        let mut image = base.to_vec();
+        let mut blocks : Vec<(usize, usize)> = Vec::new();
+        let mut pos = 0;
+
+        image.resize(size, 0);
+
+        while pos < delta.len() {
+            let word = |at : usize| u32::from_le_bytes([delta[at], delta[at + 1], delta[at + 2], delta[at + 3]]) as usize;
+            let (offset, len) = (word(pos), word(pos + 4));
+
+            image[offset..offset + len].copy_from_slice(&delta[pos + 8..pos + 8 + len]);
+            blocks.push((offset, len));
+            pos += 8 + len;
+        }
+
+        assert_eq!(pos, delta.len());
+        (blocks, image)
+    }
+
+    /// Builds the delta from base to target, checks the
+    /// image rebuilt from it and returns its blocks.
+    fn delta_blocks(base : &[u8], target : &[u8]) -> Vec<(usize, usize)> {
+        let delta = image(target.to_vec()).delta(&image(base.to_vec()));
+        let (blocks, rebuilt) = apply(base, &delta, target.len());
+
+        assert!(rebuilt == target, "Image not rebuilt from delta");
+        blocks
+    }
+
+    #[test]
+    fn delta_of_same_image_is_empty() {
+        let data = pattern(1000);
+
+        assert!(delta_blocks(&data, &data).is_empty());
+    }
+
+    #[test]
+    fn delta_sends_changed_blocks_only() {
+        let base = pattern(4096);
+        let mut target = base.clone();
+
+        target[5] ^= 0xFF;
+        target[64 * 10 + 3] ^= 0xFF;
+        target[4095] ^= 0xFF;
+
+        assert_eq!(delta_blocks(&base, &target), [(0, 64), (640, 64), (4032, 64)]);
+    }
+
+    #[test]
+    fn delta_merges_consecutive_blocks() {
+        let base = pattern(4096);
+        let mut target = base.clone();
+
+        for byte in &mut target[100..300] {
+            *byte ^= 0x55;
+        }
+
+        assert_eq!(delta_blocks(&base, &target), [(64, 256)]);
+    }
+
+    #[test]
+    fn delta_of_grown_image() {
+        let base = pattern(1000);
+        let mut target = base.clone();
+
+        target.extend(pattern(500).iter().map(|b| b ^ 0xA5));
+
+        // The block holding the old end of image is
+        // sent, as well as everything after it.
+        assert_eq!(delta_blocks(&base, &target), [(960, 540)]);
+    }
+
+    #[test]
+    fn delta_of_shrunk_image() {
+        let base = pattern(1000);
+        let mut target = base[..900].to_vec();
+
+        target[0] ^= 1;
+
+        // Data past the new end of image is not sent,
+        // since the console is given the size of the image.
+        assert_eq!(delta_blocks(&base, &target), [(0, 64)]);
+    }
+
+    #[test]
+    fn parse_range_accepts_valid_ranges() {
+        assert_eq!(parse_range("0", "0"), Some((0, 0)));
+        assert_eq!(parse_range("2048", "4096"), Some((2048, 4096)));
+        assert_eq!(parse_range("18446744073709551615", "0"), Some((u64::MAX, 0)));
+    }
+
+    #[test]
+    fn parse_range_rejects_invalid_ranges() {
+        assert_eq!(parse_range("18446744073709551615", "1"), None);
+        assert_eq!(parse_range("18446744073709551616", "0"), None);
+        assert_eq!(parse_range("0", "18446744073709551616"), None);
+        assert_eq!(parse_range("", "0"), None);
+    }
+
+    #[test]
+    fn file_request_ranges() {
+        let captures = FILE_REQUEST_RX.captures(r"cdrom:\DATA\LEVEL1.ARC;1:2048:4096").expect("Request not matched");
+
+        assert_eq!(&captures[1], r"DATA\LEVEL1.ARC");
+        assert_eq!(&captures[2], "2048");
+        assert_eq!(&captures[3], "4096");
+
+        let captures = FILE_REQUEST_RX.captures(r"cdrom:\DATA\LEVEL1.ARC;1").expect("Request not matched");
+
+        assert!(captures.get(2).is_none());
+        assert!(!FILE_REQUEST_RX.is_match(r"cdrom:\DATA\LEVEL1.ARC;1:-1:0"));
+    }
+}
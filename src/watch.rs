@@ -0,0 +1,184 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process::Command,
+    thread,
+    time::Duration
+};
+
+use link::ResetLine;
+
+#[cfg(target_os = "linux")]
+use inotify::{Inotify, WatchDescriptor, WatchMask};
+
+/// Time without further changes before a change is reported,
+/// so a build writing several files is only reported once.
+const SETTLE_TIME : Duration = Duration::from_millis(300);
+
+/// This structure defines how watch mode
+/// has been configured by the user.
+pub struct WatchOptions {
+    /// Additional folder to monitor, if any.
+    pub folder : Option<String>,
+
+    /// Command run before the executable is sent again, if any.
+    pub build_command : Option<String>,
+
+    /// Control line used to reset the console, if any.
+    pub reset_line : Option<ResetLine>
+}
+
+/// This structure monitors the executable sent to the console,
+/// as well as SYSTEM.CNF and, optionally, a whole folder, e.g.:
+/// the source code of the executable.
+#[cfg(target_os = "linux")]
+pub struct Watcher {
+    inotify : Inotify,
+
+    /// Watch for the folder where the executable is found.
+    /// Only changes to the files below are reported from it.
+    exe_folder : WatchDescriptor,
+    files : Vec<String>
+}
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    /// This function starts monitoring the given files inside
+    /// exe_folder, as well as everything inside folder, if given,
+    /// including its subfolders.
+    pub fn new(exe_folder : &str, files : &[&str], folder : Option<&str>) -> io::Result<Watcher> {
+        // Files are often replaced rather than modified, so
+        // folders are monitored instead of the files themselves.
+        let mask = WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE | WatchMask::DELETE;
+
+        let inotify = Inotify::init()?;
+        let exe_folder = inotify.watches().add(exe_folder, mask)?;
+
+        if let Some(folder) = folder {
+            for path in subfolders(Path::new(folder))? {
+                inotify.watches().add(&path, mask)?;
+            }
+        }
+
+        Ok(Watcher {
+            inotify,
+            exe_folder,
+            files : files.iter().map(|f| String::from(*f)).collect()
+        })
+    }
+
+    /// Returns the name of a file that has changed since
+    /// the last call, if any, once no more changes happen.
+    pub fn changed(&mut self) -> io::Result<Option<String>> {
+        let mut changed = self.read_changes()?;
+
+        if changed.is_some() {
+            // Wait until all changes have been made.
+            loop {
+                thread::sleep(SETTLE_TIME);
+
+                match self.read_changes()? {
+                    None => break,
+                    Some(name) => changed = Some(name)
+                }
+            }
+        }
+
+        Ok(changed)
+    }
+
+    /// Drops any change not reported yet, e.g.:
+    /// those made by the build command.
+    pub fn discard(&mut self) -> io::Result<()> {
+        while self.read_changes()?.is_some() {}
+
+        Ok(())
+    }
+
+    fn read_changes(&mut self) -> io::Result<Option<String>> {
+        let mut buffer : [u8; 4096] = [0; 4096];
+        let mut changed : Option<String> = None;
+
+        loop {
+            let events = match self.inotify.read_events(&mut buffer) {
+                Ok(events) => events,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(changed),
+                Err(e) => return Err(e)
+            };
+
+            for event in events {
+                let name = event.name.map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+
+                if event.wd != self.exe_folder || self.files.iter().any(|f| f.eq_ignore_ascii_case(&name)) {
+                    changed = Some(name);
+                }
+            }
+        }
+    }
+}
+
+/// Watch mode relies on inotify, only available on Linux.
+#[cfg(not(target_os = "linux"))]
+pub struct Watcher;
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    pub fn new(_exe_folder : &str, _files : &[&str], _folder : Option<&str>) -> io::Result<Watcher> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "Watch mode is only supported on Linux"))
+    }
+
+    pub fn changed(&mut self) -> io::Result<Option<String>> {
+        Ok(None)
+    }
+
+    pub fn discard(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns the given folder and all folders inside it.
+#[cfg(target_os = "linux")]
+fn subfolders(folder : &Path) -> io::Result<Vec<PathBuf>> {
+    let mut folders : Vec<PathBuf> = vec![folder.to_path_buf()];
+    let mut pos = 0;
+
+    while pos < folders.len() {
+        for entry in std::fs::read_dir(&folders[pos])? {
+            let entry = entry?;
+
+            if entry.file_type()?.is_dir() {
+                folders.push(entry.path());
+            }
+        }
+
+        pos += 1;
+    }
+
+    Ok(folders)
+}
+
+/// This function runs a build command given by the user through
+/// the system shell. Returns whether the command succeeded.
+pub fn run_build_command(command : &str) -> bool {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    }
+    else
+    {
+        ("sh", "-c")
+    };
+
+    println!("Running {}", command);
+
+    match Command::new(shell).arg(flag).arg(command).status() {
+        Ok(status) if status.success() => true,
+        Ok(status) => {
+            println!("Build command failed: {}", status);
+            false
+        },
+        Err(e) => {
+            println!("Could not run build command: {}", e);
+            false
+        }
+    }
+}
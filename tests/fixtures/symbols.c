@@ -0,0 +1,46 @@
+/*
+ * Source of the symbol file fixtures, built with:
+ * gcc -m32 -g -gdwarf-4 -O0 -nostdlib -static -no-pie -fno-pic
+ *     -fno-asynchronous-unwind-tables -Wl,--build-id=none
+ *     -Wl,-Map=symbols.map -o symbols.elf symbols.c
+ * gcc -m32 -g -gdwarf-5 -O0 -fno-pic -fno-asynchronous-unwind-tables
+ *     -c -o symbols.o symbols.c
+ * psyq.map was written by hand after PSYLINK's /m output,
+ * placing the same symbols at 0x80010000.
+ */
+
+struct vec
+{
+    short x, y;
+};
+
+struct player
+{
+    int score;
+    struct vec pos;
+    union
+    {
+        unsigned char health;
+        unsigned short shield;
+    };
+};
+
+typedef struct player player_t;
+
+volatile player_t player;
+const struct vec origin;
+int counter;
+
+/* Never defined, so it stays undefined on the symbol table of symbols.o. */
+extern int missing __attribute__((weak));
+
+void _start(void)
+{
+    static int frames;
+
+    for (;;)
+    {
+        player.health = &missing != 0;
+        counter += frames++;
+    }
+}